@@ -0,0 +1,223 @@
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::raytracing::*;
+use crate::sky::Sky;
+
+const BACKGROUND_TOP: ColourFloat = ColourFloat::new(1.0, 1.0, 1.0);
+const BACKGROUND_BOTTOM: ColourFloat = ColourFloat::new(0.5, 0.7, 1.0);
+
+/// What rays that leave the scene see.
+pub enum Background {
+    Constant(ColourFloat),
+    Gradient {
+        top: ColourFloat,
+        bottom: ColourFloat,
+    },
+    Environment(EnvironmentMap),
+    Sky(Sky),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Gradient {
+            top: BACKGROUND_TOP,
+            bottom: BACKGROUND_BOTTOM,
+        }
+    }
+}
+
+impl Background {
+    pub fn get_colour(&self, dir: Vector) -> ColourFloat {
+        use Background::*;
+        match *self {
+            Constant(colour) => colour,
+            Gradient { top, bottom } => ((1.0 - dir.y) * top) + (dir.y * bottom),
+            Environment(ref map) => map.get_colour(dir),
+            Sky(ref sky) => sky.get_colour(dir),
+        }
+    }
+
+    /// Pick a direction towards the bright parts of the background, with its solid angle pdf.
+    /// Returns `None` for backgrounds that aren't worth importance sampling.
+    pub fn sample(&self, u: f32, v: f32) -> Option<(Vector, f32)> {
+        match *self {
+            Background::Environment(ref map) => Some(map.sample(u, v)),
+            _ => None,
+        }
+    }
+
+    /// Whether `sample` picks directions at all
+    pub fn is_sampled(&self) -> bool {
+        matches!(*self, Background::Environment(_))
+    }
+
+    pub fn pdf(&self, dir: Vector) -> f32 {
+        match *self {
+            Background::Environment(ref map) => map.pdf(dir),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Equirectangular (latitude/longitude) HDR image surrounding the scene.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    texels: Vec<ColourFloat>,
+    /// Rotation about the vertical axis
+    pub rotation: Degrees,
+    /// Scale applied to the stored radiance
+    pub intensity: f32,
+    /// File the map was loaded from, so scenes using it can be saved
+    pub path: Option<String>,
+    // Luminance CDFs: one over the rows, then one within each row
+    marginal_cdf: Vec<f32>,
+    conditional_cdfs: Vec<Vec<f32>>,
+}
+
+impl EnvironmentMap {
+    pub fn new(
+        width: usize,
+        height: usize,
+        texels: Vec<ColourFloat>,
+        rotation: Degrees,
+        intensity: f32,
+    ) -> Self {
+        assert_eq!(texels.len(), width * height);
+        let mut row_weights = Vec::with_capacity(height);
+        let mut conditional_cdfs = Vec::with_capacity(height);
+        for y in 0..height {
+            // Rows near the poles cover less solid angle
+            let sin_theta = ((y as f32 + 0.5) / height as f32 * PI).sin();
+            let row = &texels[y * width..(y + 1) * width];
+            let (cdf, total) = build_cdf(row.iter().map(|t| luminance(*t) * sin_theta));
+            conditional_cdfs.push(cdf);
+            row_weights.push(total);
+        }
+        let (marginal_cdf, _) = build_cdf(row_weights.into_iter());
+        EnvironmentMap {
+            width,
+            height,
+            texels,
+            rotation,
+            intensity,
+            path: None,
+            marginal_cdf,
+            conditional_cdfs,
+        }
+    }
+
+    pub fn load(path: &str, rotation: Degrees, intensity: f32) -> image::ImageResult<Self> {
+        let decoder = image::hdr::HdrDecoder::new(BufReader::new(File::open(path)?))?;
+        let meta = decoder.metadata();
+        let texels = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .map(|p| ColourFloat::new(p.0[0], p.0[1], p.0[2]))
+            .collect();
+        let mut map = EnvironmentMap::new(
+            meta.width as usize,
+            meta.height as usize,
+            texels,
+            rotation,
+            intensity,
+        );
+        map.path = Some(path.to_string());
+        Ok(map)
+    }
+
+    pub fn get_colour(&self, dir: Vector) -> ColourFloat {
+        let (u, v) = self.dir_to_uv(dir);
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.intensity * self.texels[y * self.width + x]
+    }
+
+    /// Sample a direction proportional to the luminance of the map, returning its pdf
+    pub fn sample(&self, u: f32, v: f32) -> (Vector, f32) {
+        let (y, v_offset) = sample_cdf(&self.marginal_cdf, v);
+        let (x, u_offset) = sample_cdf(&self.conditional_cdfs[y], u);
+        let dir = self.uv_to_dir(
+            (x as f32 + u_offset) / self.width as f32,
+            (y as f32 + v_offset) / self.height as f32,
+        );
+        (dir, self.pdf(dir))
+    }
+
+    /// Solid angle density of `sample` producing `dir`
+    pub fn pdf(&self, dir: Vector) -> f32 {
+        let (u, v) = self.dir_to_uv(dir);
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let pixel_probability =
+            cdf_step(&self.marginal_cdf, y) * cdf_step(&self.conditional_cdfs[y], x);
+        pixel_probability * (self.width * self.height) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn dir_to_uv(&self, dir: Vector) -> (f32, f32) {
+        let phi = dir.x.atan2(-dir.z) / (2.0 * PI) + 0.5 - self.rotation.0 / 360.0;
+        let theta = dir.y.clamp(-1.0, 1.0).acos() / PI;
+        (phi - phi.floor(), theta)
+    }
+
+    fn uv_to_dir(&self, u: f32, v: f32) -> Vector {
+        let phi = (u - 0.5 + self.rotation.0 / 360.0) * 2.0 * PI;
+        let theta = v * PI;
+        Vector::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+}
+
+pub fn luminance(colour: ColourFloat) -> f32 {
+    0.2126 * colour.x + 0.7152 * colour.y + 0.0722 * colour.z
+}
+
+/// Normalised running sum of `weights`, with a leading zero, and the total weight.
+/// All-zero weights give a uniform distribution.
+fn build_cdf(weights: impl Iterator<Item = f32>) -> (Vec<f32>, f32) {
+    let mut cdf = vec![0.0];
+    for weight in weights {
+        let last = *cdf.last().unwrap();
+        cdf.push(last + weight.max(0.0));
+    }
+    let total = *cdf.last().unwrap();
+    let n = (cdf.len() - 1) as f32;
+    for (i, value) in cdf.iter_mut().enumerate() {
+        *value = if total > 0.0 {
+            *value / total
+        } else {
+            i as f32 / n
+        };
+    }
+    (cdf, total)
+}
+
+/// Pick a bucket of `cdf` using `u` in 0-1, along with how far through the bucket `u` landed
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    let buckets = cdf.len() - 1;
+    let index = match cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+        Ok(i) => i,
+        Err(i) => i - 1,
+    }
+    .min(buckets - 1);
+    let step = cdf_step(cdf, index);
+    let offset = if step > 0.0 {
+        (u - cdf[index]) / step
+    } else {
+        0.5
+    };
+    (index, offset.clamp(0.0, 1.0))
+}
+
+fn cdf_step(cdf: &[f32], index: usize) -> f32 {
+    cdf[index + 1] - cdf[index]
+}
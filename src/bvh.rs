@@ -0,0 +1,294 @@
+use crate::packet::{PacketIntersections, RayPacket, PACKET_SIZE};
+use crate::raytracing::*;
+use crate::simd::{F32x4, Mask4};
+
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Aabb {
+    /// Contains nothing, growing it by anything gives that thing's bounds
+    pub fn empty() -> Self {
+        Aabb {
+            min: Point::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Point::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn from_points(points: &[Point]) -> Self {
+        points
+            .iter()
+            .fold(Aabb::empty(), |bounds, &point| bounds.grow(point))
+    }
+
+    pub fn grow(&self, point: Point) -> Self {
+        Aabb {
+            min: Point::new(
+                self.min.x.min(point.x),
+                self.min.y.min(point.y),
+                self.min.z.min(point.z),
+            ),
+            max: Point::new(
+                self.max.x.max(point.x),
+                self.max.y.max(point.y),
+                self.max.z.max(point.z),
+            ),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        self.grow(other.min).grow(other.max)
+    }
+
+    pub fn centre(&self) -> Point {
+        (self.min + self.max) / 2.0
+    }
+
+    /// Index of the longest side
+    fn longest_axis(&self) -> usize {
+        let size = self.max - self.min;
+        if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Distance along `ray` where it enters the box, if it does so before `max_distance`.
+    /// Zero if the ray starts inside.
+    pub fn hit(&self, ray: &Ray, max_distance: f32) -> Option<f32> {
+        let mut near = 0.0_f32;
+        let mut far = max_distance;
+        for i in 0..3 {
+            // Division by zero gives infinities, which the comparisons handle
+            let inv_dir = 1.0 / ray.dir[i];
+            let t0 = (self.min[i] - ray.start[i]) * inv_dir;
+            let t1 = (self.max[i] - ray.start[i]) * inv_dir;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+
+    /// Which of the packet's rays `hit` would find enter the box before their
+    /// `max_distances`
+    pub fn packet_hit(&self, packet: &RayPacket, max_distances: F32x4) -> Mask4 {
+        let mut near = F32x4::splat(0.0);
+        let mut far = max_distances;
+        for i in 0..3 {
+            let t0 = (F32x4::splat(self.min[i]) - packet.start[i]) * packet.inv_dir[i];
+            let t1 = (F32x4::splat(self.max[i]) - packet.start[i]) * packet.inv_dir[i];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        !near.gt(far)
+    }
+}
+
+enum BvhNode {
+    /// `count` primitives from `first` in the BVH's index list
+    Leaf {
+        bounds: Aabb,
+        first: usize,
+        count: usize,
+    },
+    /// The left child directly follows its parent
+    Interior { bounds: Aabb, right: usize },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { bounds, .. } | BvhNode::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// Bounding volume hierarchy over primitives identified by their index, so only
+/// primitives whose boxes a ray passes through need testing.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    /// Build from the bounds of each primitive, splitting at the median along the widest axis
+    pub fn new(bounds: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, bounds: &[Aabb], first: usize, end: usize) -> usize {
+        let node_bounds = self.indices[first..end]
+            .iter()
+            .fold(Aabb::empty(), |total, &i| total.union(&bounds[i]));
+        let node = self.nodes.len();
+        let count = end - first;
+        if count <= MAX_LEAF_SIZE {
+            self.nodes.push(BvhNode::Leaf {
+                bounds: node_bounds,
+                first,
+                count,
+            });
+            return node;
+        }
+
+        let centres = self.indices[first..end]
+            .iter()
+            .fold(Aabb::empty(), |total, &i| total.grow(bounds[i].centre()));
+        let axis = centres.longest_axis();
+        let middle = first + count / 2;
+        // Bad vertices, e.g. infinite ones from an imported file, can centre bounds on NaN
+        self.indices[first..end].select_nth_unstable_by(count / 2, |&a, &b| {
+            bounds[a].centre()[axis].total_cmp(&bounds[b].centre()[axis])
+        });
+
+        self.nodes.push(BvhNode::Interior {
+            bounds: node_bounds,
+            right: 0,
+        });
+        self.build(bounds, first, middle);
+        let right_child = self.build(bounds, middle, end);
+        if let BvhNode::Interior { ref mut right, .. } = self.nodes[node] {
+            *right = right_child;
+        }
+        node
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(node) => *node.bounds(),
+            None => Aabb::empty(),
+        }
+    }
+
+    /// Nearest primitive hit by `ray`, where `intersect` tests a single primitive
+    pub fn closest_intersection(
+        &self,
+        ray: &Ray,
+        intersect: impl Fn(usize) -> Option<IntersectionLocation>,
+    ) -> Option<(usize, IntersectionLocation)> {
+        let mut closest: Option<(usize, IntersectionLocation)> = None;
+        let mut closest_dist = f32::MAX;
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            if self.nodes[node].bounds().hit(ray, closest_dist).is_none() {
+                continue;
+            }
+            match self.nodes[node] {
+                BvhNode::Leaf { first, count, .. } => {
+                    for &index in &self.indices[first..first + count] {
+                        if let Some(location) = intersect(index) {
+                            if location.distance < closest_dist {
+                                closest_dist = location.distance;
+                                closest = Some((index, location));
+                            }
+                        }
+                    }
+                }
+                BvhNode::Interior { right, .. } => {
+                    stack.push(right);
+                    stack.push(node + 1);
+                }
+            }
+        }
+        closest
+    }
+
+    /// Every primitive hit by `ray`, in no particular order
+    pub fn intersections<T>(
+        &self,
+        ray: &Ray,
+        intersect: impl Fn(usize) -> Option<T>,
+    ) -> Vec<(usize, T)> {
+        let mut hits = Vec::new();
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            if self.nodes[node].bounds().hit(ray, f32::MAX).is_none() {
+                continue;
+            }
+            match self.nodes[node] {
+                BvhNode::Leaf { first, count, .. } => {
+                    for &index in &self.indices[first..first + count] {
+                        if let Some(hit) = intersect(index) {
+                            hits.push((index, hit));
+                        }
+                    }
+                }
+                BvhNode::Interior { right, .. } => {
+                    stack.push(right);
+                    stack.push(node + 1);
+                }
+            }
+        }
+        hits
+    }
+
+    /// Nearest primitive hit by each of the packet's rays, where `intersect` tests a
+    /// single primitive against the rays in the lanes it's given. Each ray visits just
+    /// the nodes it would alone, so it finds exactly what `closest_intersection` would.
+    pub fn closest_packet_intersection(
+        &self,
+        packet: &RayPacket,
+        intersect: impl Fn(usize, Mask4) -> PacketIntersections,
+    ) -> [Option<(usize, IntersectionLocation)>; PACKET_SIZE] {
+        let mut closest: [Option<(usize, IntersectionLocation)>; PACKET_SIZE] = Default::default();
+        let mut closest_dist = [f32::MAX; PACKET_SIZE];
+        // Nodes with the lanes that reached them
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push((0, packet.active));
+        }
+        while let Some((node, lanes)) = stack.pop() {
+            let lanes = lanes
+                & self.nodes[node]
+                    .bounds()
+                    .packet_hit(packet, F32x4::from_array(closest_dist));
+            if !lanes.any() {
+                continue;
+            }
+            match self.nodes[node] {
+                BvhNode::Leaf { first, count, .. } => {
+                    for &index in &self.indices[first..first + count] {
+                        let locations = IntoIterator::into_iter(intersect(index, lanes));
+                        for (lane, location) in locations.enumerate() {
+                            if let Some(location) = location.filter(|_| lanes.lane(lane)) {
+                                if location.distance < closest_dist[lane] {
+                                    closest_dist[lane] = location.distance;
+                                    closest[lane] = Some((index, location));
+                                }
+                            }
+                        }
+                    }
+                }
+                BvhNode::Interior { right, .. } => {
+                    stack.push((right, lanes));
+                    stack.push((node + 1, lanes));
+                }
+            }
+        }
+        closest
+    }
+}
@@ -0,0 +1,155 @@
+//! Checkpoint files, holding everything a render has accumulated so a later run can
+//! carry it on where it stopped.
+//!
+//! A sample's random numbers depend only on the seed and which sample of which pixel it
+//! is, so the number of samples each pixel has taken stands in for the state of the
+//! generators. A checkpoint also holds a hash of everything else that decides the image
+//! (the scene, camera, image size, crop and render settings), and isn't resumed if that has
+//! changed. How long to render for can change between runs.
+//!
+//! The file is binary and little-endian: an 8 byte tag, the hash, the image width and
+//! height, the samples taken, rays cast and seconds spent so far, then for each pixel
+//! its weighted colour and weight totals and its sample statistics.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use crate::draw::{PixelStats, RenderSettings, RenderState, MAX_DEPTH};
+use crate::raytracing::*;
+use crate::scene_file;
+
+const TAG: &[u8; 8] = b"RTCKPT01";
+const HEADER_SIZE: usize = 8 + 8 + 4 + 4 + 8 + 8 + 8;
+const PIXEL_SIZE: usize = 4 * 4 + 3 * 4;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Hash of everything that decides the image a render gives. Fails for scenes that
+/// can't be written as a scene file, e.g. with textures not loaded from files.
+pub fn scene_hash(
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> io::Result<u64> {
+    let text = format!(
+        "{}\n{}x{} seed {} sampler {} samples {} filter {:?} crop {:?} depth {}",
+        scene_file::write(scene, Some(&visualiser.camera))?,
+        visualiser.screen.width(),
+        visualiser.screen.height(),
+        settings.seed,
+        settings.sampler.name(),
+        settings.samples.max(1),
+        settings.filter,
+        settings.crop,
+        MAX_DEPTH
+    );
+    Ok(hash(&text))
+}
+
+/// FNV-1a, which is fixed, unlike the standard library's hashers
+pub fn hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Save `state`, replacing the file only once it's completely written so that being
+/// stopped part way through doesn't lose the last checkpoint
+pub fn save(path: &str, hash: u64, state: &RenderState) -> io::Result<()> {
+    let film = &state.film;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + state.pixels.len() * PIXEL_SIZE);
+    bytes.extend_from_slice(TAG);
+    bytes.extend_from_slice(&hash.to_le_bytes());
+    bytes.extend_from_slice(&(film.width as u32).to_le_bytes());
+    bytes.extend_from_slice(&(film.height as u32).to_le_bytes());
+    bytes.extend_from_slice(&state.samples.to_le_bytes());
+    bytes.extend_from_slice(&state.rays.to_le_bytes());
+    bytes.extend_from_slice(&state.elapsed.as_secs_f64().to_le_bytes());
+    let (sums, weights) = film.totals();
+    for ((sum, weight), pixel) in sums.iter().zip(weights.iter()).zip(state.pixels.iter()) {
+        for value in [sum.x, sum.y, sum.z, *weight].iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&pixel.samples.to_le_bytes());
+        bytes.extend_from_slice(&pixel.mean.to_le_bytes());
+        bytes.extend_from_slice(&pixel.m2.to_le_bytes());
+    }
+    let partial = format!("{}.partial", path);
+    fs::write(&partial, bytes)?;
+    fs::rename(partial, path)
+}
+
+/// Render state saved at `path` for rendering this scene, or `None` if there's no file.
+/// Fails if the checkpoint is for a different scene, camera or settings.
+pub fn resume(
+    path: &str,
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> io::Result<Option<RenderState>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let hash = scene_hash(visualiser, scene, settings)?;
+    let width = visualiser.screen.width() as usize;
+    let height = visualiser.screen.height() as usize;
+    read(&bytes, hash, width, height, settings).map(Some)
+}
+
+fn read(
+    bytes: &[u8],
+    hash: u64,
+    width: usize,
+    height: usize,
+    settings: &RenderSettings,
+) -> io::Result<RenderState> {
+    if bytes.len() < HEADER_SIZE || &bytes[..8] != TAG {
+        return Err(invalid("not a checkpoint file"));
+    }
+    let mut position = 8;
+    let mut take = |size: usize| {
+        let taken = &bytes[position..position + size];
+        position += size;
+        taken
+    };
+    let u64_at = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+    let u32_at = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let f32_at = |bytes: &[u8]| f32::from_le_bytes(bytes.try_into().unwrap());
+    if u64_at(take(8)) != hash {
+        return Err(invalid(
+            "checkpoint is for a different scene, camera or settings",
+        ));
+    }
+    if (u32_at(take(4)) as usize, u32_at(take(4)) as usize) != (width, height) {
+        return Err(invalid("checkpoint is for a different image size"));
+    }
+    if bytes.len() != HEADER_SIZE + width * height * PIXEL_SIZE {
+        return Err(invalid("checkpoint is truncated"));
+    }
+    let mut state = RenderState::new(width, height, settings.filter);
+    state.samples = u64_at(take(8));
+    state.rays = u64_at(take(8));
+    let seconds = f64::from_le_bytes(take(8).try_into().unwrap());
+    state.elapsed = Duration::from_secs_f64(seconds.max(0.0));
+    let (sums, weights) = state.film.totals_mut();
+    for ((sum, weight), pixel) in sums
+        .iter_mut()
+        .zip(weights.iter_mut())
+        .zip(state.pixels.iter_mut())
+    {
+        *sum = ColourFloat::new(f32_at(take(4)), f32_at(take(4)), f32_at(take(4)));
+        *weight = f32_at(take(4));
+        *pixel = PixelStats {
+            samples: u32_at(take(4)),
+            mean: f32_at(take(4)),
+            m2: f32_at(take(4)),
+        };
+    }
+    Ok(state)
+}
@@ -60,18 +60,20 @@ pub fn get_scene() -> Scene {
     let right2 = Object::Triangle(Triangle::new(p3, p2, p4, [WHITE; 3], Material::Diffuse));
     let top1 = Object::Triangle(Triangle::new(p6, p7, p4, [PURPLE; 3], Material::Diffuse));
     let top2 = Object::Triangle(Triangle::new(p7, p5, p4, [PURPLE; 3], Material::Diffuse));
+    // Vertex colours are weighted by the barycentric (w, u, v) like normals and uvs,
+    // but were once weighted by (u, v, w), so these are rotated to look as they did
     let front1 = Object::Triangle(Triangle::new(
         p6,
         p2,
         p0,
-        [RED, GREEN, BLUE],
+        [BLUE, RED, GREEN],
         Material::Diffuse,
     ));
     let front2 = Object::Triangle(Triangle::new(
         p6,
         p4,
         p2,
-        [RED, GREEN, BLUE],
+        [BLUE, RED, GREEN],
         Material::Specular,
     ));
 
@@ -79,48 +81,56 @@ pub fn get_scene() -> Scene {
         centre: p0,
         radius: 1.0,
         colour: RED,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere1 = Object::Sphere(Sphere {
         centre: p1,
         radius: 1.0,
         colour: GREEN,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere2 = Object::Sphere(Sphere {
         centre: p2,
         radius: 1.0,
         colour: RED,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere3 = Object::Sphere(Sphere {
         centre: p3,
         radius: 1.0,
         colour: GREEN,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere4 = Object::Sphere(Sphere {
         centre: p4,
         radius: 1.0,
         colour: RED,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere5 = Object::Sphere(Sphere {
         centre: p5,
         radius: 1.0,
         colour: GREEN,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere6 = Object::Sphere(Sphere {
         centre: p6,
         radius: 1.0,
         colour: RED,
+        texture: None,
         material: Material::Diffuse,
     });
     let sphere7 = Object::Sphere(Sphere {
         centre: p7,
         radius: 1.0,
         colour: GREEN,
+        texture: None,
         material: Material::Diffuse,
     });
 
@@ -134,6 +144,7 @@ pub fn get_scene() -> Scene {
         centre: p_centre,
         radius: 1.0,
         colour: BLUE,
+        texture: None,
         material: Material::Lambertian,
     });
     let p_mid_centre = Point {
@@ -145,6 +156,7 @@ pub fn get_scene() -> Scene {
         centre: p_mid_centre,
         radius: 2.0,
         colour: PURPLE,
+        texture: None,
         material: Material::Lambertian,
     });
     let p_back_centre = Point {
@@ -156,6 +168,7 @@ pub fn get_scene() -> Scene {
         centre: p_back_centre,
         radius: 2.0,
         colour: RED,
+        texture: None,
         material: Material::Lambertian,
     });
 
@@ -184,5 +197,5 @@ pub fn get_scene() -> Scene {
         front1,
         front2,
     ];
-    Scene { objects }
+    Scene::new(objects)
 }
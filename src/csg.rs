@@ -0,0 +1,304 @@
+use cgmath::prelude::*;
+
+use crate::instance::Instance;
+use crate::mesh::Mesh;
+use crate::primitives::*;
+use crate::raytracing::*;
+use crate::utils;
+
+/// Stretch of a ray's line that lies inside a solid, from where it enters to where it leaves.
+pub struct Span {
+    pub enter: IntersectionLocation,
+    pub exit: IntersectionLocation,
+}
+
+/// Closed objects that can report every span of a ray inside them, not just the nearest hit.
+///
+/// Spans cover the whole line, including behind the ray's start, so it is known whether
+/// the ray starts inside. They are returned in order and don't overlap.
+pub trait Solid {
+    fn spans(&self, ray: &Ray) -> Vec<Span>;
+}
+
+/// One span between the first and last crossings of a convex surface
+fn convex_span(
+    crossings: &[(f32, usize)],
+    location: impl Fn(f32, usize) -> IntersectionLocation,
+) -> Vec<Span> {
+    match (crossings.first(), crossings.last()) {
+        (Some(&(enter, enter_face)), Some(&(exit, exit_face))) if enter < exit => vec![Span {
+            enter: location(enter, enter_face),
+            exit: location(exit, exit_face),
+        }],
+        _ => Vec::new(),
+    }
+}
+
+impl Solid for Sphere {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        match self.chord(ray) {
+            Some((enter, exit)) => vec![Span {
+                enter: IntersectionLocation::new(enter, TextureCoords::None),
+                exit: IntersectionLocation::new(exit, TextureCoords::None),
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Solid for Cuboid {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        match self.slabs(ray) {
+            Some((enter, exit)) => vec![Span {
+                enter: self.location(ray, enter),
+                exit: self.location(ray, exit),
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Solid for Cylinder {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        if !self.capped {
+            return Vec::new();
+        }
+        convex_span(&self.crossings(ray), |distance, face| {
+            self.location(ray, distance, face)
+        })
+    }
+}
+
+impl Solid for Cone {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        if !self.capped {
+            return Vec::new();
+        }
+        convex_span(&self.crossings(ray), |distance, face| {
+            self.location(ray, distance, face)
+        })
+    }
+}
+
+impl Solid for Torus {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let (start, dir) = self.frame().local_ray(ray);
+        self.chords(start, dir)
+            .into_iter()
+            .map(|(enter, exit)| Span {
+                enter: self.location(start + enter * dir, enter),
+                exit: self.location(start + exit * dir, exit),
+            })
+            .collect()
+    }
+}
+
+impl Solid for Mesh {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        if !self.is_closed() {
+            return Vec::new();
+        }
+        // Count how deep inside the ray is, as it can cross several faces at a shared edge
+        let mut depth = 0;
+        let mut spans = Vec::new();
+        let mut enter = None;
+        for (location, front) in self.crossings(ray) {
+            if front {
+                depth += 1;
+                if depth == 1 {
+                    enter = Some(location);
+                }
+            } else if depth > 0 {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some(enter) = enter.take() {
+                        spans.push(Span {
+                            enter,
+                            exit: location,
+                        });
+                    }
+                }
+            }
+        }
+        spans
+    }
+}
+
+impl Solid for Instance {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let dir = self.to_object_vector(ray.dir);
+        let scale = dir.magnitude();
+        let object_ray = Ray::new(self.to_object_point(ray.start), dir / scale);
+        let mut spans = self.object.spans(&object_ray);
+        for span in spans.iter_mut() {
+            span.enter.distance /= scale;
+            span.exit.distance /= scale;
+        }
+        spans
+    }
+}
+
+impl Object {
+    /// Whether the object is closed, so it can be used in CSG
+    pub fn is_solid(&self) -> bool {
+        match *self {
+            Object::Sphere(_) | Object::Cuboid(_) | Object::Torus(_) | Object::Csg(_) => true,
+            Object::Cylinder(ref c) => c.capped,
+            Object::Cone(ref c) => c.capped,
+            Object::Mesh(ref m) => m.is_closed(),
+            Object::Instance(ref i) => i.object.is_solid(),
+            _ => false,
+        }
+    }
+}
+
+impl Solid for Object {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        match *self {
+            Object::Sphere(ref s) => s.spans(ray),
+            Object::Cuboid(ref c) => c.spans(ray),
+            Object::Cylinder(ref c) => c.spans(ray),
+            Object::Cone(ref c) => c.spans(ray),
+            Object::Torus(ref t) => t.spans(ray),
+            Object::Mesh(ref m) => m.spans(ray),
+            Object::Instance(ref i) => i.spans(ray),
+            Object::Csg(ref c) => c.spans(ray),
+            _ => panic!("Only closed objects can be used in CSG."),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    /// The left object with the right one cut out of it
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(self, left: bool, right: bool) -> bool {
+        match self {
+            CsgOperation::Union => left || right,
+            CsgOperation::Intersection => left && right,
+            CsgOperation::Difference => left && !right,
+        }
+    }
+}
+
+// Which child a CSG boundary came from, stored in `IntersectionLocation::primitive`
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+
+/// Two solids combined by a boolean operation. Each surface keeps its own material and texture.
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Box<Object>,
+    pub right: Box<Object>,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, left: Object, right: Object) -> Self {
+        assert!(
+            left.is_solid() && right.is_solid(),
+            "Only closed objects can be used in CSG."
+        );
+        Csg {
+            operation,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn child<'a, 'b>(
+        &'a self,
+        location: &'b IntersectionLocation,
+    ) -> (&'a Object, &'b IntersectionLocation) {
+        let child = location
+            .inner
+            .as_ref()
+            .expect("CSG intersection is missing its child");
+        match location.primitive {
+            LEFT => (&self.left, child),
+            _ => (&self.right, child),
+        }
+    }
+
+    pub fn get_material(&self, location: &IntersectionLocation) -> &Material {
+        let (object, child) = self.child(location);
+        object.get_material(child)
+    }
+
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
+        let (object, child) = self.child(location);
+        let normal = object.get_normal(position, child);
+        // Surfaces of the cut-out object face into it, so they face out of the result
+        if self.operation == CsgOperation::Difference && location.primitive == RIGHT {
+            -normal
+        } else {
+            normal
+        }
+    }
+}
+
+impl Coloured for Csg {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let (object, child) = self.child(location);
+        object.get_colour(position, child, footprint)
+    }
+}
+
+impl Solid for Csg {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        // Walk every boundary of both children in order, tracking which ones the ray is inside
+        let mut boundaries = Vec::new();
+        for (side, object) in [(LEFT, &self.left), (RIGHT, &self.right)].iter() {
+            for span in object.spans(ray) {
+                boundaries.push((*side, span.enter));
+                boundaries.push((*side, span.exit));
+            }
+        }
+        boundaries.sort_by(|a, b| a.1.distance.total_cmp(&b.1.distance));
+
+        let mut inside = [false, false];
+        let mut spans = Vec::new();
+        let mut enter = None;
+        for (side, location) in boundaries {
+            let was_inside = self.operation.inside(inside[LEFT], inside[RIGHT]);
+            inside[side] = !inside[side];
+            let is_inside = self.operation.inside(inside[LEFT], inside[RIGHT]);
+            if was_inside == is_inside {
+                continue;
+            }
+            let location = IntersectionLocation {
+                distance: location.distance,
+                texture_coords: TextureCoords::None,
+                primitive: side,
+                inner: Some(Box::new(location)),
+            };
+            if is_inside {
+                enter = Some(location);
+            } else if let Some(enter) = enter.take() {
+                spans.push(Span {
+                    enter,
+                    exit: location,
+                });
+            }
+        }
+        spans
+    }
+}
+
+impl Intersectable for Csg {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        self.spans(ray)
+            .into_iter()
+            .flat_map(|span| vec![span.enter, span.exit])
+            .find(|location| utils::is_positive(location.distance))
+    }
+}
@@ -0,0 +1,381 @@
+//! Rendering split between worker processes, which may be on other machines.
+//!
+//! A coordinator listens on a socket and cuts the image into tiles. Each worker loads
+//! the same scene with the same settings, connects, and is handed tiles one at a time,
+//! sending back the filtered samples it took for each. A worker that disconnects part
+//! way through a tile has it handed to another. The tiles are merged in order once
+//! they're all in, so the image is the same however many workers there are. It matches
+//! rendering in one process up to rounding, as the samples splatted across a tile's edge
+//! are added up in a different order.
+//!
+//! Messages are binary and little-endian. A worker opens with an 8 byte tag and the
+//! hash of its scene and settings, including when pixels stop taking samples, and the coordinator answers with a byte, 1 if the
+//! hash matches its own. Then it sends a 1 and a tile's x, y, width and height for each
+//! tile, and the worker answers with the weighted colour and weight totals of every
+//! pixel the tile's samples reach, the sample statistics of the tile's pixels and the
+//! number of rays cast. A 0 instead of a tile means there's nothing left to do.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::checkpoint;
+use crate::draw::{self, PixelStats, RenderSettings, RenderState, Rendered};
+use crate::film::{Film, Filter, Region};
+use crate::raytracing::*;
+
+const TAG: &[u8; 8] = b"RTWORK01";
+/// Width and height of the tiles handed out
+pub const TILE_SIZE: usize = 32;
+/// How long a worker keeps trying to reach a coordinator that isn't listening yet
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often idle threads check for something to do
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the coordinator waits to hear from a worker, e.g. for a tile, before giving
+/// up on it and handing its tile to another
+pub const WORKER_TIMEOUT: Duration = Duration::from_secs(600);
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// What a worker sends back for a tile
+struct TileResult {
+    /// Every pixel the tile's samples reach
+    film: Film,
+    /// The tile's pixels, row by row
+    pixels: Vec<PixelStats>,
+    rays: u64,
+}
+
+/// What the coordinator's threads share
+struct Job {
+    hash: u64,
+    timeout: Duration,
+    width: usize,
+    height: usize,
+    filter: Filter,
+    tiles: Vec<Region>,
+}
+
+/// Hash of everything that decides the image, as for checkpoints, along with when
+/// pixels stop taking samples, which a checkpoint leaves to each run
+pub fn job_hash(
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> io::Result<u64> {
+    let text = format!(
+        "{:x} max_samples {} noise_threshold {} time_budget {:?}",
+        checkpoint::scene_hash(visualiser, scene, settings)?,
+        settings.max_samples,
+        settings.noise_threshold,
+        settings.time_budget
+    );
+    Ok(checkpoint::hash(&text))
+}
+
+/// Tiles covering `region`, row by row
+pub fn tiles(region: Region) -> Vec<Region> {
+    let mut tiles = Vec::new();
+    for y in (region.y..region.y + region.height).step_by(TILE_SIZE) {
+        for x in (region.x..region.x + region.width).step_by(TILE_SIZE) {
+            tiles.push(Region {
+                x,
+                y,
+                width: TILE_SIZE.min(region.x + region.width - x),
+                height: TILE_SIZE.min(region.y + region.height - y),
+            });
+        }
+    }
+    tiles
+}
+
+/// Render the scene with whichever workers connect to `listener`, waiting until some
+/// do. Fails if the scene can't be hashed to check the workers have the same one.
+pub fn coordinate(
+    listener: &TcpListener,
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> io::Result<Rendered> {
+    coordinate_with_timeout(listener, visualiser, scene, settings, WORKER_TIMEOUT)
+}
+
+/// `coordinate`, giving up on workers that go quiet for `timeout`
+pub fn coordinate_with_timeout(
+    listener: &TcpListener,
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+    timeout: Duration,
+) -> io::Result<Rendered> {
+    let hash = job_hash(visualiser, scene, settings)?;
+    let width = visualiser.screen.width() as usize;
+    let height = visualiser.screen.height() as usize;
+    let region = draw::traced_region(settings, width, height);
+    let job = Job {
+        hash,
+        timeout,
+        width,
+        height,
+        filter: settings.filter,
+        tiles: tiles(region),
+    };
+    let tiles = &job.tiles;
+    let queue = Mutex::new((0..tiles.len()).rev().collect::<Vec<_>>());
+    let finished = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    let mut results: Vec<Option<TileResult>> = tiles.iter().map(|_| None).collect();
+    let mut state = RenderState::new(width, height, settings.filter);
+    let (started, mut last_report) = (Instant::now(), Instant::now());
+
+    listener.set_nonblocking(true)?;
+    std::thread::scope(|scope| {
+        let mut done = 0;
+        while done < tiles.len() {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    println!("Worker {} connected", address);
+                    let (job, queue, finished) = (&job, &queue, &finished);
+                    let sender = sender.clone();
+                    scope.spawn(move || {
+                        if let Err(e) = serve(stream, job, queue, finished, sender) {
+                            println!("Lost worker {}: {}", address, e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    finished.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+            if let Ok((index, result)) = receiver.recv_timeout(POLL_INTERVAL) {
+                state.samples += result
+                    .pixels
+                    .iter()
+                    .map(|pixel| pixel.samples as u64)
+                    .sum::<u64>();
+                state.rays += result.rays;
+                state.elapsed = started.elapsed();
+                results[index] = Some(result);
+                done += 1;
+                if settings.progress && last_report.elapsed() >= draw::PROGRESS_INTERVAL {
+                    draw::report_progress(&state, done as f64 / tiles.len() as f64, None);
+                    last_report = Instant::now();
+                }
+            }
+        }
+        finished.store(true, Ordering::Relaxed);
+        Ok(())
+    })?;
+    listener.set_nonblocking(false)?;
+
+    // In order, so the sums come out the same whichever worker finished first
+    for (tile, result) in tiles.iter().zip(results.iter().flatten()) {
+        state.film.merge(&result.film);
+        for (i, pixel) in result.pixels.iter().enumerate() {
+            let (x, y) = (tile.x + i % tile.width, tile.y + i / tile.width);
+            state.pixels[y * width + x] = *pixel;
+        }
+    }
+    if settings.progress {
+        draw::report_progress(&state, 1.0, None);
+    }
+    Ok(Rendered {
+        colours: state.film.colours(),
+        samples: state.pixels.iter().map(|pixel| pixel.samples).collect(),
+        region,
+    })
+}
+
+/// Hand tiles to the worker on `stream` until they're all rendered, putting the one
+/// it has back in the queue if it goes away or takes too long
+fn serve(
+    mut stream: TcpStream,
+    job: &Job,
+    queue: &Mutex<Vec<usize>>,
+    finished: &AtomicBool,
+    sender: mpsc::Sender<(usize, TileResult)>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(job.timeout))?;
+    let mut tag = [0; 8];
+    stream.read_exact(&mut tag)?;
+    if &tag != TAG {
+        return Err(invalid("not a worker"));
+    }
+    let matches = read_u64(&mut stream)? == job.hash;
+    stream.write_all(&[matches as u8])?;
+    if !matches {
+        return Err(invalid("rendering a different scene, camera or settings"));
+    }
+    while !finished.load(Ordering::Relaxed) {
+        let index = match queue.lock().unwrap().pop() {
+            Some(index) => index,
+            None => {
+                // Others may still give theirs back
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+        match take_tile(&mut stream, job, job.tiles[index]) {
+            Ok(result) => sender
+                .send((index, result))
+                .map_err(|_| invalid("stopped"))?,
+            Err(e) => {
+                queue.lock().unwrap().push(index);
+                return Err(e);
+            }
+        }
+    }
+    stream.write_all(&[0])
+}
+
+/// Send a worker `tile` and read back what it rendered
+fn take_tile(stream: &mut TcpStream, job: &Job, tile: Region) -> io::Result<TileResult> {
+    let mut message = vec![1];
+    for value in [tile.x, tile.y, tile.width, tile.height].iter() {
+        message.extend_from_slice(&(*value as u32).to_le_bytes());
+    }
+    stream.write_all(&message)?;
+
+    let mut film = Film::reached_from(job.width, job.height, tile, job.filter);
+    let (sums, weights) = film.totals_mut();
+    let mut bytes = vec![0; sums.len() * 16 + tile.width * tile.height * 12 + 8];
+    stream.read_exact(&mut bytes)?;
+    let (totals, rays) = bytes.split_at(bytes.len() - 8);
+    let mut values = totals
+        .chunks_exact(4)
+        .map(|value| [value[0], value[1], value[2], value[3]]);
+    let mut next = || values.next().unwrap();
+    for (sum, weight) in sums.iter_mut().zip(weights.iter_mut()) {
+        let [r, g, b] = [next(), next(), next()].map(f32::from_le_bytes);
+        *sum = ColourFloat::new(r, g, b);
+        *weight = f32::from_le_bytes(next());
+    }
+    let pixels = (0..tile.width * tile.height)
+        .map(|_| PixelStats {
+            samples: u32::from_le_bytes(next()),
+            mean: f32::from_le_bytes(next()),
+            m2: f32::from_le_bytes(next()),
+        })
+        .collect();
+    let rays = u64::from_le_bytes(rays.try_into().unwrap());
+    Ok(TileResult { film, pixels, rays })
+}
+
+/// Render tiles for the coordinator at `address` until it has no more, returning how
+/// many. Fails if it can't be reached or is rendering a different scene.
+pub fn work(
+    address: &str,
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> io::Result<usize> {
+    let hash = job_hash(visualiser, scene, settings)?;
+    let width = visualiser.screen.width() as usize;
+    let height = visualiser.screen.height() as usize;
+    let mut stream = connect(address)?;
+    stream.set_nodelay(true)?;
+    stream.write_all(TAG)?;
+    stream.write_all(&hash.to_le_bytes())?;
+    let mut reply = [0];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 1 {
+        return Err(invalid(
+            "coordinator is rendering a different scene, camera or settings",
+        ));
+    }
+    // The coordinator does the reporting and saving, and the time budget is for a
+    // whole render rather than each tile
+    let tile_settings = RenderSettings {
+        time_budget: None,
+        heatmap: None,
+        progress: false,
+        checkpoint_image: None,
+        checkpoint: None,
+        ..settings.clone()
+    };
+    let mut rendered = 0;
+    loop {
+        stream.read_exact(&mut reply)?;
+        if reply[0] == 0 {
+            return Ok(rendered);
+        }
+        let mut numbers = [0; 4];
+        for number in numbers.iter_mut() {
+            *number = read_u32(&mut stream)? as usize;
+        }
+        let [x, y, tile_width, tile_height] = numbers;
+        let tile = Region {
+            x,
+            y,
+            width: tile_width,
+            height: tile_height,
+        };
+        if tile_width == 0 || tile_height == 0 || tile.within(width, height) != tile {
+            return Err(invalid("coordinator sent a tile outside the image"));
+        }
+        let mut state = RenderState::new(width, height, settings.filter);
+        let tile_settings = RenderSettings {
+            crop: Some(tile),
+            ..tile_settings.clone()
+        };
+        draw::advance(visualiser, scene, &tile_settings, &mut state);
+
+        let mut film = Film::reached_from(width, height, tile, settings.filter);
+        film.merge(&state.film);
+        let (sums, weights) = film.totals();
+        let mut message = Vec::new();
+        for (sum, weight) in sums.iter().zip(weights.iter()) {
+            for value in [sum.x, sum.y, sum.z, *weight].iter() {
+                message.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        for row in y..y + tile_height {
+            for pixel in &state.pixels[row * width + x..row * width + x + tile_width] {
+                message.extend_from_slice(&pixel.samples.to_le_bytes());
+                message.extend_from_slice(&pixel.mean.to_le_bytes());
+                message.extend_from_slice(&pixel.m2.to_le_bytes());
+            }
+        }
+        message.extend_from_slice(&state.rays.to_le_bytes());
+        stream.write_all(&message)?;
+        rendered += 1;
+    }
+}
+
+/// Connect to `address`, waiting a while for it to start listening
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let started = Instant::now();
+    loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                if started.elapsed() >= CONNECT_TIMEOUT {
+                    return Err(e);
+                }
+                std::thread::sleep(10 * POLL_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_u32(stream: &mut TcpStream) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(stream: &mut TcpStream) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -1,7 +1,14 @@
+use crate::background::luminance;
+use crate::checkpoint;
+use crate::film::{Film, Filter, Region};
+use crate::packet::PACKET_SIZE;
 use crate::raytracing::*;
-use crate::utils;
+use crate::sampler::{Sampler, SamplerKind};
+use crate::scene_file;
 
 use pixels::{Error, Pixels, SurfaceTexture};
+use std::io;
+use std::time::{Duration, Instant};
 use winit::dpi::{LogicalPosition, LogicalSize, PhysicalSize};
 use winit::event::{Event, VirtualKeyCode};
 use winit::event_loop::{ControlFlow, EventLoop};
@@ -10,19 +17,86 @@ use winit_input_helper::WinitInputHelper;
 pub const SCREEN_HEIGHT: u32 = 400;
 pub const SCREEN_WIDTH: u32 = 400;
 pub const ANTIALIAS_SAMPLES: u32 = 1;
+pub const MAX_DEPTH: u32 = 5;
+pub const SCENE_FILE: &str = "render.scene";
+/// Least time between progress reports
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
-pub fn render_scene(mut visualiser: Visualiser, scene: Scene) -> Result<(), Error> {
+/// How to render, fixed for a whole render
+#[derive(Clone)]
+pub struct RenderSettings {
+    /// Seed for all the random numbers, so the same seed gives the same image
+    pub seed: u64,
+    pub threads: usize,
+    pub sampler: SamplerKind,
+    /// Samples per pixel, or the fewest any pixel gets with adaptive sampling
+    pub samples: u32,
+    /// Most samples a pixel can get with adaptive sampling, which is off unless this is
+    /// more than `samples`
+    pub max_samples: u32,
+    /// Pixels stop getting more samples once the standard error of their brightness is
+    /// below this, in the 0-255 steps of the image
+    pub noise_threshold: f32,
+    /// Pixels stop getting more samples after rendering for this long
+    pub time_budget: Option<Duration>,
+    pub filter: Filter,
+    /// Where to save an image of how many samples each pixel took
+    pub heatmap: Option<String>,
+    /// Report progress on stderr after each pass
+    pub progress: bool,
+    /// Where to save the image so far every `checkpoint_interval`
+    pub checkpoint_image: Option<String>,
+    /// Where to save everything rendered so far every `checkpoint_interval`, and
+    /// at the end, for a later render to carry on from
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: Duration,
+    /// Only trace the pixels in this rectangle, leaving the rest of the image as it was
+    pub crop: Option<Region>,
+    /// Find camera rays' first hits in packets, tested together with SIMD. The image is
+    /// the same either way.
+    pub packets: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            seed: 0,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            sampler: SamplerKind::Sobol,
+            samples: ANTIALIAS_SAMPLES,
+            max_samples: ANTIALIAS_SAMPLES,
+            noise_threshold: 1.0,
+            time_budget: None,
+            filter: Filter::default(),
+            heatmap: None,
+            progress: false,
+            checkpoint_image: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(60),
+            crop: None,
+            packets: true,
+        }
+    }
+}
+
+pub fn render_scene(
+    mut visualiser: Visualiser,
+    mut scene: Scene,
+    mut settings: RenderSettings,
+) -> Result<(), Error> {
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
     let (window, p_width, p_height) = create_window("RustyRaytracer", &event_loop);
     let surface_texture = SurfaceTexture::new(p_width, p_height, &window);
     let mut pixels = Pixels::new(SCREEN_WIDTH, SCREEN_HEIGHT, surface_texture)?;
+    let mut drag_start = None;
+    let mut picked: Option<String> = None;
 
     event_loop.run(move |event, _, control_flow| {
         // The one and only event that winit_input_helper doesn't have for us...
         if let Event::RedrawRequested(_) = event {
             println!("REDRAW");
-            draw(&mut visualiser, &scene, pixels.get_frame());
+            draw(&mut visualiser, &scene, &settings, pixels.get_frame());
             if pixels
                 .render()
                 .map_err(|e| println!("pixels.render() failed: {}", e))
@@ -40,6 +114,66 @@ pub fn render_scene(mut visualiser: Visualiser, scene: Scene) -> Result<(), Erro
                 *control_flow = ControlFlow::Exit;
                 return;
             }
+            // Save the scene as seen from the current camera pose
+            if input.key_pressed(VirtualKeyCode::S) {
+                match scene_file::save(SCENE_FILE, &scene, Some(&visualiser.camera)) {
+                    Ok(_) => println!("Saved {} successfully", SCENE_FILE),
+                    Err(e) => println!("Problem saving {}: {}", SCENE_FILE, e),
+                }
+            }
+            // Dragging a box crops the render to it, and clicking reports what's there
+            let pixel = |position| {
+                pixels
+                    .window_pos_to_pixel(position)
+                    .unwrap_or_else(|outside| pixels.clamp_pixel_pos(outside))
+            };
+            if input.mouse_pressed(0) {
+                drag_start = input.mouse().map(pixel);
+            }
+            if input.mouse_released(0) {
+                match (drag_start.take(), input.mouse().map(pixel)) {
+                    (Some(start), Some(end)) if start != end => {
+                        let crop = Region::spanning(start, end);
+                        println!(
+                            "Cropped to {},{},{},{}",
+                            crop.x, crop.y, crop.width, crop.height
+                        );
+                        settings.crop = Some(crop);
+                        window.request_redraw();
+                    }
+                    (Some(_), Some((x, y))) => {
+                        let ray = visualiser.create_camera_ray(x as f32, y as f32);
+                        match scene.closest_intersection(&ray) {
+                            Some(intersection) => match scene.graph.path(intersection.object) {
+                                Some(path) => {
+                                    println!("Picked {}", path);
+                                    picked = path.rsplit('/').next().map(String::from);
+                                }
+                                None => println!("Picked an unnamed object"),
+                            },
+                            None => println!("Picked nothing"),
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            // Hide the last picked node, or show it again
+            if input.key_pressed(VirtualKeyCode::H) {
+                if let Some(name) = &picked {
+                    scene.graph.edit(name, |node| node.visible = !node.visible);
+                    match scene.graph.find(name) {
+                        Some(node) if node.visible => println!("Showed {}", name),
+                        _ => println!("Hid {}", name),
+                    }
+                    window.request_redraw();
+                }
+            }
+            // Render the whole image again
+            if input.key_pressed(VirtualKeyCode::C) && settings.crop.is_some() {
+                println!("Uncropped");
+                settings.crop = None;
+                window.request_redraw();
+            }
             let modified = {
                 if input.key_pressed(VirtualKeyCode::Left) {
                     println!("Left");
@@ -68,24 +202,472 @@ pub fn render_scene(mut visualiser: Visualiser, scene: Scene) -> Result<(), Erro
     });
 }
 
-fn draw(visualiser: &mut Visualiser, scene: &Scene, screen: &mut [u8]) {
-    for (idx, pix) in screen.chunks_exact_mut(4).enumerate() {
-        let x = idx as u32 % SCREEN_WIDTH;
-        let y = idx as u32 / SCREEN_WIDTH;
-        let mut colour_float = ColourFloat::new(0.0, 0.0, 0.0);
-        for s in 0..ANTIALIAS_SAMPLES {
-            let xx = x as f32 + utils::rand_f32();
-            let yy = y as f32 + utils::rand_f32();
-            let cam_ray = visualiser.create_camera_ray(xx, yy);
-            colour_float += trace(cam_ray, &scene, 5);
+/// Running mean and variance of the brightness of a pixel's samples, by Welford's method
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct PixelStats {
+    pub samples: u32,
+    pub mean: f32,
+    pub m2: f32,
+}
+
+impl PixelStats {
+    fn add(&mut self, colour: ColourFloat) {
+        let brightness = luminance(colour);
+        self.samples += 1;
+        let delta = brightness - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (brightness - self.mean);
+    }
+
+    /// Standard error of the mean brightness, roughly how far it is from converged
+    fn error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.samples as f32;
+        (self.m2 / ((n - 1.0) * n)).sqrt()
+    }
+}
+
+/// Everything a render has accumulated, which is enough to carry it on later
+pub struct RenderState {
+    pub film: Film,
+    /// Every pixel's samples so far, row by row
+    pub pixels: Vec<PixelStats>,
+    /// Samples taken and rays cast so far, for reporting speed
+    pub samples: u64,
+    pub rays: u64,
+    /// Time spent rendering so far, which counts towards the time budget
+    pub elapsed: Duration,
+}
+
+impl RenderState {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        RenderState {
+            film: Film::new(width, height, filter),
+            pixels: vec![PixelStats::default(); width * height],
+            samples: 0,
+            rays: 0,
+            elapsed: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Result of a render
+pub struct Rendered {
+    /// Colours of every pixel, row by row
+    pub colours: Vec<ColourFloat>,
+    /// Number of samples each pixel took, row by row
+    pub samples: Vec<u32>,
+    /// Pixels that were traced, outside of which the colours are black or only partly
+    /// rendered
+    pub region: Region,
+}
+
+/// Trace `counts` more camera rays spread over each pixel of row `y`, splatting each
+/// into `film`. Each sample's numbers depend only on the sampler's seed and the
+/// sample's position.
+///
+/// With `packets`, the rays' first hits are found in packets of neighbouring samples,
+/// which then carry on one at a time. That gives the same image, as each ray gets the
+/// same intersection and the samples are splatted in the same order.
+#[allow(clippy::too_many_arguments)]
+fn render_row(
+    visualiser: &Visualiser,
+    scene: &Scene,
+    y: u32,
+    counts: &[u32],
+    packets: bool,
+    sampler: &mut dyn Sampler,
+    film: &mut Film,
+    pixels: &mut [PixelStats],
+) {
+    // Each sample's pixel, its index among the pixel's samples and the pixel's count
+    let samples: Vec<(usize, u32, u32)> = counts
+        .iter()
+        .zip(pixels.iter())
+        .enumerate()
+        .flat_map(|(x, (&count, pixel))| {
+            (pixel.samples..pixel.samples + count).map(move |index| (x, index, count))
+        })
+        .collect();
+    let camera_sample = |sampler: &mut dyn Sampler, (x, index, count): (usize, u32, u32)| {
+        sampler.start(x as u32, y, index);
+        let [u, v] = sampler.get_2d();
+        let position = [x as f32 + u, y as f32 + v];
+        (
+            position,
+            visualiser.sample_camera_ray(position, count, sampler),
+        )
+    };
+    if !packets {
+        for &sample in &samples {
+            let (position, cam_ray) = camera_sample(sampler, sample);
+            let colour = trace(cam_ray, scene, MAX_DEPTH, sampler);
+            film.splat(position, colour);
+            pixels[sample.0].add(colour);
+        }
+        return;
+    }
+    let (mut positions, mut rays) = (Vec::new(), Vec::new());
+    for chunk in samples.chunks(PACKET_SIZE) {
+        positions.clear();
+        rays.clear();
+        for &sample in chunk {
+            let (position, cam_ray) = camera_sample(sampler, sample);
+            positions.push(position);
+            rays.push(cam_ray);
+        }
+        let hits = scene.closest_intersections(&rays);
+        let traced = positions
+            .iter()
+            .zip(rays.drain(..))
+            .zip(IntoIterator::into_iter(hits));
+        for (&(x, index, _), ((&position, cam_ray), hit)) in chunk.iter().zip(traced) {
+            // Back to where the sampler was after this sample's camera ray, its pixel
+            // and lens dimensions, so tracing carries on as it would without packets
+            sampler.start(x as u32, y, index);
+            sampler.skip_2d(2);
+            let colour = trace_hit(cam_ray, hit, scene, MAX_DEPTH, sampler);
+            film.splat(position, colour);
+            pixels[x].add(colour);
+        }
+    }
+}
+
+/// Render the scene in passes. Every pixel gets `settings.samples` in the first, and
+/// with adaptive sampling the pixels still noisier than the threshold get that many
+/// more in each pass after, until they reach `settings.max_samples` or time runs out.
+///
+/// Which pixels carry on depends only on their own samples, so the image is the same
+/// however many threads there are, unless it's cut short by the time budget.
+pub fn render(visualiser: &Visualiser, scene: &Scene, settings: &RenderSettings) -> Rendered {
+    let width = visualiser.screen.width() as usize;
+    let height = visualiser.screen.height() as usize;
+    let state = RenderState::new(width, height, settings.filter);
+    render_from(visualiser, scene, settings, state)
+}
+
+/// Carry on rendering from `state`, e.g. from a checkpoint file, giving the same image
+/// as if the render had never stopped
+pub fn render_from(
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+    mut state: RenderState,
+) -> Rendered {
+    advance(visualiser, scene, settings, &mut state);
+    Rendered {
+        colours: state.film.colours(),
+        samples: state.pixels.iter().map(|pixel| pixel.samples).collect(),
+        region: traced_region(settings, state.film.width, state.film.height),
+    }
+}
+
+/// Pixels a render traces, all of them unless it's cropped
+pub fn traced_region(settings: &RenderSettings, width: usize, height: usize) -> Region {
+    settings.crop.map_or(Region::whole(width, height), |crop| {
+        crop.within(width, height)
+    })
+}
+
+/// Take samples into `state` until the render is finished
+pub fn advance(
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+    state: &mut RenderState,
+) {
+    let (width, height) = (state.film.width, state.film.height);
+    let region = traced_region(settings, width, height);
+    let traced = region.width * region.height;
+    let batch = settings.samples.max(1);
+    let max_samples = settings.max_samples.max(batch);
+    let (previously, started) = (state.elapsed, Instant::now());
+    let (mut last_report, mut last_checkpoint) = (started, started);
+    let mut checkpoint_hash = settings.checkpoint.as_ref().and_then(|path| {
+        checkpoint::scene_hash(visualiser, scene, settings)
+            .map_err(|e| println!("Can't save checkpoints to {}: {}", path, e))
+            .ok()
+    });
+    loop {
+        let out_of_time = settings
+            .time_budget
+            .is_some_and(|budget| state.elapsed >= budget);
+        let counts: Vec<u32> = state
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(index, pixel)| {
+                if !region.contains(index % width, index / width) {
+                    return 0;
+                }
+                let more = pixel.samples < max_samples
+                    && !out_of_time
+                    && pixel.error() > settings.noise_threshold;
+                if pixel.samples == 0 || more {
+                    batch.min(max_samples - pixel.samples)
+                } else {
+                    0
+                }
+            })
+            .collect();
+        if counts.iter().all(|&count| count == 0) {
+            break;
+        }
+        state.rays += render_pass(
+            visualiser,
+            scene,
+            settings,
+            &counts,
+            &mut state.pixels,
+            &mut state.film,
+        );
+        state.samples += counts.iter().map(|&count| count as u64).sum::<u64>();
+        state.elapsed = previously + started.elapsed();
+
+        if settings.progress && last_report.elapsed() >= PROGRESS_INTERVAL {
+            // Finished pixels count as fully sampled, so it's the most there is to do
+            let done = state
+                .pixels
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| region.contains(index % width, index / width))
+                .map(|(_, pixel)| {
+                    if pixel.error() > settings.noise_threshold {
+                        pixel.samples as f64
+                    } else {
+                        max_samples as f64
+                    }
+                })
+                .sum::<f64>()
+                / (traced as f64 * max_samples as f64);
+            report_progress(state, done, settings.time_budget);
+            last_report = Instant::now();
+        }
+        if last_checkpoint.elapsed() >= settings.checkpoint_interval {
+            if let Some(ref path) = settings.checkpoint_image {
+                save_image(path, width as u32, &state.film.colours());
+            }
+            save_checkpoint(settings, &mut checkpoint_hash, state);
+            last_checkpoint = Instant::now();
+        }
+    }
+    if settings.progress {
+        report_progress(state, 1.0, None);
+    }
+    save_checkpoint(settings, &mut checkpoint_hash, state);
+}
+
+/// Save `state` to the checkpoint file, if there is one and the scene could be hashed,
+/// giving up on checkpoints if saving fails
+fn save_checkpoint(settings: &RenderSettings, hash: &mut Option<u64>, state: &RenderState) {
+    if let (Some(path), Some(scene_hash)) = (settings.checkpoint.as_ref(), *hash) {
+        if let Err(e) = checkpoint::save(path, scene_hash, state) {
+            println!("Problem saving {}: {}", path, e);
+            *hash = None;
+        }
+    }
+}
+
+/// Print how far a render has got on stderr. `done` is the fraction of the samples it
+/// could take that it has, though with a time budget it's how long it has left that
+/// counts.
+pub fn report_progress(state: &RenderState, done: f64, time_budget: Option<Duration>) {
+    let seconds = state.elapsed.as_secs_f64();
+    let done = match time_budget {
+        Some(budget) => seconds / budget.as_secs_f64().max(1e-3),
+        None => done,
+    }
+    .min(1.0);
+    let eta = if done > 0.0 {
+        format!("{:.1}s", seconds * (1.0 - done) / done)
+    } else {
+        "unknown".to_string()
+    };
+    let rate = |count: u64| count as f64 / seconds.max(1e-3);
+    eprintln!(
+        "{:5.1}% after {:.1}s, {} left, {:.0} samples/s, {:.0} rays/s",
+        100.0 * done,
+        seconds,
+        eta,
+        rate(state.samples),
+        rate(state.rays)
+    );
+}
+
+/// Take `counts` more samples in each pixel. Rows are dealt out between the threads,
+/// each with its own sampler, and splatted into films of their own. As every pixel has
+/// its own samples, and the rows' films are merged in order, the image is the same
+/// however many threads there are. Returns the number of rays cast.
+fn render_pass(
+    visualiser: &Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+    counts: &[u32],
+    pixels: &mut [PixelStats],
+    film: &mut Film,
+) -> u64 {
+    let (width, height) = (film.width, film.height);
+    let threads = settings.threads.max(1);
+    let mut rows: Vec<Option<Film>> = (0..height).map(|_| None).collect();
+    type Share<'a> = Vec<(usize, &'a mut Option<Film>, &'a mut [PixelStats], &'a [u32])>;
+    let mut shares: Vec<Share> = (0..threads).map(|_| Vec::new()).collect();
+    let row_pixels = pixels.chunks_mut(width).zip(counts.chunks(width));
+    for (y, (row, (row_pixels, row_counts))) in rows.iter_mut().zip(row_pixels).enumerate() {
+        if row_counts.iter().any(|&count| count > 0) {
+            shares[y % threads].push((y, row, row_pixels, row_counts));
+        }
+    }
+    let rays = std::thread::scope(|scope| {
+        let handles: Vec<_> = shares
+            .into_iter()
+            .map(|share| {
+                scope.spawn(move || {
+                    let rays_before = rays_cast();
+                    let mut sampler = settings
+                        .sampler
+                        .create(settings.seed, settings.samples.max(1));
+                    for (y, row, row_pixels, row_counts) in share {
+                        let region = Region {
+                            x: 0,
+                            y,
+                            width,
+                            height: 1,
+                        };
+                        let mut row_film =
+                            Film::reached_from(width, height, region, settings.filter);
+                        render_row(
+                            visualiser,
+                            scene,
+                            y as u32,
+                            row_counts,
+                            settings.packets,
+                            sampler.as_mut(),
+                            &mut row_film,
+                            row_pixels,
+                        );
+                        *row = Some(row_film);
+                    }
+                    rays_cast() - rays_before
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum()
+    });
+    for row in rows.iter().flatten() {
+        film.merge(row);
+    }
+    rays
+}
+
+/// Save rendered colours as an image
+pub fn save_image(path: &str, width: u32, colours: &[ColourFloat]) {
+    let height = colours.len() as u32 / width.max(1);
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb(as_int(colours[(y * width + x) as usize]))
+    });
+    match image.save(path) {
+        Ok(_) => println!("Saved {} successfully", path),
+        Err(e) => println!("Problem saving {}: {}", path, e),
+    }
+}
+
+/// Save an image of how many samples each pixel took, from dark blue for the fewest
+/// through red to yellow for the most
+pub fn save_heatmap(path: &str, width: u32, samples: &[u32]) {
+    let (fewest, most) = (
+        samples.iter().copied().min().unwrap_or(0),
+        samples.iter().copied().max().unwrap_or(0),
+    );
+    let stops = [
+        [0.0, 0.0, 0.3],
+        [0.0, 0.2, 1.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+    ];
+    let height = samples.len() as u32 / width.max(1);
+    let heatmap = image::RgbImage::from_fn(width, height, |x, y| {
+        let count = samples[(y * width + x) as usize];
+        let t = (count - fewest) as f32 / (most - fewest).max(1) as f32;
+        let position = t * (stops.len() - 1) as f32;
+        let stop = (position as usize).min(stops.len() - 2);
+        let blend = position - stop as f32;
+        let [r, g, b] = [0, 1, 2].map(|i| {
+            let value = stops[stop][i] * (1.0 - blend) + stops[stop + 1][i] * blend;
+            (value * 255.0) as u8
+        });
+        image::Rgb([r, g, b])
+    });
+    match heatmap.save(path) {
+        Ok(_) => println!("Saved {} successfully", path),
+        Err(e) => println!("Problem saving {}: {}", path, e),
+    }
+}
+
+/// Render to the visualiser's image without opening a window, carrying on from the
+/// checkpoint file if there is one. Fails if the checkpoint is unreadable or for a
+/// different scene.
+pub fn render_headless(
+    visualiser: &mut Visualiser,
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> io::Result<()> {
+    let resumed = match settings.checkpoint {
+        Some(ref path) => checkpoint::resume(path, visualiser, scene, settings)?,
+        None => None,
+    };
+    let rendered = match resumed {
+        Some(state) => {
+            println!("Resuming from {} samples", state.samples);
+            render_from(visualiser, scene, settings, state)
         }
-        colour_float /= ANTIALIAS_SAMPLES as f32;
-        // Draw to screen buffer
-        let colour_rgba = as_int4(colour_float);
-        pix.copy_from_slice(&colour_rgba);
-        // Save to render image
-        let colour_rgb = as_int(colour_float);
-        visualiser.put_pixel(x, y, colour_rgb)
+        None => render(visualiser, scene, settings),
+    };
+    keep(visualiser, settings, &rendered);
+    Ok(())
+}
+
+/// Put what was rendered in the visualiser's image, and save its heatmap if wanted
+pub fn keep(visualiser: &mut Visualiser, settings: &RenderSettings, rendered: &Rendered) {
+    store(visualiser, rendered);
+    save_heatmap_if_wanted(visualiser, settings, rendered);
+}
+
+/// Copy the traced pixels' colours into the visualiser's image, keeping the rest
+fn store(visualiser: &mut Visualiser, rendered: &Rendered) {
+    let width = visualiser.screen.width() as usize;
+    for (idx, colour) in rendered.colours.iter().enumerate() {
+        let (x, y) = (idx % width, idx / width);
+        if rendered.region.contains(x, y) {
+            visualiser.put_pixel(x as u32, y as u32, as_int(*colour));
+        }
+    }
+}
+
+fn draw(visualiser: &mut Visualiser, scene: &Scene, settings: &RenderSettings, screen: &mut [u8]) {
+    let rendered = render(visualiser, scene, settings);
+    let width = visualiser.screen.width() as usize;
+    for (idx, (pix, colour)) in screen
+        .chunks_exact_mut(4)
+        .zip(rendered.colours.iter())
+        .enumerate()
+    {
+        // Draw to screen buffer, leaving what's outside the crop as it was
+        if rendered.region.contains(idx % width, idx / width) {
+            pix.copy_from_slice(&as_int4(*colour));
+        }
+    }
+    // Save to render image
+    keep(visualiser, settings, &rendered);
+}
+
+fn save_heatmap_if_wanted(visualiser: &Visualiser, settings: &RenderSettings, rendered: &Rendered) {
+    if let Some(ref path) = settings.heatmap {
+        save_heatmap(path, visualiser.screen.width(), &rendered.samples);
     }
 }
 
@@ -102,7 +684,7 @@ fn create_window(title: &str, event_loop: &EventLoop<()>) -> (winit::window::Win
     let window = winit::window::WindowBuilder::new()
         .with_visible(false)
         .with_title(title)
-        .build(&event_loop)
+        .build(event_loop)
         .unwrap();
     let hidpi_factor = window.scale_factor();
 
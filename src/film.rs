@@ -0,0 +1,324 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::raytracing::*;
+
+/// Reconstruction filter, weighting each sample's contribution to the pixels around it
+/// by its offset from their centres. Filters wider than a pixel blend neighbouring
+/// pixels' samples, smoothing jagged edges.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    /// Even weight, a radius of 0.5 averaging just the pixel's own samples
+    Box { radius: f32 },
+    /// Weight falling linearly to zero at the radius
+    Tent { radius: f32 },
+    /// Gaussian of standard deviation `sigma`, shifted down to reach zero at the radius
+    Gaussian { radius: f32, sigma: f32 },
+    /// Mitchell and Netravali's cubic, from blurry (`b` = 1) to ringing (`c` large).
+    /// Its slightly negative lobes keep edges sharp.
+    Mitchell { radius: f32, b: f32, c: f32 },
+    /// Sinc windowed by a wider sinc, sharp but with some ringing at hard edges
+    Lanczos { radius: f32 },
+}
+
+impl Filter {
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    /// Same filter stretched to `radius`, except that Lanczos gains more lobes instead
+    pub fn with_radius(self, radius: f32) -> Self {
+        let scale = radius / self.radius();
+        match self {
+            Filter::Box { .. } => Filter::Box { radius },
+            Filter::Tent { .. } => Filter::Tent { radius },
+            Filter::Gaussian { sigma, .. } => Filter::Gaussian {
+                radius,
+                sigma: sigma * scale,
+            },
+            Filter::Mitchell { b, c, .. } => Filter::Mitchell { radius, b, c },
+            Filter::Lanczos { .. } => Filter::Lanczos { radius },
+        }
+    }
+
+    /// Weight of a pixel whose centre is offset by (x, y) pixels from a sample
+    pub fn eval(&self, x: f32, y: f32) -> f32 {
+        self.eval_1d(x) * self.eval_1d(y)
+    }
+
+    fn eval_1d(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        // Half open, so a sample on the edge between two pixels only counts for one
+        if x <= -radius || x > radius {
+            return 0.0;
+        }
+        let x = x.abs();
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { .. } => radius - x,
+            Filter::Gaussian { sigma, .. } => {
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { b, c, .. } => {
+                // The cubic spans two units either side
+                let x = 2.0 * x / radius;
+                let (x2, x3) = (x * x, x * x * x);
+                let weight = if x < 1.0 {
+                    (12.0 - 9.0 * b - 6.0 * c) * x3
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x2
+                        + (6.0 - 2.0 * b)
+                } else {
+                    (-b - 6.0 * c) * x3
+                        + (6.0 * b + 30.0 * c) * x2
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c)
+                };
+                weight / 6.0
+            }
+            Filter::Lanczos { .. } => sinc(x) * sinc(x / radius),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Filter::Box { .. } => "box",
+            Filter::Tent { .. } => "tent",
+            Filter::Gaussian { .. } => "gaussian",
+            Filter::Mitchell { .. } => "mitchell",
+            Filter::Lanczos { .. } => "lanczos",
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        }
+    }
+}
+
+/// Filters by name, with their usual sizes
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        [
+            Filter::Box { radius: 0.5 },
+            Filter::Tent { radius: 1.0 },
+            Filter::default(),
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            Filter::Lanczos { radius: 2.0 },
+        ]
+        .iter()
+        .find(|filter| filter.name() == name)
+        .copied()
+        .ok_or_else(|| format!("Unknown filter {}", name))
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Rectangle of pixels, from (x, y) up to but not including (x + width, y + height)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    /// Every pixel of a `width` by `height` image
+    pub fn whole(width: usize, height: usize) -> Self {
+        Region {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Smallest region holding both corner pixels, in either order
+    pub fn spanning((x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> Self {
+        Region {
+            x: x0.min(x1),
+            y: y0.min(y1),
+            width: x0.max(x1) - x0.min(x1) + 1,
+            height: y0.max(y1) - y0.min(y1) + 1,
+        }
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Part of the region inside a `width` by `height` image, which may be empty
+    pub fn within(&self, width: usize, height: usize) -> Self {
+        let (x, y) = (self.x.min(width), self.y.min(height));
+        Region {
+            x,
+            y,
+            width: (self.x + self.width).min(width) - x,
+            height: (self.y + self.height).min(height) - y,
+        }
+    }
+}
+
+/// Region from "x,y,width,height"
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let numbers: Vec<usize> = text
+            .split(',')
+            .map(|number| number.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Regions are x,y,width,height in pixels, not {}", text))?;
+        match numbers[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            [_, _, _, _] => Err(format!("Region {} is empty", text)),
+            _ => Err(format!(
+                "Regions are x,y,width,height in pixels, not {}",
+                text
+            )),
+        }
+    }
+}
+
+/// Filtered samples accumulating into an image, or a region of one.
+///
+/// Each sample is splatted into every pixel its filter reaches, adding its weighted
+/// colour and its weight, and a pixel's colour is the first over the second. Films of
+/// parts of an image are merged into one of the whole.
+pub struct Film {
+    pub filter: Filter,
+    /// Size of the whole image
+    pub width: usize,
+    pub height: usize,
+    /// Pixels held, which samples outside it can still reach
+    pub region: Region,
+    sums: Vec<ColourFloat>,
+    weights: Vec<f32>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        Film::region(width, height, Region::whole(width, height), filter)
+    }
+
+    pub fn region(width: usize, height: usize, region: Region, filter: Filter) -> Self {
+        let pixels = region.width * region.height;
+        Film {
+            filter,
+            width,
+            height,
+            region,
+            sums: vec![ColourFloat::new(0.0, 0.0, 0.0); pixels],
+            weights: vec![0.0; pixels],
+        }
+    }
+
+    /// Film holding every pixel that samples taken within `rendered` reach
+    pub fn reached_from(width: usize, height: usize, rendered: Region, filter: Filter) -> Self {
+        let reach = filter.radius().ceil() as usize;
+        let (x0, y0) = (
+            rendered.x.saturating_sub(reach),
+            rendered.y.saturating_sub(reach),
+        );
+        let x1 = (rendered.x + rendered.width + reach).min(width);
+        let y1 = (rendered.y + rendered.height + reach).min(height);
+        let region = Region {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        };
+        Film::region(width, height, region, filter)
+    }
+
+    /// Add a sample at image position (x, y), where pixel (i, j) spans i to i + 1
+    /// across and j to j + 1 down
+    pub fn splat(&mut self, [x, y]: [f32; 2], colour: ColourFloat) {
+        let radius = self.filter.radius();
+        let region = self.region;
+        // Pixels whose centres are within the radius
+        let first =
+            |at: f32, start: usize| ((at - 0.5 - radius).floor() + 1.0).max(start as f32) as usize;
+        let last = |at: f32, end: usize| {
+            ((at - 0.5 + radius).floor() + 1.0).clamp(0.0, end as f32) as usize
+        };
+        for py in first(y, region.y)..last(y, region.y + region.height) {
+            for px in first(x, region.x)..last(x, region.x + region.width) {
+                let weight = self.filter.eval(px as f32 + 0.5 - x, py as f32 + 0.5 - y);
+                if weight != 0.0 {
+                    let index = (py - region.y) * region.width + (px - region.x);
+                    self.sums[index] += weight * colour;
+                    self.weights[index] += weight;
+                }
+            }
+        }
+    }
+
+    /// Add in what's been splatted into `other`, where the two overlap
+    pub fn merge(&mut self, other: &Film) {
+        let (region, theirs) = (self.region, other.region);
+        for y in region.y.max(theirs.y)..(region.y + region.height).min(theirs.y + theirs.height) {
+            for x in region.x.max(theirs.x)..(region.x + region.width).min(theirs.x + theirs.width)
+            {
+                let mine = (y - region.y) * region.width + (x - region.x);
+                let other_index = (y - theirs.y) * theirs.width + (x - theirs.x);
+                self.sums[mine] += other.sums[other_index];
+                self.weights[mine] += other.weights[other_index];
+            }
+        }
+    }
+
+    /// Sums of weighted colours and of weights for the pixels held, row by row, e.g.
+    /// to save them
+    pub fn totals(&self) -> (&[ColourFloat], &[f32]) {
+        (&self.sums, &self.weights)
+    }
+
+    pub fn totals_mut(&mut self) -> (&mut [ColourFloat], &mut [f32]) {
+        (&mut self.sums, &mut self.weights)
+    }
+
+    /// Colours of the pixels held, row by row
+    pub fn colours(&self) -> Vec<ColourFloat> {
+        self.sums
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(&sum, &weight)| {
+                if weight > 0.0 {
+                    sum / weight
+                } else {
+                    ColourFloat::new(0.0, 0.0, 0.0)
+                }
+            })
+            .collect()
+    }
+}
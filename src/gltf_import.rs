@@ -0,0 +1,264 @@
+use cgmath::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::instance::{Group, Transform};
+use crate::mesh::*;
+use crate::mipmap::*;
+use crate::raytracing::*;
+use crate::scene_graph::*;
+use crate::texture::*;
+use gltf::khr_lights_punctual::Kind;
+use image::RgbImage;
+
+/// Scene loaded from glTF, with the first camera found in it if there is one.
+pub struct GltfScene {
+    pub scene: Scene,
+    pub camera: Option<Camera>,
+}
+
+/// Load a `.gltf` or `.glb` file, along with any buffers and images it refers to
+pub fn load<P: AsRef<Path>>(path: P) -> gltf::Result<GltfScene> {
+    let (document, buffers, images) = gltf::import(path)?;
+    Ok(convert(&document, &buffers, &images))
+}
+
+/// Load glTF from memory, e.g. a `.glb` file, with any other data embedded in it
+pub fn load_slice(bytes: &[u8]) -> gltf::Result<GltfScene> {
+    let (document, buffers, images) = gltf::import_slice(bytes)?;
+    Ok(convert(&document, &buffers, &images))
+}
+
+struct Converter<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    // Each glTF mesh is converted once, and instanced by every node that uses it
+    meshes: HashMap<usize, Arc<Object>>,
+    textures: HashMap<usize, Arc<ImageTexture>>,
+    lights: Vec<Light>,
+    camera: Option<Camera>,
+}
+
+fn convert(
+    document: &gltf::Document,
+    buffers: &[gltf::buffer::Data],
+    images: &[gltf::image::Data],
+) -> GltfScene {
+    let mut converter = Converter {
+        buffers,
+        images,
+        meshes: HashMap::new(),
+        textures: HashMap::new(),
+        lights: Vec::new(),
+        camera: None,
+    };
+    let mut scene = Scene::new(Vec::new());
+    let gltf_scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next());
+    if let Some(gltf_scene) = gltf_scene {
+        for node in gltf_scene.nodes() {
+            scene
+                .graph
+                .add(converter.node(&node, Transform::identity()));
+        }
+    }
+    scene.lights = converter.lights;
+    GltfScene {
+        scene,
+        camera: converter.camera,
+    }
+}
+
+impl<'a> Converter<'a> {
+    /// Scene graph node for a glTF node and its children. Lights and cameras aren't
+    /// part of the graph, so are placed in the world using the `parent` transform.
+    fn node(&mut self, node: &gltf::Node, parent: Transform) -> Node {
+        let name = match node.name() {
+            Some(name) => name.to_string(),
+            None => format!("node{}", node.index()),
+        };
+        let mut scene_node = Node::new(&name);
+        scene_node.transform = Transform::from(node.transform().matrix());
+        let transform = parent * scene_node.transform;
+        scene_node.object = node.mesh().map(|mesh| self.mesh(&mesh));
+        if let Some(light) = node.light() {
+            self.lights.push(convert_light(&light, transform));
+        }
+        if let Some(camera) = node.camera() {
+            if self.camera.is_none() {
+                self.camera = convert_camera(&camera, transform);
+            }
+        }
+        scene_node.children = node
+            .children()
+            .map(|child| self.node(&child, transform))
+            .collect();
+        scene_node
+    }
+
+    fn mesh(&mut self, mesh: &gltf::Mesh) -> Arc<Object> {
+        if let Some(object) = self.meshes.get(&mesh.index()) {
+            return Arc::clone(object);
+        }
+        let primitives = mesh
+            .primitives()
+            .filter(|p| p.mode() == gltf::mesh::Mode::Triangles)
+            .filter_map(|p| self.primitive(&p))
+            .collect();
+        let object = Arc::new(Object::Group(Group::new(primitives)));
+        self.meshes.insert(mesh.index(), Arc::clone(&object));
+        object
+    }
+
+    /// One glTF primitive becomes a `Mesh`, as it has its own buffers and a single material
+    fn primitive(&mut self, primitive: &gltf::Primitive) -> Option<Object> {
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let positions: Vec<Point> = reader.read_positions()?.map(Point::from).collect();
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        let material = primitive.material();
+        let pbr = material.pbr_metallic_roughness();
+        let faces = indices
+            .chunks_exact(3)
+            .map(|face| Face {
+                indices: [face[0], face[1], face[2]],
+                material: 0,
+            })
+            .collect();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let mut mesh = Mesh::new(
+            positions,
+            faces,
+            ColourFloat::new(r, g, b) * 255.0,
+            vec![convert_material(&material)],
+        );
+        mesh.normals = reader
+            .read_normals()
+            .map(|normals| normals.map(Vector::from).collect());
+        mesh.colours = reader.read_colors(0).map(|colours| {
+            colours
+                .into_rgb_f32()
+                .map(|c| ColourFloat::from(c) * 255.0)
+                .collect()
+        });
+
+        let base_colour = pbr.base_color_texture();
+        let normal_texture = material.normal_texture();
+        // Only one set of texture coordinates is supported, shared by all textures
+        let tex_coord = base_colour
+            .as_ref()
+            .map(|t| t.tex_coord())
+            .or_else(|| normal_texture.as_ref().map(|t| t.tex_coord()))
+            .unwrap_or(0);
+        mesh.uvs = reader.read_tex_coords(tex_coord).map(|uvs| {
+            // glTF puts v = 0 at the top of the image, textures here have it at the bottom
+            uvs.into_f32().map(|[u, v]| Uv::new(u, 1.0 - v)).collect()
+        });
+        if mesh.uvs.is_some() {
+            mesh.texture = base_colour.map(|info| Texture::Image(self.texture(&info.texture())));
+            mesh.normal_map = normal_texture
+                .map(|info| NormalMap::TangentSpace(Texture::Image(self.texture(&info.texture()))));
+        }
+        Some(Object::Mesh(mesh))
+    }
+
+    fn texture(&mut self, texture: &gltf::Texture) -> Arc<ImageTexture> {
+        let index = texture.source().index();
+        if let Some(image) = self.textures.get(&index) {
+            return Arc::clone(image);
+        }
+        let image = Arc::new(ImageTexture::new(
+            &to_rgb_image(&self.images[index]),
+            TextureFilter::Trilinear,
+        ));
+        self.textures.insert(index, Arc::clone(&image));
+        image
+    }
+}
+
+/// Shiny metals become mirrors, unlit materials keep their flat colour and everything
+/// else is diffuse
+fn convert_material(material: &gltf::Material) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    if material.unlit() {
+        Material::Diffuse
+    } else if pbr.metallic_factor() > 0.5 && pbr.roughness_factor() < 0.5 {
+        Material::Specular
+    } else {
+        Material::Lambertian
+    }
+}
+
+/// Intensities are used as they are, lux for directional lights and candela for the others
+fn convert_light(light: &gltf::khr_lights_punctual::Light, transform: Transform) -> Light {
+    let colour = ColourFloat::from(light.color()) * light.intensity();
+    let position = (transform * Point::zero().extend(1.0)).truncate();
+    // Lights shine down their local -z axis
+    let direction = (transform * -Vector::unit_z().extend(0.0))
+        .truncate()
+        .normalize();
+    match light.kind() {
+        Kind::Directional => Light::Directional {
+            direction: -direction,
+            colour,
+        },
+        Kind::Point => Light::Point { position, colour },
+        Kind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => Light::Spot {
+            position,
+            direction,
+            colour,
+            cos_inner: inner_cone_angle.cos(),
+            cos_outer: outer_cone_angle.cos(),
+        },
+    }
+}
+
+/// The camera here can only turn about the vertical axis, so any pitch or roll is dropped
+fn convert_camera(camera: &gltf::Camera, transform: Transform) -> Option<Camera> {
+    let yfov = match camera.projection() {
+        gltf::camera::Projection::Perspective(perspective) => perspective.yfov(),
+        gltf::camera::Projection::Orthographic(_) => return None,
+    };
+    let position = (transform * Point::zero().extend(1.0)).truncate();
+    let forward = (transform * -Vector::unit_z().extend(0.0)).truncate();
+    let yaw = cgmath::Deg::from(cgmath::Rad((-forward.x).atan2(-forward.z)));
+    Some(Camera::posed(position, 1.0 / (yfov / 2.0).tan(), yaw))
+}
+
+fn to_rgb_image(data: &gltf::image::Data) -> RgbImage {
+    use gltf::image::Format::*;
+    // Bytes per channel and channel order
+    let (size, channels): (usize, &[usize]) = match data.format {
+        R8 => (1, &[0, 0, 0]),
+        // Two channel images repeat green in blue
+        R8G8 => (1, &[0, 1, 1]),
+        R8G8B8 => (1, &[0, 1, 2]),
+        R8G8B8A8 => (1, &[0, 1, 2, 3]),
+        B8G8R8 => (1, &[2, 1, 0]),
+        B8G8R8A8 => (1, &[2, 1, 0, 3]),
+        R16 => (2, &[0, 0, 0]),
+        R16G16 => (2, &[0, 1, 1]),
+        R16G16B16 => (2, &[0, 1, 2]),
+        R16G16B16A16 => (2, &[0, 1, 2, 3]),
+    };
+    let components = match data.format {
+        R8 | R16 => 1,
+        R8G8 | R16G16 => 2,
+        R8G8B8 | B8G8R8 | R16G16B16 => 3,
+        _ => 4,
+    };
+    let stride = components * size;
+    RgbImage::from_fn(data.width, data.height, |x, y| {
+        let pixel = (y * data.width + x) as usize * stride;
+        // Keep the most significant byte of 16 bit little-endian channels
+        let channel = |c: usize| data.pixels[pixel + channels[c] * size + size - 1];
+        image::Rgb([channel(0), channel(1), channel(2)])
+    })
+}
@@ -0,0 +1,149 @@
+use cgmath::prelude::*;
+use std::sync::Arc;
+
+use crate::raytracing::*;
+
+pub type Transform = cgmath::Matrix4<f32>;
+
+/// A shared object placed in the world by a transform.
+///
+/// Many instances can point at the same object, so geometry is only stored once.
+pub struct Instance {
+    pub object: Arc<Object>,
+    transform: Transform,
+    inverse: Transform,
+}
+
+impl Instance {
+    pub fn new(object: Arc<Object>, transform: Transform) -> Self {
+        let inverse = transform
+            .invert()
+            .expect("Instance transform must be invertible");
+        Instance {
+            object,
+            transform,
+            inverse,
+        }
+    }
+
+    pub fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    pub fn to_object_point(&self, point: Point) -> Point {
+        (self.inverse * point.extend(1.0)).truncate()
+    }
+
+    pub fn to_object_vector(&self, vector: Vector) -> Vector {
+        (self.inverse * vector.extend(0.0)).truncate()
+    }
+
+    /// Normals are carried back out by the inverse transpose, so they stay perpendicular
+    pub fn to_world_normal(&self, normal: Vector) -> Vector {
+        (self.inverse.transpose() * normal.extend(0.0))
+            .truncate()
+            .normalize()
+    }
+
+    fn to_object_footprint(&self, footprint: &Footprint) -> Footprint {
+        Footprint {
+            dpdx: self.to_object_vector(footprint.dpdx),
+            dpdy: self.to_object_vector(footprint.dpdy),
+        }
+    }
+
+    pub fn get_material(&self, location: &IntersectionLocation) -> &Material {
+        self.object.get_material(location)
+    }
+
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
+        let normal = self
+            .object
+            .get_normal(self.to_object_point(position), location);
+        self.to_world_normal(normal)
+    }
+}
+
+impl Coloured for Instance {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        // Textures are evaluated in object space, so they move with the object
+        self.object.get_colour(
+            self.to_object_point(position),
+            location,
+            &self.to_object_footprint(footprint),
+        )
+    }
+}
+
+impl Intersectable for Instance {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let dir = self.to_object_vector(ray.dir);
+        // Scaling stretches the direction, distances along it are scaled back afterwards
+        let scale = dir.magnitude();
+        let object_ray = Ray::new(self.to_object_point(ray.start), dir / scale);
+        let mut location = self.object.intersection(&object_ray)?;
+        location.distance /= scale;
+        Some(location)
+    }
+}
+
+/// Several objects treated as one, e.g. the triangles making up a model to be instanced.
+pub struct Group {
+    pub objects: Vec<Object>,
+}
+
+impl Group {
+    pub fn new(objects: Vec<Object>) -> Self {
+        Group { objects }
+    }
+
+    fn child<'a, 'b>(
+        &'a self,
+        location: &'b IntersectionLocation,
+    ) -> (&'a Object, &'b IntersectionLocation) {
+        let child = location
+            .inner
+            .as_ref()
+            .expect("Group intersection is missing its child");
+        (&self.objects[location.primitive], child)
+    }
+
+    pub fn get_material(&self, location: &IntersectionLocation) -> &Material {
+        let (object, child) = self.child(location);
+        object.get_material(child)
+    }
+
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
+        let (object, child) = self.child(location);
+        object.get_normal(position, child)
+    }
+}
+
+impl Coloured for Group {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let (object, child) = self.child(location);
+        object.get_colour(position, child, footprint)
+    }
+}
+
+impl Intersectable for Group {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (index, location) = closest_intersection(&self.objects, ray)?;
+        Some(IntersectionLocation {
+            distance: location.distance,
+            texture_coords: TextureCoords::None,
+            primitive: index,
+            inner: Some(Box::new(location)),
+        })
+    }
+}
@@ -1,25 +1,268 @@
+mod background;
+mod bvh;
+mod checkpoint;
 mod cornell_box;
+mod csg;
+mod distributed;
 mod draw;
+mod film;
+mod gltf_import;
+mod instance;
+mod medium;
+mod mesh;
+mod mesh_import;
+mod mipmap;
+mod packet;
+mod primitives;
 mod raytracing;
+mod sampler;
+mod sampling;
+mod scene_file;
+mod scene_graph;
+mod simd;
+mod sky;
 #[cfg(test)]
 mod tests;
+mod texture;
 mod utils;
 
 use crate::draw::*;
 use crate::raytracing::*;
 use pixels::Error;
 
-fn main() -> Result<(), Error> {
-    let p0 = cgmath::Vector3 {
-        x: 0.0,
-        y: 0.0,
-        z: 25.0,
+const USAGE: &str = "Usage: raytracer [--seed N] [--threads N] [--samples N] [--max-samples N] \
+                     [--noise-threshold X] [--time-budget SECONDS] [--heatmap FILE] \
+                     [--checkpoint-image FILE] [--checkpoint FILE] [--checkpoint-interval SECONDS] \
+                     [--sampler independent|stratified|halton|sobol|blue-noise] \
+                     [--filter box|tent|gaussian|mitchell|lanczos] [--filter-radius R] \
+                     [--crop X,Y,WIDTH,HEIGHT] [--no-packets] [--headless] \
+                     [--coordinator ADDRESS | --worker ADDRESS] [SCENE]";
+
+/// Options from the command line
+struct Args {
+    scene: Option<String>,
+    settings: RenderSettings,
+    /// Render straight to `render.png` without opening a window, reporting progress
+    headless: bool,
+    /// Listen here for workers and have them render the image, saving it like `headless`
+    coordinator: Option<String>,
+    /// Render tiles for the coordinator listening here
+    worker: Option<String>,
+}
+
+fn parse_args(words: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut args = Args {
+        scene: None,
+        settings: RenderSettings::default(),
+        headless: false,
+        coordinator: None,
+        worker: None,
+    };
+    let mut filter_radius = None;
+    let mut max_samples = None;
+    let mut words = words.into_iter();
+    while let Some(word) = words.next() {
+        let mut value = |flag: &str| {
+            words
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match word.as_str() {
+            "--seed" => {
+                args.settings.seed = value("--seed")?
+                    .parse()
+                    .map_err(|_| "--seed must be a whole number".to_string())?
+            }
+            "--threads" => {
+                args.settings.threads = value("--threads")?
+                    .parse()
+                    .map_err(|_| "--threads must be a whole number".to_string())?
+            }
+            "--samples" => {
+                args.settings.samples = value("--samples")?
+                    .parse()
+                    .map_err(|_| "--samples must be a whole number".to_string())?
+            }
+            "--max-samples" => {
+                max_samples = Some(
+                    value("--max-samples")?
+                        .parse()
+                        .map_err(|_| "--max-samples must be a whole number".to_string())?,
+                )
+            }
+            "--noise-threshold" => {
+                args.settings.noise_threshold = value("--noise-threshold")?
+                    .parse()
+                    .map_err(|_| "--noise-threshold must be a number".to_string())?
+            }
+            "--time-budget" => {
+                args.settings.time_budget = Some(seconds("--time-budget", value("--time-budget")?)?)
+            }
+            "--heatmap" => args.settings.heatmap = Some(value("--heatmap")?),
+            "--checkpoint-image" => {
+                args.settings.checkpoint_image = Some(value("--checkpoint-image")?)
+            }
+            "--checkpoint" => args.settings.checkpoint = Some(value("--checkpoint")?),
+            "--checkpoint-interval" => {
+                args.settings.checkpoint_interval =
+                    seconds("--checkpoint-interval", value("--checkpoint-interval")?)?
+            }
+            "--sampler" => args.settings.sampler = value("--sampler")?.parse()?,
+            "--filter" => args.settings.filter = value("--filter")?.parse()?,
+            "--filter-radius" => {
+                let radius: f32 = value("--filter-radius")?
+                    .parse()
+                    .map_err(|_| "--filter-radius must be a number".to_string())?;
+                if radius <= 0.0 {
+                    return Err("--filter-radius must be positive".to_string());
+                }
+                filter_radius = Some(radius);
+            }
+            "--crop" => args.settings.crop = Some(value("--crop")?.parse()?),
+            "--no-packets" => args.settings.packets = false,
+            "--headless" => {
+                args.headless = true;
+                args.settings.progress = true;
+            }
+            "--coordinator" => {
+                args.coordinator = Some(value("--coordinator")?);
+                args.settings.progress = true;
+            }
+            "--worker" => args.worker = Some(value("--worker")?),
+            _ if word.starts_with("--") => return Err(format!("Unknown option {}", word)),
+            _ if args.scene.is_none() => args.scene = Some(word),
+            _ => return Err("Only one scene can be given".to_string()),
+        }
+    }
+    if args.coordinator.is_some() && args.worker.is_some() {
+        return Err("Can't be both a coordinator and a worker".to_string());
+    }
+    // Workers render each tile to the end, as the budget is for the whole image
+    let distributed = args.coordinator.is_some() || args.worker.is_some();
+    if distributed && max_samples.is_none() && args.settings.time_budget.is_some() {
+        return Err("Distributed renders need --max-samples rather than --time-budget".to_string());
+    }
+    // Applied last, whichever order the options come in
+    if let Some(radius) = filter_radius {
+        args.settings.filter = args.settings.filter.with_radius(radius);
+    }
+    // Given just a time budget, keep adding samples until it runs out
+    args.settings.max_samples = match (max_samples, args.settings.time_budget) {
+        (Some(max_samples), _) => max_samples,
+        (None, Some(_)) => u32::MAX,
+        (None, None) => args.settings.max_samples,
+    };
+    Ok(args)
+}
+
+fn seconds(flag: &str, text: String) -> Result<std::time::Duration, String> {
+    text.parse::<f32>()
+        .ok()
+        .filter(|seconds| *seconds >= 0.0)
+        .map(std::time::Duration::from_secs_f32)
+        .ok_or_else(|| format!("{} must be a number of seconds", flag))
+}
+
+/// Load the scene file given on the command line, or fall back to the Cornell box
+fn load_scene(path: Option<String>) -> (Scene, Option<Camera>) {
+    let path = match path {
+        Some(path) => path,
+        None => return (crate::cornell_box::get_scene(), None),
+    };
+    match read_scene(&path) {
+        Ok(loaded) => {
+            println!("Loaded {} successfully", path);
+            loaded
+        }
+        Err(e) => panic!("Problem loading {}: {}", path, e),
+    }
+}
+
+/// Scene file, glTF file or a lone PLY or STL mesh, picked by the file's extension
+fn read_scene(path: &str) -> Result<(Scene, Option<Camera>), String> {
+    // A lone mesh is a node named after its file, so picking it reports that
+    let lone_mesh = |mesh: std::io::Result<mesh::Mesh>| {
+        let mesh = mesh.map_err(|e| e.to_string())?;
+        let name = std::path::Path::new(path)
+            .file_stem()
+            .map_or_else(|| path.into(), |stem| stem.to_string_lossy());
+        let mut scene = Scene::new(Vec::new());
+        scene
+            .graph
+            .add(scene_graph::Node::with_object(&name, Object::Mesh(mesh)));
+        Ok((scene, None))
     };
-    let camera = Camera::new(p0, 1.0, cgmath::Deg(0.0));
+    let grey = ColourFloat::new(200.0, 200.0, 200.0);
+    if path.ends_with(".gltf") {
+        gltf_import::load(path)
+            .map(|loaded| (loaded.scene, loaded.camera))
+            .map_err(|e| e.to_string())
+    } else if path.ends_with(".glb") {
+        // Binary glTF has its buffers and images embedded, so is read in one go
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        gltf_import::load_slice(&bytes)
+            .map(|loaded| (loaded.scene, loaded.camera))
+            .map_err(|e| e.to_string())
+    } else if path.ends_with(".ply") {
+        lone_mesh(mesh_import::load_ply(path, grey, Material::Lambertian))
+    } else if path.ends_with(".stl") {
+        lone_mesh(mesh_import::load_stl(path, grey, Material::Lambertian))
+    } else {
+        scene_file::load(path).map_err(|e| e.to_string())
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let args = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(2);
+    });
+    let (scene, camera) = load_scene(args.scene.clone());
 
-    let visualiser = Visualiser::new(SCREEN_HEIGHT, SCREEN_WIDTH, camera);
+    let camera = camera.unwrap_or_else(|| {
+        let p0 = cgmath::Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 25.0,
+        };
+        Camera::new(p0, 1.0, cgmath::Deg(0.0))
+    });
 
-    let scene = crate::cornell_box::get_scene();
+    let mut visualiser = Visualiser::new(SCREEN_HEIGHT, SCREEN_WIDTH, camera);
 
-    return draw::render_scene(visualiser, scene);
+    if let Some(ref address) = args.coordinator {
+        let rendered = std::net::TcpListener::bind(address).and_then(|listener| {
+            println!("Waiting for workers on {}", address);
+            distributed::coordinate(&listener, &visualiser, &scene, &args.settings)
+        });
+        match rendered {
+            Ok(rendered) => draw::keep(&mut visualiser, &args.settings, &rendered),
+            Err(e) => {
+                eprintln!("Problem coordinating on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        visualiser.save();
+        return Ok(());
+    }
+    if let Some(ref address) = args.worker {
+        match distributed::work(address, &visualiser, &scene, &args.settings) {
+            Ok(tiles) => println!("Rendered {} tiles for {}", tiles, address),
+            Err(e) => {
+                eprintln!("Problem working for {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if args.headless {
+        if let Err(e) = draw::render_headless(&mut visualiser, &scene, &args.settings) {
+            let path = args.settings.checkpoint.unwrap_or_default();
+            eprintln!("Problem resuming from {}: {}", path, e);
+            std::process::exit(1);
+        }
+        visualiser.save();
+        return Ok(());
+    }
+    draw::render_scene(visualiser, scene, args.settings)
 }
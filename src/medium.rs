@@ -0,0 +1,283 @@
+use cgmath::prelude::*;
+use std::f32::consts::PI;
+
+use crate::csg::Solid;
+use crate::primitives::Frame;
+use crate::raytracing::*;
+use crate::sampler::Sampler;
+
+/// Henyey-Greenstein phase function, `g` from -1 (backward) through 0 (even) to 1 (forward).
+#[derive(Clone, Copy)]
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    /// Density over the sphere of light turning by an angle with cosine `cos_theta`
+    pub fn eval(&self, cos_theta: f32) -> f32 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    /// New direction for light travelling along `dir`, distributed by `eval`
+    pub fn sample(&self, dir: Vector, u: f32, v: f32) -> Vector {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        }
+        .clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = 2.0 * PI * v;
+        let frame = Frame::new(Point::zero(), dir);
+        frame
+            .to_world_vector(Vector::new(
+                sin_theta * phi.cos(),
+                cos_theta,
+                sin_theta * phi.sin(),
+            ))
+            .normalize()
+    }
+}
+
+/// Densities on a regular grid filling the box from `min` to `max`, zero outside it.
+pub struct VoxelGrid {
+    pub min: Point,
+    pub max: Point,
+    resolution: [usize; 3],
+    densities: Vec<f32>,
+    max_density: f32,
+}
+
+impl VoxelGrid {
+    /// `densities` are x-major: all of x for the first y and z, then the next y. Each
+    /// resolution must be at least 1.
+    pub fn new(min: Point, max: Point, resolution: [usize; 3], densities: Vec<f32>) -> Self {
+        assert!(
+            !resolution.contains(&0),
+            "Voxel grids need at least one voxel along each axis."
+        );
+        assert_eq!(densities.len(), resolution.iter().product::<usize>());
+        let max_density = densities.iter().cloned().fold(0.0, f32::max);
+        VoxelGrid {
+            min,
+            max,
+            resolution,
+            densities,
+            max_density,
+        }
+    }
+
+    /// Fill the grid by evaluating `density` at the centre of each voxel
+    pub fn from_fn(
+        min: Point,
+        max: Point,
+        resolution: [usize; 3],
+        density: impl Fn(Point) -> f32,
+    ) -> Self {
+        let mut densities = Vec::with_capacity(resolution.iter().product());
+        let size = max - min;
+        for z in 0..resolution[2] {
+            for y in 0..resolution[1] {
+                for x in 0..resolution[0] {
+                    let centre = min
+                        + Vector::new(
+                            size.x * (x as f32 + 0.5) / resolution[0] as f32,
+                            size.y * (y as f32 + 0.5) / resolution[1] as f32,
+                            size.z * (z as f32 + 0.5) / resolution[2] as f32,
+                        );
+                    densities.push(density(centre).max(0.0));
+                }
+            }
+        }
+        VoxelGrid::new(min, max, resolution, densities)
+    }
+
+    pub fn max_density(&self) -> f32 {
+        self.max_density
+    }
+
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    pub fn densities(&self) -> &[f32] {
+        &self.densities
+    }
+
+    fn voxel(&self, x: i64, y: i64, z: i64) -> f32 {
+        let [width, height, depth] = self.resolution;
+        let x = x.clamp(0, width as i64 - 1) as usize;
+        let y = y.clamp(0, height as i64 - 1) as usize;
+        let z = z.clamp(0, depth as i64 - 1) as usize;
+        self.densities[(z * height + y) * width + x]
+    }
+
+    /// Trilinear interpolation between voxel centres
+    pub fn density(&self, position: Point) -> f32 {
+        let size = self.max - self.min;
+        let relative = position - self.min;
+        let mut cell = [0.0; 3];
+        for i in 0..3 {
+            let t = relative[i] / size[i];
+            if !(0.0..=1.0).contains(&t) {
+                return 0.0;
+            }
+            cell[i] = t * self.resolution[i] as f32 - 0.5;
+        }
+        let (x0, y0, z0) = (cell[0].floor(), cell[1].floor(), cell[2].floor());
+        let (fx, fy, fz) = (cell[0] - x0, cell[1] - y0, cell[2] - z0);
+        let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let plane = |z: i64| {
+            lerp(
+                lerp(self.voxel(x0, y0, z), self.voxel(x0 + 1, y0, z), fx),
+                lerp(self.voxel(x0, y0 + 1, z), self.voxel(x0 + 1, y0 + 1, z), fx),
+                fy,
+            )
+        };
+        lerp(plane(z0), plane(z0 + 1), fz)
+    }
+}
+
+pub enum Density {
+    Homogeneous,
+    Grid(VoxelGrid),
+}
+
+/// Material that absorbs and scatters light throughout its volume.
+///
+/// Coefficients are per unit distance, and scaled by the density where it varies.
+pub struct Medium {
+    pub absorption: f32,
+    pub scattering: f32,
+    pub phase: HenyeyGreenstein,
+    pub density: Density,
+}
+
+impl Medium {
+    pub fn homogeneous(absorption: f32, scattering: f32, g: f32) -> Self {
+        Medium {
+            absorption,
+            scattering,
+            phase: HenyeyGreenstein { g },
+            density: Density::Homogeneous,
+        }
+    }
+
+    pub fn extinction(&self) -> f32 {
+        self.absorption + self.scattering
+    }
+
+    /// Fraction of collisions that scatter rather than absorb
+    pub fn albedo(&self) -> f32 {
+        if self.extinction() > 0.0 {
+            self.scattering / self.extinction()
+        } else {
+            0.0
+        }
+    }
+
+    fn density_at(&self, position: Point) -> f32 {
+        match self.density {
+            Density::Homogeneous => 1.0,
+            Density::Grid(ref grid) => grid.density(position),
+        }
+    }
+
+    fn max_density(&self) -> f32 {
+        match self.density {
+            Density::Homogeneous => 1.0,
+            Density::Grid(ref grid) => grid.max_density(),
+        }
+    }
+
+    /// Distance along `ray` of the first collision between `near` and `far`, if any.
+    ///
+    /// Uses delta tracking: tentative collisions against the maximum density,
+    /// accepted in proportion to the actual density there.
+    pub fn sample_collision(
+        &self,
+        ray: &Ray,
+        near: f32,
+        far: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<f32> {
+        let majorant = self.extinction() * self.max_density();
+        if majorant <= 0.0 {
+            return None;
+        }
+        let mut distance = near;
+        loop {
+            distance -= (1.0 - sampler.get_1d()).ln() / majorant;
+            if distance >= far {
+                return None;
+            }
+            let density = self.density_at(ray.start + distance * ray.dir);
+            if sampler.get_1d() * self.max_density() < density {
+                return Some(distance);
+            }
+        }
+    }
+
+    /// Fraction of light getting through between `near` and `far` along `ray`
+    pub fn transmittance(&self, ray: &Ray, near: f32, far: f32, sampler: &mut dyn Sampler) -> f32 {
+        let majorant = self.extinction() * self.max_density();
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+        match self.density {
+            Density::Homogeneous => (-self.extinction() * (far - near)).exp(),
+            Density::Grid(_) => {
+                // Ratio tracking, an unbiased estimate
+                let mut transmittance = 1.0;
+                let mut distance = near;
+                loop {
+                    distance -= (1.0 - sampler.get_1d()).ln() / majorant;
+                    if distance >= far {
+                        return transmittance;
+                    }
+                    let density = self.density_at(ray.start + distance * ray.dir);
+                    transmittance *= 1.0 - density / self.max_density();
+                    if transmittance <= 0.0 {
+                        return 0.0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A medium filling the inside of a closed object, e.g. smoke in a box.
+pub struct Volume {
+    pub boundary: Object,
+    pub medium: Medium,
+}
+
+impl Volume {
+    pub fn new(boundary: Object, medium: Medium) -> Self {
+        assert!(
+            boundary.is_solid(),
+            "Volume boundaries must be closed objects."
+        );
+        Volume { boundary, medium }
+    }
+
+    /// Parts of `ray` inside the boundary, clipped to between its start and `max_distance`
+    pub fn spans(&self, ray: &Ray, max_distance: f32) -> Vec<(f32, f32)> {
+        self.boundary
+            .spans(ray)
+            .into_iter()
+            .map(|span| {
+                (
+                    span.enter.distance.max(0.0),
+                    span.exit.distance.min(max_distance),
+                )
+            })
+            .filter(|(near, far)| near < far)
+            .collect()
+    }
+}
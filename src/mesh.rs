@@ -0,0 +1,212 @@
+use cgmath::prelude::*;
+use std::collections::HashMap;
+
+use crate::bvh::*;
+use crate::packet::{self, PacketIntersections, RayPacket};
+use crate::raytracing::*;
+use crate::texture::*;
+
+/// One triangle of a mesh, as indices into its vertex buffers and its material list.
+#[derive(Clone, Copy)]
+pub struct Face {
+    pub indices: [usize; 3],
+    pub material: usize,
+}
+
+/// Triangles sharing vertex buffers, so each vertex is stored once however many
+/// faces use it. Faces are found through a BVH.
+///
+/// The optional per-vertex buffers must be the same length as `positions`.
+pub struct Mesh {
+    positions: Vec<Point>,
+    faces: Vec<Face>,
+    pub normals: Option<Vec<Vector>>,
+    pub uvs: Option<Vec<Uv>>,
+    /// Per-vertex colours, used instead of `colour` when present
+    pub colours: Option<Vec<ColourFloat>>,
+    pub colour: ColourFloat,
+    pub materials: Vec<Material>,
+    pub texture: Option<Texture>,
+    pub normal_map: Option<NormalMap>,
+    face_normals: Vec<Vector>,
+    bvh: Bvh,
+    closed: bool,
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Point>,
+        faces: Vec<Face>,
+        colour: ColourFloat,
+        materials: Vec<Material>,
+    ) -> Self {
+        for face in faces.iter() {
+            assert!(face.indices.iter().all(|&i| i < positions.len()));
+            assert!(face.material < materials.len());
+        }
+        let vertices = |face: &Face| face.indices.map(|i| positions[i]);
+        let face_normals = faces
+            .iter()
+            .map(|face| {
+                let [v0, v1, v2] = vertices(face);
+                compute_normal(v0, v1, v2)
+            })
+            .collect();
+        let bounds: Vec<Aabb> = faces
+            .iter()
+            .map(|face| Aabb::from_points(&vertices(face)))
+            .collect();
+        let bvh = Bvh::new(&bounds);
+        let closed = is_closed(&faces);
+        Mesh {
+            positions,
+            faces,
+            normals: None,
+            uvs: None,
+            colours: None,
+            colour,
+            materials,
+            texture: None,
+            normal_map: None,
+            face_normals,
+            bvh,
+            closed,
+        }
+    }
+
+    pub fn positions(&self) -> &[Point] {
+        &self.positions
+    }
+
+    pub fn faces(&self) -> &[Face] {
+        &self.faces
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    /// Whether the faces enclose a volume, each edge shared by two faces that agree
+    /// on which side is outside
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Every face crossed by the whole line of `ray`, in order, and whether it's crossed
+    /// from the front
+    pub fn crossings(&self, ray: &Ray) -> Vec<(IntersectionLocation, bool)> {
+        // Start the ray behind the mesh so the BVH finds faces behind the real start
+        let bounds = self.bounds();
+        let back =
+            (bounds.centre() - ray.start).magnitude() + (bounds.max - bounds.min).magnitude();
+        let line = Ray::new(ray.start - back * ray.dir, ray.dir);
+        let mut crossings: Vec<_> = self
+            .bvh
+            .intersections(&line, |face| {
+                let [v0, v1, v2] = self.vertices(&self.faces[face]);
+                intersect_triangle_line(v0, v1, v2, &line)
+            })
+            .into_iter()
+            .map(|(face, (mut location, front))| {
+                location.distance -= back;
+                location.primitive = face;
+                (location, front)
+            })
+            .collect();
+        crossings.sort_by(|a, b| a.0.distance.total_cmp(&b.0.distance));
+        crossings
+    }
+
+    fn vertices(&self, face: &Face) -> [Point; 3] {
+        face.indices.map(|i| self.positions[i])
+    }
+
+    fn shading(&self, face_index: usize) -> TriangleShading<'_> {
+        let face = &self.faces[face_index];
+        TriangleShading {
+            vertices: self.vertices(face),
+            normal: self.face_normals[face_index],
+            normals: self
+                .normals
+                .as_ref()
+                .map(|normals| face.indices.map(|i| normals[i])),
+            uvs: self.uvs.as_ref().map(|uvs| face.indices.map(|i| uvs[i])),
+            normal_map: self.normal_map.as_ref(),
+        }
+    }
+
+    pub fn get_material(&self, location: &IntersectionLocation) -> &Material {
+        &self.materials[self.faces[location.primitive].material]
+    }
+
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
+        self.shading(location.primitive)
+            .get_normal(position, &location.texture_coords)
+    }
+}
+
+/// Whether every edge is used once in each direction, so the faces close up with
+/// consistent winding
+fn is_closed(faces: &[Face]) -> bool {
+    let mut edges = HashMap::new();
+    for face in faces {
+        let [a, b, c] = face.indices;
+        for &edge in [(a, b), (b, c), (c, a)].iter() {
+            *edges.entry(edge).or_insert(0) += 1;
+        }
+    }
+    !edges.is_empty()
+        && edges
+            .iter()
+            .all(|(&(a, b), &count)| count == 1 && edges.get(&(b, a)) == Some(&1))
+}
+
+impl Coloured for Mesh {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let face = &self.faces[location.primitive];
+        let colours = match self.colours {
+            Some(ref colours) => face.indices.map(|i| colours[i]),
+            None => [self.colour; 3],
+        };
+        match location.texture_coords {
+            TextureCoords::Barycentric(ref coords) => self.shading(location.primitive).get_colour(
+                position,
+                coords,
+                colours,
+                self.texture.as_ref(),
+                footprint,
+            ),
+            _ => panic!("Incorrect texture coord type specified for mesh."),
+        }
+    }
+}
+
+impl Intersectable for Mesh {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (face, mut location) = self.bvh.closest_intersection(ray, |face| {
+            let [v0, v1, v2] = self.vertices(&self.faces[face]);
+            intersect_triangle(v0, v1, v2, ray)
+        })?;
+        location.primitive = face;
+        Some(location)
+    }
+
+    fn packet_intersection(&self, packet: &RayPacket) -> PacketIntersections {
+        self.bvh
+            .closest_packet_intersection(packet, |face, lanes| {
+                let [v0, v1, v2] = self.vertices(&self.faces[face]);
+                packet::intersect_triangle(v0, v1, v2, packet, lanes)
+            })
+            .map(|closest| {
+                closest.map(|(face, mut location)| {
+                    location.primitive = face;
+                    location
+                })
+            })
+    }
+}
@@ -0,0 +1,414 @@
+use cgmath::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::path::Path;
+
+use crate::mesh::*;
+use crate::raytracing::*;
+use crate::texture::*;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Load an ASCII or binary PLY file as a mesh of one material. Per-vertex colours,
+/// normals and texture coordinates are used when the file has them.
+pub fn load_ply<P: AsRef<Path>>(
+    path: P,
+    colour: ColourFloat,
+    material: Material,
+) -> io::Result<Mesh> {
+    read_ply(&std::fs::read(path)?, colour, material)
+}
+
+/// Load an ASCII or binary STL file as a mesh of one material
+pub fn load_stl<P: AsRef<Path>>(
+    path: P,
+    colour: ColourFloat,
+    material: Material,
+) -> io::Result<Mesh> {
+    read_stl(&std::fs::read(path)?, colour, material)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum PlyScalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyScalar {
+    fn parse(name: &str) -> io::Result<Self> {
+        use PlyScalar::*;
+        Ok(match name {
+            "char" | "int8" => I8,
+            "uchar" | "uint8" => U8,
+            "short" | "int16" => I16,
+            "ushort" | "uint16" => U16,
+            "int" | "int32" => I32,
+            "uint" | "uint32" => U32,
+            "float" | "float32" => F32,
+            "double" | "float64" => F64,
+            _ => return Err(invalid(format!("Unknown PLY type {}", name))),
+        })
+    }
+
+    fn size(self) -> usize {
+        use PlyScalar::*;
+        match self {
+            I8 | U8 => 1,
+            I16 | U16 => 2,
+            I32 | U32 | F32 => 4,
+            F64 => 8,
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, PlyScalar::F32 | PlyScalar::F64)
+    }
+}
+
+enum PlyProperty {
+    Scalar(PlyScalar),
+    List { count: PlyScalar, item: PlyScalar },
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<(String, PlyProperty)>,
+}
+
+impl PlyElement {
+    fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|(name, _)| names.contains(&name.as_str()))
+    }
+}
+
+/// Values following the header, read one at a time in the file's format
+enum PlyBody<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool },
+}
+
+impl<'a> PlyBody<'a> {
+    fn read(&mut self, scalar: PlyScalar) -> io::Result<f64> {
+        match self {
+            PlyBody::Ascii(tokens) => tokens
+                .next()
+                .ok_or_else(|| invalid("PLY file ends early"))?
+                .parse()
+                .map_err(|_| invalid("Malformed number in PLY file")),
+            PlyBody::Binary { bytes, big_endian } => {
+                let size = scalar.size();
+                if bytes.len() < size {
+                    return Err(invalid("PLY file ends early"));
+                }
+                // Convert to little-endian, then decode
+                let mut b = [0_u8; 8];
+                b[..size].copy_from_slice(&bytes[..size]);
+                if *big_endian {
+                    b[..size].reverse();
+                }
+                *bytes = &bytes[size..];
+                use PlyScalar::*;
+                Ok(match scalar {
+                    I8 => b[0] as i8 as f64,
+                    U8 => b[0] as f64,
+                    I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    I32 => i32::from_le_bytes(b[..4].try_into().unwrap()) as f64,
+                    U32 => u32::from_le_bytes(b[..4].try_into().unwrap()) as f64,
+                    F32 => f32::from_le_bytes(b[..4].try_into().unwrap()) as f64,
+                    F64 => f64::from_le_bytes(b),
+                })
+            }
+        }
+    }
+
+    /// One value per property, lists are read and given as their length
+    fn read_row(
+        &mut self,
+        element: &PlyElement,
+        lists: &mut Vec<Vec<usize>>,
+    ) -> io::Result<Vec<f64>> {
+        lists.clear();
+        let mut row = Vec::with_capacity(element.properties.len());
+        for (_, property) in element.properties.iter() {
+            match *property {
+                PlyProperty::Scalar(scalar) => row.push(self.read(scalar)?),
+                PlyProperty::List { count, item } => {
+                    let count = self.read(count)? as usize;
+                    let items = (0..count)
+                        .map(|_| self.read(item).map(|i| i as usize))
+                        .collect::<io::Result<Vec<usize>>>()?;
+                    row.push(count as f64);
+                    lists.push(items);
+                }
+            }
+        }
+        Ok(row)
+    }
+}
+
+/// Split off the header, returning the format, the elements it declares and the rest of the file
+fn read_ply_header(bytes: &[u8]) -> io::Result<(PlyFormat, Vec<PlyElement>, &[u8])> {
+    let mut rest = bytes;
+    let mut next_line = || -> io::Result<String> {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| invalid("PLY header is not terminated"))?;
+        let line = String::from_utf8_lossy(&rest[..end]).trim().to_string();
+        rest = &rest[end + 1..];
+        Ok(line)
+    };
+    if next_line()? != "ply" {
+        return Err(invalid("Not a PLY file"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    loop {
+        let line = next_line()?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["end_header"] => break,
+            ["format", name, _] => {
+                format = Some(match name {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                    "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                    _ => return Err(invalid(format!("Unknown PLY format {}", name))),
+                })
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid("Malformed PLY element count"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside an element"))?
+                .properties
+                .push((
+                    name.to_string(),
+                    PlyProperty::List {
+                        count: PlyScalar::parse(count)?,
+                        item: PlyScalar::parse(item)?,
+                    },
+                )),
+            ["property", scalar, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside an element"))?
+                .properties
+                .push((
+                    name.to_string(),
+                    PlyProperty::Scalar(PlyScalar::parse(scalar)?),
+                )),
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(invalid(format!("Malformed PLY header line: {}", line))),
+        }
+    }
+    let format = format.ok_or_else(|| invalid("PLY header has no format"))?;
+    Ok((format, elements, rest))
+}
+
+/// Parse a PLY file held in memory. Polygons are split into triangle fans and any
+/// elements other than vertices and faces are skipped.
+///
+/// Integer colour channels are taken to be 0-255 and floating point ones 0-1.
+pub fn read_ply(bytes: &[u8], colour: ColourFloat, material: Material) -> io::Result<Mesh> {
+    let (format, elements, rest) = read_ply_header(bytes)?;
+    let mut body = match format {
+        PlyFormat::Ascii => PlyBody::Ascii(
+            std::str::from_utf8(rest)
+                .map_err(|_| invalid("ASCII PLY file is not valid text"))?
+                .split_ascii_whitespace(),
+        ),
+        _ => PlyBody::Binary {
+            bytes: rest,
+            big_endian: format == PlyFormat::BinaryBigEndian,
+        },
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut colours = Vec::new();
+    let mut faces = Vec::new();
+    let mut lists = Vec::new();
+    for element in elements.iter() {
+        match element.name.as_str() {
+            "vertex" => {
+                let find = |names: &[&[&str]; 3]| -> Option<[usize; 3]> {
+                    Some([
+                        element.property(names[0])?,
+                        element.property(names[1])?,
+                        element.property(names[2])?,
+                    ])
+                };
+                let position = find(&[&["x"], &["y"], &["z"]])
+                    .ok_or_else(|| invalid("PLY vertices have no position"))?;
+                let normal = find(&[&["nx"], &["ny"], &["nz"]]);
+                let rgb = find(&[
+                    &["red", "diffuse_red"],
+                    &["green", "diffuse_green"],
+                    &["blue", "diffuse_blue"],
+                ]);
+                let uv = ["u", "s", "texture_u"]
+                    .iter()
+                    .zip(["v", "t", "texture_v"].iter())
+                    .find_map(|(u, v)| Some([element.property(&[u])?, element.property(&[v])?]));
+                let colour_scale = match rgb.map(|rgb| &element.properties[rgb[0]].1) {
+                    Some(PlyProperty::Scalar(scalar)) if scalar.is_float() => 255.0,
+                    _ => 1.0,
+                };
+
+                for _ in 0..element.count {
+                    let row = body.read_row(element, &mut lists)?;
+                    let vector = |[x, y, z]: [usize; 3]| {
+                        Vector::new(row[x] as f32, row[y] as f32, row[z] as f32)
+                    };
+                    positions.push(vector(position));
+                    if let Some(normal) = normal {
+                        normals.push(vector(normal).normalize());
+                    }
+                    if let Some(rgb) = rgb {
+                        colours.push(vector(rgb) * colour_scale);
+                    }
+                    if let Some([u, v]) = uv {
+                        uvs.push(Uv::new(row[u] as f32, row[v] as f32));
+                    }
+                }
+            }
+            "face" => {
+                let list = element.properties[..]
+                    .iter()
+                    .filter(|(_, p)| matches!(p, PlyProperty::List { .. }))
+                    .position(|(name, _)| name == "vertex_indices" || name == "vertex_index")
+                    .ok_or_else(|| invalid("PLY faces have no vertex indices"))?;
+                for _ in 0..element.count {
+                    body.read_row(element, &mut lists)?;
+                    let polygon = &lists[list];
+                    for i in 2..polygon.len() {
+                        faces.push(Face {
+                            indices: [polygon[0], polygon[i - 1], polygon[i]],
+                            material: 0,
+                        });
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    body.read_row(element, &mut lists)?;
+                }
+            }
+        }
+    }
+
+    if faces
+        .iter()
+        .any(|face| face.indices.iter().any(|&i| i >= positions.len()))
+    {
+        return Err(invalid("PLY face refers to a missing vertex"));
+    }
+    let mut mesh = Mesh::new(positions, faces, colour, vec![material]);
+    mesh.normals = Some(normals).filter(|n| !n.is_empty());
+    mesh.uvs = Some(uvs).filter(|uvs| !uvs.is_empty());
+    mesh.colours = Some(colours).filter(|c| !c.is_empty());
+    Ok(mesh)
+}
+
+/// Parse an STL file held in memory. STL stores every triangle separately, so
+/// identical corners are welded back into shared vertices.
+pub fn read_stl(bytes: &[u8], colour: ColourFloat, material: Material) -> io::Result<Mesh> {
+    // Binary files may also start with "solid", so check whether the size fits first
+    let triangles = if bytes.len() >= 84
+        && 84 + 50 * u32::from_le_bytes(bytes[80..84].try_into().unwrap()) as usize == bytes.len()
+    {
+        read_binary_stl(&bytes[84..])
+    } else if bytes.starts_with(b"solid") {
+        read_ascii_stl(bytes)?
+    } else {
+        return Err(invalid("Not an STL file"));
+    };
+
+    let mut positions = Vec::new();
+    let mut welded = HashMap::new();
+    let faces = triangles
+        .iter()
+        .map(|triangle| Face {
+            indices: triangle.map(|v| {
+                *welded.entry(v.map(f32::to_bits)).or_insert_with(|| {
+                    positions.push(Point::from(v));
+                    positions.len() - 1
+                })
+            }),
+            material: 0,
+        })
+        .collect();
+    Ok(Mesh::new(positions, faces, colour, vec![material]))
+}
+
+/// Each record is a normal, three corners and two attribute bytes. The normal is
+/// recomputed from the winding instead.
+fn read_binary_stl(records: &[u8]) -> Vec<[[f32; 3]; 3]> {
+    let float = |b: &[u8], i: usize| f32::from_le_bytes(b[i * 4..i * 4 + 4].try_into().unwrap());
+    records
+        .chunks_exact(50)
+        .map(|record| {
+            let corner = |c: usize| {
+                let first = 3 + c * 3;
+                [
+                    float(record, first),
+                    float(record, first + 1),
+                    float(record, first + 2),
+                ]
+            };
+            [corner(0), corner(1), corner(2)]
+        })
+        .collect()
+}
+
+fn read_ascii_stl(bytes: &[u8]) -> io::Result<Vec<[[f32; 3]; 3]>> {
+    let text =
+        std::str::from_utf8(bytes).map_err(|_| invalid("ASCII STL file is not valid text"))?;
+    let mut corners = Vec::new();
+    let mut tokens = text.split_ascii_whitespace();
+    while let Some(token) = tokens.next() {
+        if token == "vertex" {
+            let mut coordinate = || -> io::Result<f32> {
+                tokens
+                    .next()
+                    .and_then(|t| t.parse().ok())
+                    .ok_or_else(|| invalid("Malformed STL vertex"))
+            };
+            corners.push([coordinate()?, coordinate()?, coordinate()?]);
+        }
+    }
+    if !corners.len().is_multiple_of(3) {
+        return Err(invalid("STL facet does not have three vertices"));
+    }
+    Ok(corners
+        .chunks_exact(3)
+        .map(|c| [c[0], c[1], c[2]])
+        .collect())
+}
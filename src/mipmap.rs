@@ -0,0 +1,167 @@
+use cgmath::prelude::*;
+
+use crate::raytracing::*;
+use crate::texture::Uv;
+use crate::utils;
+use image::RgbImage;
+
+/// How an image texture is sampled given the footprint of a pixel.
+#[derive(Clone, Copy)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    /// Bilinear lookups blended between the two nearest MIP levels
+    Trilinear,
+    /// Several trilinear lookups along the long axis of the footprint, up to the given ratio
+    Anisotropic(u32),
+}
+
+struct MipLevel {
+    width: u32,
+    height: u32,
+    texels: Vec<ColourFloat>,
+}
+
+impl MipLevel {
+    fn texel(&self, x: i64, y: i64) -> ColourFloat {
+        // Textures repeat outside of 0-1
+        let x = x.rem_euclid(self.width as i64) as u32;
+        let y = y.rem_euclid(self.height as i64) as u32;
+        self.texels[(y * self.width + x) as usize]
+    }
+
+    fn nearest(&self, uv: Uv) -> ColourFloat {
+        let x = (uv.x * self.width as f32).floor() as i64;
+        let y = ((1.0 - uv.y) * self.height as f32).floor() as i64;
+        self.texel(x, y)
+    }
+
+    fn bilinear(&self, uv: Uv) -> ColourFloat {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0).lerp(self.texel(x0 + 1, y0), fx);
+        let bottom = self.texel(x0, y0 + 1).lerp(self.texel(x0 + 1, y0 + 1), fx);
+        top.lerp(bottom, fy)
+    }
+
+    /// Box filter down to half the size, rounding odd sizes down
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = (2 * x as i64, 2 * y as i64);
+                let sum = self.texel(sx, sy)
+                    + self.texel(sx + 1, sy)
+                    + self.texel(sx, sy + 1)
+                    + self.texel(sx + 1, sy + 1);
+                texels.push(sum / 4.0);
+            }
+        }
+        MipLevel {
+            width,
+            height,
+            texels,
+        }
+    }
+}
+
+/// An image texture with its MIP-map pyramid, colours in the 0-255 range.
+pub struct ImageTexture {
+    levels: Vec<MipLevel>,
+    pub filter: TextureFilter,
+    /// File the image was loaded from, so scenes using it can be saved
+    pub path: Option<String>,
+}
+
+impl ImageTexture {
+    pub fn new(image: &RgbImage, filter: TextureFilter) -> Self {
+        let base = MipLevel {
+            width: image.width(),
+            height: image.height(),
+            texels: image.pixels().map(|p| as_float(p.0)).collect(),
+        };
+        let mut levels = vec![base];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
+        }
+        ImageTexture {
+            levels,
+            filter,
+            path: None,
+        }
+    }
+
+    pub fn load(path: &str, filter: TextureFilter) -> image::ImageResult<Self> {
+        let image = image::open(path)?.to_rgb();
+        let mut texture = ImageTexture::new(&image, filter);
+        texture.path = Some(path.to_string());
+        Ok(texture)
+    }
+
+    pub fn mip_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn lookup(&self, uv: Uv, duvdx: Uv, duvdy: Uv) -> ColourFloat {
+        match self.filter {
+            TextureFilter::Nearest => self.levels[0].nearest(uv),
+            TextureFilter::Bilinear => self.levels[0].bilinear(uv),
+            TextureFilter::Trilinear => {
+                let width = self.texel_length(duvdx).max(self.texel_length(duvdy));
+                self.trilinear(uv, width)
+            }
+            TextureFilter::Anisotropic(max_ratio) => {
+                let (major, minor) = if self.texel_length(duvdx) > self.texel_length(duvdy) {
+                    (duvdx, duvdy)
+                } else {
+                    (duvdy, duvdx)
+                };
+                let major_length = self.texel_length(major);
+                // Blur along the minor axis rather than take too many samples
+                let minor_length = self
+                    .texel_length(minor)
+                    .max(major_length / max_ratio.max(1) as f32);
+                if utils::is_zero(minor_length) {
+                    return self.levels[0].bilinear(uv);
+                }
+                let samples = (major_length / minor_length).ceil().max(1.0) as u32;
+                let mut sum = ColourFloat::zero();
+                for i in 0..samples {
+                    let offset = (i as f32 + 0.5) / samples as f32 - 0.5;
+                    sum += self.trilinear(uv + major * offset, minor_length);
+                }
+                sum / samples as f32
+            }
+        }
+    }
+
+    /// Length of a UV-space vector measured in texels of the largest level
+    fn texel_length(&self, duv: Uv) -> f32 {
+        let base = &self.levels[0];
+        Uv::new(duv.x * base.width as f32, duv.y * base.height as f32).magnitude()
+    }
+
+    /// Blend the two levels whose texels are closest to `width` texels of the largest level
+    fn trilinear(&self, uv: Uv, width: f32) -> ColourFloat {
+        let last = (self.mip_levels() - 1) as f32;
+        let lod = width.max(f32::MIN_POSITIVE).log2().clamp(0.0, last);
+        let lower = lod.floor() as usize;
+        if lower as f32 == lod {
+            return self.levels[lower].bilinear(uv);
+        }
+        let upper = lower + 1;
+        self.levels[lower]
+            .bilinear(uv)
+            .lerp(self.levels[upper].bilinear(uv), lod - lower as f32)
+    }
+}
@@ -0,0 +1,150 @@
+//! Packets of rays tested against objects together, a ray in each SIMD lane.
+//!
+//! Rays through neighbouring pixels mostly hit the same things, so testing them
+//! together costs little more than testing one. Objects without a packet test of their
+//! own test the rays one at a time, and either way each ray gets exactly the
+//! intersection it would alone.
+
+use crate::raytracing::*;
+use crate::simd::{self, F32x4, Mask4, Vector4};
+use crate::utils;
+
+/// Most rays in a packet
+pub const PACKET_SIZE: usize = 4;
+
+pub type PacketIntersections = [Option<IntersectionLocation>; PACKET_SIZE];
+
+/// Up to `PACKET_SIZE` rays, lane `i` holding `rays[i]`
+pub struct RayPacket<'a> {
+    pub rays: &'a [Ray],
+    pub start: Vector4,
+    pub dir: Vector4,
+    /// Reciprocals of the directions, for slab tests against boxes
+    pub inv_dir: Vector4,
+    /// Lanes holding rays, the rest repeating the last ray
+    pub active: Mask4,
+}
+
+impl<'a> RayPacket<'a> {
+    #[inline]
+    pub fn new(rays: &'a [Ray]) -> Self {
+        assert!(!rays.is_empty() && rays.len() <= PACKET_SIZE);
+        let ray = |lane: usize| &rays[lane.min(rays.len() - 1)];
+        let (a, b, c, d) = (ray(0), ray(1), ray(2), ray(3));
+        let lanes = |f: fn(&Ray) -> f32| F32x4::from_array([f(a), f(b), f(c), f(d)]);
+        let start = [
+            lanes(|ray| ray.start.x),
+            lanes(|ray| ray.start.y),
+            lanes(|ray| ray.start.z),
+        ];
+        let dir = [
+            lanes(|ray| ray.dir.x),
+            lanes(|ray| ray.dir.y),
+            lanes(|ray| ray.dir.z),
+        ];
+        let one = F32x4::splat(1.0);
+        RayPacket {
+            rays,
+            start,
+            dir,
+            inv_dir: [one / dir[0], one / dir[1], one / dir[2]],
+            active: Mask4::from_bits((1 << rays.len()) - 1),
+        }
+    }
+}
+
+/// Intersections of each of the packet's rays, tested one at a time
+pub fn intersect_each(object: &impl Intersectable, packet: &RayPacket) -> PacketIntersections {
+    std::array::from_fn(|lane| {
+        packet
+            .rays
+            .get(lane)
+            .and_then(|ray| object.intersection(ray))
+    })
+}
+
+fn is_negative(value: F32x4) -> Mask4 {
+    value.lt(F32x4::splat(-utils::EPSILON))
+}
+
+fn is_greater_than(value: F32x4, other: f32) -> Mask4 {
+    value.gt(F32x4::splat(other + utils::EPSILON))
+}
+
+/// `intersect_triangle` for the packet's rays in lanes set in `active`
+pub fn intersect_triangle(
+    v0: Point,
+    v1: Point,
+    v2: Point,
+    packet: &RayPacket,
+    active: Mask4,
+) -> PacketIntersections {
+    // The same steps as the scalar version, with its tests turned into masks
+    let v0v1 = simd::splat(v1 - v0);
+    let v0v2 = simd::splat(v2 - v0);
+    let pvec = simd::cross(packet.dir, v0v2);
+    let determinant = simd::dot(v0v1, pvec);
+    let epsilon = F32x4::splat(utils::EPSILON);
+    let is_zero = determinant.lt(epsilon) & determinant.gt(F32x4::splat(-utils::EPSILON));
+    let mut hit = active & !is_negative(determinant) & !is_zero;
+    if !hit.any() {
+        return Default::default();
+    }
+
+    let inv_det = F32x4::splat(1.0) / determinant;
+    let tvec = simd::sub(packet.start, simd::splat(v0));
+    let u = simd::dot(tvec, pvec) * inv_det;
+    hit = hit & !is_negative(u) & !is_greater_than(u, 1.0);
+
+    let qvec = simd::cross(tvec, v0v1);
+    let v = simd::dot(packet.dir, qvec) * inv_det;
+    hit = hit & !is_negative(v) & !is_greater_than(u + v, 1.0);
+
+    let distance = simd::dot(v0v2, qvec) * inv_det;
+    hit = hit & distance.gt(epsilon);
+    if !hit.any() {
+        return Default::default();
+    }
+
+    let (distance, u, v) = (distance.to_array(), u.to_array(), v.to_array());
+    std::array::from_fn(|lane| {
+        if hit.lane(lane) {
+            Some(IntersectionLocation::new(
+                distance[lane],
+                TextureCoords::Barycentric(BarycentricCoords::new(u[lane], v[lane])),
+            ))
+        } else {
+            None
+        }
+    })
+}
+
+/// Where the packet's rays hit `sphere`, as `Sphere::intersection` gives
+pub fn intersect_sphere(sphere: &Sphere, packet: &RayPacket) -> PacketIntersections {
+    let to_centre = simd::sub(simd::splat(sphere.centre), packet.start);
+    let adjacent = simd::dot(packet.dir, to_centre);
+    let opposite_squared = simd::dot(to_centre, to_centre) - adjacent * adjacent;
+    let radius_squared = sphere.radius * sphere.radius;
+    let hit = packet.active & !is_greater_than(opposite_squared, radius_squared);
+    if !hit.any() {
+        return Default::default();
+    }
+
+    // Clamped like `Sphere::chord`, for rays that only just graze the sphere
+    let half_chord = (F32x4::splat(radius_squared) - opposite_squared)
+        .max(F32x4::splat(0.0))
+        .sqrt();
+    let near = (adjacent - half_chord).to_array();
+    let far = (adjacent + half_chord).to_array();
+    std::array::from_fn(|lane| {
+        if !hit.lane(lane) || (near[lane].is_sign_negative() && far[lane].is_sign_negative()) {
+            return None;
+        }
+        let distance = if near[lane].is_sign_negative() {
+            far[lane]
+        } else {
+            near[lane]
+        };
+        Some(IntersectionLocation::new(distance, TextureCoords::None))
+    })
+}
@@ -0,0 +1,968 @@
+use cgmath::prelude::*;
+use std::f32::consts::PI;
+
+use crate::raytracing::*;
+use crate::texture::*;
+use crate::utils;
+
+/// Orthonormal frame at `origin`, with `y` as the primitive's main axis.
+#[derive(Clone)]
+pub struct Frame {
+    pub origin: Point,
+    pub x: Vector,
+    pub y: Vector,
+    pub z: Vector,
+}
+
+impl Frame {
+    pub fn new(origin: Point, axis: Vector) -> Self {
+        let y = axis.normalize();
+        let helper = if y.z.abs() > 0.9 {
+            Vector::unit_x()
+        } else {
+            Vector::unit_z()
+        };
+        let x = y.cross(helper).normalize();
+        Frame {
+            origin,
+            x,
+            y,
+            z: x.cross(y),
+        }
+    }
+
+    pub fn from_axes(origin: Point, axes: cgmath::Matrix3<f32>) -> Self {
+        Frame {
+            origin,
+            x: axes.x.normalize(),
+            y: axes.y.normalize(),
+            z: axes.z.normalize(),
+        }
+    }
+
+    pub fn to_local_point(&self, point: Point) -> Point {
+        self.to_local_vector(point - self.origin)
+    }
+
+    pub fn to_local_vector(&self, vector: Vector) -> Vector {
+        Vector::new(vector.dot(self.x), vector.dot(self.y), vector.dot(self.z))
+    }
+
+    pub fn to_world_vector(&self, vector: Vector) -> Vector {
+        self.x * vector.x + self.y * vector.y + self.z * vector.z
+    }
+
+    pub(crate) fn local_ray(&self, ray: &Ray) -> (Point, Vector) {
+        (
+            self.to_local_point(ray.start),
+            self.to_local_vector(ray.dir),
+        )
+    }
+}
+
+/// Roots of `a t^2 + b t + c`, smallest first
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    if utils::is_zero(a) {
+        if utils::is_zero(b) {
+            return None;
+        }
+        let t = -c / b;
+        return Some((t, t));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+/// Real roots of `t^3 + a t^2 + b t + c`, from Cardano's formula or, with three roots,
+/// the trigonometric method
+fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // Depressed to s^3 + p s + q with t = s - a / 3
+    let shift = a / 3.0;
+    let p = b - a * shift;
+    let q = c - b * shift + 2.0 * shift * shift * shift;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+    if discriminant > 0.0 {
+        let root = discriminant.sqrt();
+        vec![(-q / 2.0 + root).cbrt() + (-q / 2.0 - root).cbrt() - shift]
+    } else if p == 0.0 {
+        vec![-shift]
+    } else {
+        let radius = 2.0 * (-p / 3.0).sqrt();
+        let angle = (3.0 * q / (p * radius)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3)
+            .map(|k| radius * (angle - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos() - shift)
+            .collect()
+    }
+}
+
+/// Real roots of the quartic with coefficients `[a, b, c, d, e]`, highest power first,
+/// from Ferrari's method, smallest first. A double root is given twice.
+fn solve_quartic([a, b, c, d, e]: [f64; 5]) -> Vec<f64> {
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    // Depressed to y^4 + p y^2 + q y + r with t = y - b / 4
+    let shift = b / 4.0;
+    let p = c - 6.0 * shift * shift;
+    let q = d - 2.0 * c * shift + 8.0 * shift * shift * shift;
+    let r = e - d * shift + c * shift * shift - 3.0 * shift * shift * shift * shift;
+    let scale = 1.0 + p.abs() + q.abs() + r.abs();
+    // Roots of y^2 + linear y + constant, allowing for rounding where they meet
+    let quadratic = |linear: f64, constant: f64, roots: &mut Vec<f64>| {
+        let discriminant = linear * linear - 4.0 * constant;
+        if discriminant > -1e-12 * scale {
+            let root = discriminant.max(0.0).sqrt();
+            roots.push((-linear - root) / 2.0);
+            roots.push((-linear + root) / 2.0);
+        }
+    };
+    let mut roots = Vec::new();
+    // The largest root of the resolvent cubic makes both sides of
+    // (y^2 + p / 2 + m)^2 = 2 m y^2 - q y + m^2 + m p + p^2 / 4 - r perfect squares
+    let m = solve_cubic(p, p * p / 4.0 - r, -q * q / 8.0)
+        .into_iter()
+        .fold(f64::NEG_INFINITY, f64::max);
+    if m > 1e-12 * scale {
+        let s = (2.0 * m).sqrt();
+        quadratic(-s, p / 2.0 + m + q / (2.0 * s), &mut roots);
+        quadratic(s, p / 2.0 + m - q / (2.0 * s), &mut roots);
+    } else {
+        // Biquadratic, a quadratic in y^2
+        let mut squares = Vec::new();
+        quadratic(p, r, &mut squares);
+        for square in squares {
+            if square > -1e-12 * scale {
+                let y = square.max(0.0).sqrt();
+                roots.push(-y);
+                roots.push(y);
+            }
+        }
+    }
+    // Polish against the original quartic, undoing its depression
+    let quartic = |t: f64| (((t + b) * t + c) * t + d) * t + e;
+    let slope = |t: f64| ((4.0 * t + 3.0 * b) * t + 2.0 * c) * t + d;
+    let mut roots: Vec<f64> = roots
+        .into_iter()
+        .map(|y| {
+            let mut t = y - shift;
+            for _ in 0..2 {
+                let slope = slope(t);
+                if slope != 0.0 {
+                    t -= quartic(t) / slope;
+                }
+            }
+            t
+        })
+        .collect();
+    roots.sort_by(f64::total_cmp);
+    roots
+}
+
+/// Angle around the local y axis, as a fraction of a turn
+fn azimuth(local: Point) -> f32 {
+    0.5 + local.z.atan2(local.x) / (2.0 * PI)
+}
+
+fn get_uv(location: &IntersectionLocation) -> Uv {
+    match location.texture_coords {
+        TextureCoords::Uv(uv) => uv,
+        _ => panic!("Incorrect texture coord type specified for primitive."),
+    }
+}
+
+/// Colour of a primitive's surface, from its texture if it has one
+fn surface_colour(
+    colour: ColourFloat,
+    texture: &Option<Texture>,
+    position: Point,
+    uv: Uv,
+    (dpdu, dpdv): (Vector, Vector),
+    footprint: &Footprint,
+) -> ColourFloat {
+    match texture {
+        Some(texture) => {
+            let (duvdx, duvdy) = footprint.uv_derivatives(dpdu, dpdv);
+            texture.eval(&TexturePoint {
+                position,
+                uv,
+                duvdx,
+                duvdy,
+            })
+        }
+        None => colour,
+    }
+}
+
+fn nearest_hit(hits: &[(f32, usize)]) -> Option<(f32, usize)> {
+    hits.iter().filter(|(t, _)| utils::is_positive(*t)).fold(
+        None,
+        |nearest: Option<(f32, usize)>, &hit| match nearest {
+            Some(n) if n.0 <= hit.0 => Some(n),
+            _ => Some(hit),
+        },
+    )
+}
+
+/// Infinite plane, seen from both sides.
+pub struct Plane {
+    frame: Frame,
+    pub colour: ColourFloat,
+    pub texture: Option<Texture>,
+    pub material: Material,
+}
+
+impl Plane {
+    pub fn new(point: Point, normal: Vector, colour: ColourFloat, material: Material) -> Self {
+        Plane {
+            frame: Frame::new(point, normal),
+            colour,
+            texture: None,
+            material,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn get_material(&self) -> &Material {
+        &self.material
+    }
+
+    /// The same from both sides; shading turns it towards the ray
+    pub fn get_normal(&self, _position: Point, _location: &IntersectionLocation) -> Vector {
+        self.frame.y
+    }
+}
+
+impl Coloured for Plane {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let tangents = (self.frame.x, self.frame.z);
+        let uv = get_uv(location);
+        surface_colour(
+            self.colour,
+            &self.texture,
+            position,
+            uv,
+            tangents,
+            footprint,
+        )
+    }
+}
+
+impl Intersectable for Plane {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (start, dir) = self.frame.local_ray(ray);
+        if utils::is_zero(dir.y) {
+            return None;
+        }
+        let distance = -start.y / dir.y;
+        if !utils::is_positive(distance) {
+            return None;
+        }
+        let hit = start + distance * dir;
+        let uv = Uv::new(hit.x, hit.z);
+        Some(IntersectionLocation::new(distance, TextureCoords::Uv(uv)))
+    }
+}
+
+/// Flat disc, seen from both sides.
+pub struct Disc {
+    frame: Frame,
+    pub radius: f32,
+    pub colour: ColourFloat,
+    pub texture: Option<Texture>,
+    pub material: Material,
+}
+
+impl Disc {
+    pub fn new(
+        centre: Point,
+        normal: Vector,
+        radius: f32,
+        colour: ColourFloat,
+        material: Material,
+    ) -> Self {
+        Disc {
+            frame: Frame::new(centre, normal),
+            radius,
+            colour,
+            texture: None,
+            material,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn get_material(&self) -> &Material {
+        &self.material
+    }
+
+    /// The same from both sides; shading turns it towards the ray
+    pub fn get_normal(&self, _position: Point, _location: &IntersectionLocation) -> Vector {
+        self.frame.y
+    }
+}
+
+impl Coloured for Disc {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let diameter = 2.0 * self.radius;
+        let tangents = (self.frame.x * diameter, self.frame.z * diameter);
+        let uv = get_uv(location);
+        surface_colour(
+            self.colour,
+            &self.texture,
+            position,
+            uv,
+            tangents,
+            footprint,
+        )
+    }
+}
+
+impl Intersectable for Disc {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (start, dir) = self.frame.local_ray(ray);
+        if utils::is_zero(dir.y) {
+            return None;
+        }
+        let distance = -start.y / dir.y;
+        let hit = start + distance * dir;
+        if !utils::is_positive(distance)
+            || hit.x * hit.x + hit.z * hit.z > self.radius * self.radius
+        {
+            return None;
+        }
+        let uv = Uv::new(
+            0.5 + hit.x / (2.0 * self.radius),
+            0.5 + hit.z / (2.0 * self.radius),
+        );
+        Some(IntersectionLocation::new(distance, TextureCoords::Uv(uv)))
+    }
+}
+
+/// Box, either axis-aligned or rotated by an orthonormal set of axes.
+pub struct Cuboid {
+    frame: Frame,
+    pub half_extents: Vector,
+    pub colour: ColourFloat,
+    pub texture: Option<Texture>,
+    pub material: Material,
+}
+
+impl Cuboid {
+    pub fn axis_aligned(min: Point, max: Point, colour: ColourFloat, material: Material) -> Self {
+        Cuboid::oriented(
+            (min + max) / 2.0,
+            (max - min) / 2.0,
+            cgmath::Matrix3::identity(),
+            colour,
+            material,
+        )
+    }
+
+    /// `axes` holds the box's local x, y and z axes as columns
+    pub fn oriented(
+        centre: Point,
+        half_extents: Vector,
+        axes: cgmath::Matrix3<f32>,
+        colour: ColourFloat,
+        material: Material,
+    ) -> Self {
+        Cuboid {
+            frame: Frame::from_axes(centre, axes),
+            half_extents,
+            colour,
+            texture: None,
+            material,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn get_material(&self) -> &Material {
+        &self.material
+    }
+
+    /// Local axis of the face at `position`, and whether it is on the positive side
+    fn face(&self, position: Point) -> (usize, bool) {
+        let local = self.frame.to_local_point(position);
+        let mut axis = 0;
+        for i in 1..3 {
+            if (local[i] / self.half_extents[i]).abs()
+                > (local[axis] / self.half_extents[axis]).abs()
+            {
+                axis = i;
+            }
+        }
+        (axis, local[axis] > 0.0)
+    }
+
+    fn axis(&self, index: usize) -> Vector {
+        [self.frame.x, self.frame.y, self.frame.z][index]
+    }
+
+    pub fn get_normal(&self, position: Point, _location: &IntersectionLocation) -> Vector {
+        let (axis, positive) = self.face(position);
+        if positive {
+            self.axis(axis)
+        } else {
+            -self.axis(axis)
+        }
+    }
+
+    /// Where `ray` meets the surface `distance` along it
+    pub fn location(&self, ray: &Ray, distance: f32) -> IntersectionLocation {
+        let position = ray.start + distance * ray.dir;
+        let hit = self.frame.to_local_point(position);
+        let (axis, _) = self.face(position);
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let uv = Uv::new(
+            0.5 + hit[a] / (2.0 * self.half_extents[a]),
+            0.5 + hit[b] / (2.0 * self.half_extents[b]),
+        );
+        IntersectionLocation::new(distance, TextureCoords::Uv(uv))
+    }
+
+    /// Entry and exit distances of `ray` through the box, if it passes through it at all
+    pub fn slabs(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let (start, dir) = self.frame.local_ray(ray);
+        let mut near = f32::MIN;
+        let mut far = f32::MAX;
+        for i in 0..3 {
+            let half = self.half_extents[i];
+            if utils::is_zero(dir[i]) {
+                if start[i].abs() > half {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (-half - start[i]) / dir[i];
+            let t1 = (half - start[i]) / dir[i];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        if near > far {
+            return None;
+        }
+        Some((near, far))
+    }
+}
+
+impl Coloured for Cuboid {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let (axis, _) = self.face(position);
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let tangents = (
+            self.axis(a) * 2.0 * self.half_extents[a],
+            self.axis(b) * 2.0 * self.half_extents[b],
+        );
+        let uv = get_uv(location);
+        surface_colour(
+            self.colour,
+            &self.texture,
+            position,
+            uv,
+            tangents,
+            footprint,
+        )
+    }
+}
+
+impl Intersectable for Cuboid {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (near, far) = self.slabs(ray)?;
+        let distance = nearest_hit(&[(near, 0), (far, 0)])?.0;
+        Some(self.location(ray, distance))
+    }
+}
+
+// Faces of cylinders and cones, stored in `IntersectionLocation::primitive`
+const SIDE: usize = 0;
+const BOTTOM_CAP: usize = 1;
+const TOP_CAP: usize = 2;
+
+/// Location on the side or a cap of a cylinder or cone
+fn round_location(
+    frame: &Frame,
+    radius: f32,
+    height: f32,
+    ray: &Ray,
+    distance: f32,
+    face: usize,
+) -> IntersectionLocation {
+    let local = frame.to_local_point(ray.start + distance * ray.dir);
+    let uv = match face {
+        SIDE => Uv::new(azimuth(local), local.y / height),
+        _ => Uv::new(
+            0.5 + local.x / (2.0 * radius),
+            0.5 + local.z / (2.0 * radius),
+        ),
+    };
+    let mut location = IntersectionLocation::new(distance, TextureCoords::Uv(uv));
+    location.primitive = face;
+    location
+}
+
+/// Cylinder standing on `base` and extending `height` along `axis`.
+pub struct Cylinder {
+    frame: Frame,
+    pub radius: f32,
+    pub height: f32,
+    pub capped: bool,
+    pub colour: ColourFloat,
+    pub texture: Option<Texture>,
+    pub material: Material,
+}
+
+impl Cylinder {
+    pub fn new(
+        base: Point,
+        axis: Vector,
+        radius: f32,
+        height: f32,
+        colour: ColourFloat,
+        material: Material,
+    ) -> Self {
+        Cylinder {
+            frame: Frame::new(base, axis),
+            radius,
+            height,
+            capped: true,
+            colour,
+            texture: None,
+            material,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn get_material(&self) -> &Material {
+        &self.material
+    }
+
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
+        match location.primitive {
+            BOTTOM_CAP => -self.frame.y,
+            TOP_CAP => self.frame.y,
+            _ => {
+                let local = self.frame.to_local_point(position);
+                self.frame
+                    .to_world_vector(Vector::new(local.x, 0.0, local.z))
+                    .normalize()
+            }
+        }
+    }
+
+    /// Where `ray` meets `face` of the surface `distance` along it
+    pub fn location(&self, ray: &Ray, distance: f32, face: usize) -> IntersectionLocation {
+        round_location(&self.frame, self.radius, self.height, ray, distance, face)
+    }
+
+    /// Every distance where `ray` crosses the surface, with the face it crosses
+    pub fn crossings(&self, ray: &Ray) -> Vec<(f32, usize)> {
+        let (start, dir) = self.frame.local_ray(ray);
+        let mut hits = Vec::with_capacity(4);
+        let a = dir.x * dir.x + dir.z * dir.z;
+        let b = 2.0 * (start.x * dir.x + start.z * dir.z);
+        let c = start.x * start.x + start.z * start.z - self.radius * self.radius;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for &t in &[t0, t1] {
+                let y = start.y + t * dir.y;
+                if (0.0..=self.height).contains(&y) {
+                    hits.push((t, SIDE));
+                }
+            }
+        }
+        if self.capped && !utils::is_zero(dir.y) {
+            for &(cap_y, face) in &[(0.0, BOTTOM_CAP), (self.height, TOP_CAP)] {
+                let t = (cap_y - start.y) / dir.y;
+                let hit = start + t * dir;
+                if hit.x * hit.x + hit.z * hit.z <= self.radius * self.radius {
+                    hits.push((t, face));
+                }
+            }
+        }
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        hits
+    }
+
+    fn tangents(&self, position: Point, face: usize) -> (Vector, Vector) {
+        let local = self.frame.to_local_point(position);
+        match face {
+            SIDE => (
+                self.frame
+                    .to_world_vector(2.0 * PI * Vector::new(-local.z, 0.0, local.x)),
+                self.frame.y * self.height,
+            ),
+            _ => (
+                self.frame.x * 2.0 * self.radius,
+                self.frame.z * 2.0 * self.radius,
+            ),
+        }
+    }
+}
+
+impl Coloured for Cylinder {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let tangents = self.tangents(position, location.primitive);
+        let uv = get_uv(location);
+        surface_colour(
+            self.colour,
+            &self.texture,
+            position,
+            uv,
+            tangents,
+            footprint,
+        )
+    }
+}
+
+impl Intersectable for Cylinder {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (distance, face) = nearest_hit(&self.crossings(ray))?;
+        Some(self.location(ray, distance, face))
+    }
+}
+
+/// Cone with its base on `base`, narrowing to a point `height` along `axis`.
+pub struct Cone {
+    frame: Frame,
+    pub radius: f32,
+    pub height: f32,
+    pub capped: bool,
+    pub colour: ColourFloat,
+    pub texture: Option<Texture>,
+    pub material: Material,
+}
+
+impl Cone {
+    pub fn new(
+        base: Point,
+        axis: Vector,
+        radius: f32,
+        height: f32,
+        colour: ColourFloat,
+        material: Material,
+    ) -> Self {
+        Cone {
+            frame: Frame::new(base, axis),
+            radius,
+            height,
+            capped: true,
+            colour,
+            texture: None,
+            material,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn get_material(&self) -> &Material {
+        &self.material
+    }
+
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
+        if location.primitive == BOTTOM_CAP {
+            return -self.frame.y;
+        }
+        let local = self.frame.to_local_point(position);
+        let radial = (local.x * local.x + local.z * local.z).sqrt();
+        if utils::is_zero(radial) {
+            // The apex
+            return self.frame.y;
+        }
+        let slope = self.radius / self.height;
+        self.frame
+            .to_world_vector(Vector::new(local.x / radial, slope, local.z / radial))
+            .normalize()
+    }
+
+    /// Where `ray` meets `face` of the surface `distance` along it
+    pub fn location(&self, ray: &Ray, distance: f32, face: usize) -> IntersectionLocation {
+        round_location(&self.frame, self.radius, self.height, ray, distance, face)
+    }
+
+    /// Every distance where `ray` crosses the surface, with the face it crosses
+    pub fn crossings(&self, ray: &Ray) -> Vec<(f32, usize)> {
+        let (start, dir) = self.frame.local_ray(ray);
+        let mut hits = Vec::with_capacity(3);
+        let k2 = (self.radius / self.height).powi(2);
+        let to_apex = self.height - start.y;
+        let a = dir.x * dir.x + dir.z * dir.z - k2 * dir.y * dir.y;
+        let b = 2.0 * (start.x * dir.x + start.z * dir.z + k2 * to_apex * dir.y);
+        let c = start.x * start.x + start.z * start.z - k2 * to_apex * to_apex;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for &t in &[t0, t1] {
+                // The quadratic also describes the mirrored cone above the apex
+                let y = start.y + t * dir.y;
+                if (0.0..=self.height).contains(&y) {
+                    hits.push((t, SIDE));
+                }
+            }
+        }
+        if self.capped && !utils::is_zero(dir.y) {
+            let t = -start.y / dir.y;
+            let hit = start + t * dir;
+            if hit.x * hit.x + hit.z * hit.z <= self.radius * self.radius {
+                hits.push((t, BOTTOM_CAP));
+            }
+        }
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        hits
+    }
+
+    fn tangents(&self, position: Point, face: usize) -> (Vector, Vector) {
+        let local = self.frame.to_local_point(position);
+        match face {
+            SIDE => {
+                let radial = (local.x * local.x + local.z * local.z).sqrt().max(1e-6);
+                let (cos_phi, sin_phi) = (local.x / radial, local.z / radial);
+                (
+                    self.frame
+                        .to_world_vector(2.0 * PI * Vector::new(-local.z, 0.0, local.x)),
+                    self.frame.to_world_vector(Vector::new(
+                        -self.radius * cos_phi,
+                        self.height,
+                        -self.radius * sin_phi,
+                    )),
+                )
+            }
+            _ => (
+                self.frame.x * 2.0 * self.radius,
+                self.frame.z * 2.0 * self.radius,
+            ),
+        }
+    }
+}
+
+impl Coloured for Cone {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let tangents = self.tangents(position, location.primitive);
+        let uv = get_uv(location);
+        surface_colour(
+            self.colour,
+            &self.texture,
+            position,
+            uv,
+            tangents,
+            footprint,
+        )
+    }
+}
+
+impl Intersectable for Cone {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (distance, face) = nearest_hit(&self.crossings(ray))?;
+        Some(self.location(ray, distance, face))
+    }
+}
+
+/// Torus around `axis`, the tube of `minor_radius` following a circle of `major_radius`.
+pub struct Torus {
+    frame: Frame,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub colour: ColourFloat,
+    pub texture: Option<Texture>,
+    pub material: Material,
+}
+
+impl Torus {
+    pub fn new(
+        centre: Point,
+        axis: Vector,
+        major_radius: f32,
+        minor_radius: f32,
+        colour: ColourFloat,
+        material: Material,
+    ) -> Self {
+        Torus {
+            frame: Frame::new(centre, axis),
+            major_radius,
+            minor_radius,
+            colour,
+            texture: None,
+            material,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn get_material(&self) -> &Material {
+        &self.material
+    }
+
+    /// Closest point on the circle through the middle of the tube
+    fn tube_centre(&self, local: Point) -> Point {
+        let radial = Vector::new(local.x, 0.0, local.z);
+        if utils::is_zero(radial.magnitude2()) {
+            return Vector::new(self.major_radius, 0.0, 0.0);
+        }
+        radial.normalize() * self.major_radius
+    }
+
+    fn signed_distance(&self, local: Point) -> f32 {
+        (local - self.tube_centre(local)).magnitude() - self.minor_radius
+    }
+
+    /// Distances along a local ray to where it enters and leaves the bounding sphere
+    fn bounds(&self, start: Point, dir: Vector) -> Option<(f32, f32)> {
+        let bound = self.major_radius + self.minor_radius;
+        solve_quadratic(
+            1.0,
+            2.0 * start.dot(dir),
+            start.magnitude2() - bound * bound,
+        )
+    }
+
+    /// Distances along a local ray to where its line meets the surface, in order, from
+    /// the ray-torus quartic (|p|^2 + R^2 - r^2)^2 = 4 R^2 (p.x^2 + p.z^2)
+    fn crossings(&self, start: Point, dir: Vector) -> Vec<f32> {
+        let (enter, _) = match self.bounds(start, dir) {
+            Some(bounds) => bounds,
+            None => return Vec::new(),
+        };
+        // Solved from where the line enters the bounding sphere, where the roots are small
+        let from = start + enter * dir;
+        let [px, py, pz] = [from.x as f64, from.y as f64, from.z as f64];
+        let [dx, dy, dz] = [dir.x as f64, dir.y as f64, dir.z as f64];
+        let (major, minor) = (self.major_radius as f64, self.minor_radius as f64);
+        let m = dx * dx + dy * dy + dz * dz;
+        let n = px * dx + py * dy + pz * dz;
+        let k = px * px + py * py + pz * pz + major * major - minor * minor;
+        let four_r2 = 4.0 * major * major;
+        let a = dx * dx + dz * dz;
+        let b = px * dx + pz * dz;
+        let c = px * px + pz * pz;
+        solve_quartic([
+            m * m,
+            4.0 * m * n,
+            4.0 * n * n + 2.0 * m * k - four_r2 * a,
+            4.0 * n * k - 2.0 * four_r2 * b,
+            k * k - four_r2 * c,
+        ])
+        .into_iter()
+        .map(|t| enter + t as f32)
+        .collect()
+    }
+
+    /// Stretches of the whole line of a local ray that lie inside the tube, in order
+    pub(crate) fn chords(&self, start: Point, dir: Vector) -> Vec<(f32, f32)> {
+        let crossings = self.crossings(start, dir);
+        // Grazing rays can touch the surface without crossing it, so check between crossings
+        let mut chords: Vec<(f32, f32)> = Vec::new();
+        for pair in crossings.windows(2) {
+            let middle = start + 0.5 * (pair[0] + pair[1]) * dir;
+            if self.signed_distance(middle) >= 0.0 {
+                continue;
+            }
+            match chords.last_mut() {
+                Some(chord) if chord.1 == pair[0] => chord.1 = pair[1],
+                _ => chords.push((pair[0], pair[1])),
+            }
+        }
+        chords
+    }
+
+    pub(crate) fn location(&self, local: Point, distance: f32) -> IntersectionLocation {
+        let tube = local - self.tube_centre(local);
+        let outward = tube.dot(self.tube_centre(local).normalize());
+        let uv = Uv::new(azimuth(local), 0.5 + tube.y.atan2(outward) / (2.0 * PI));
+        IntersectionLocation::new(distance, TextureCoords::Uv(uv))
+    }
+
+    pub fn get_normal(&self, position: Point, _location: &IntersectionLocation) -> Vector {
+        let local = self.frame.to_local_point(position);
+        self.frame
+            .to_world_vector(local - self.tube_centre(local))
+            .normalize()
+    }
+
+    fn tangents(&self, position: Point) -> (Vector, Vector) {
+        let local = self.frame.to_local_point(position);
+        let tube = local - self.tube_centre(local);
+        let radial = Vector::new(local.x, 0.0, local.z);
+        let outward = if utils::is_zero(radial.magnitude2()) {
+            Vector::unit_x()
+        } else {
+            radial.normalize()
+        };
+        // Around the main axis, then around the tube
+        let around_axis = 2.0 * PI * Vector::new(-local.z, 0.0, local.x);
+        let around_tube = 2.0 * PI * (Vector::unit_y() * tube.dot(outward) - outward * tube.y);
+        (
+            self.frame.to_world_vector(around_axis),
+            self.frame.to_world_vector(around_tube),
+        )
+    }
+}
+
+impl Coloured for Torus {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        let tangents = self.tangents(position);
+        let uv = get_uv(location);
+        surface_colour(
+            self.colour,
+            &self.texture,
+            position,
+            uv,
+            tangents,
+            footprint,
+        )
+    }
+}
+
+impl Intersectable for Torus {
+    fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
+        let (start, dir) = self.frame.local_ray(ray);
+        let distance = self
+            .crossings(start, dir)
+            .into_iter()
+            .find(|&distance| utils::is_positive(distance))?;
+        Some(self.location(start + distance * dir, distance))
+    }
+}
@@ -1,5 +1,17 @@
 use cgmath::prelude::*;
 
+use crate::background::*;
+use crate::csg::*;
+use crate::instance::*;
+use crate::medium::*;
+use crate::mesh::*;
+use crate::packet::{self, PacketIntersections, RayPacket, PACKET_SIZE};
+use crate::primitives::*;
+use crate::sampler::Sampler;
+use crate::sampling;
+use crate::scene_graph::SceneGraph;
+use crate::sky::Sky;
+use crate::texture::*;
 use crate::utils;
 use image::{Rgb, RgbImage};
 
@@ -10,35 +22,354 @@ pub type Vector = cgmath::Vector3<f32>;
 pub type RotationMatrix = cgmath::Matrix4<f32>;
 pub type Degrees = cgmath::Deg<f32>;
 
-const BACKGROUND_TOP: ColourFloat = ColourFloat::new(1.0, 1.0, 1.0);
-const BACKGROUND_BOTTOM: ColourFloat = ColourFloat::new(0.5, 0.7, 1.0);
+const LAMBERTIAN_ALBEDO: f32 = 0.5;
+const MAX_SUBSURFACE_STEPS: u32 = 1024;
 
 pub struct Scene {
     pub objects: Vec<Object>,
+    /// Named objects, rendered alongside `objects`
+    pub graph: SceneGraph,
+    pub background: Background,
+    pub lights: Vec<Light>,
+    pub volumes: Vec<Volume>,
+    /// Medium filling the space between surfaces. Rays that escape the scene leave it.
+    pub fog: Option<Medium>,
 }
 
 impl Scene {
-    pub fn closest_intersection(&self, ray: &Ray) -> Option<Intersection> {
-        let mut closest_dist = f32::MAX;
-        let mut closest_isect: Option<Intersection> = None;
-        for object in self.objects.iter() {
-            match object.intersection(ray) {
-                Some(location) => {
-                    if location.distance < closest_dist {
-                        closest_dist = location.distance;
-                        closest_isect = Some(Intersection::new(location, object));
-                    }
+    pub fn new(objects: Vec<Object>) -> Self {
+        Scene {
+            objects,
+            graph: SceneGraph::new(),
+            background: Background::default(),
+            lights: Vec::new(),
+            volumes: Vec::new(),
+            fog: None,
+        }
+    }
+
+    /// Light the scene with a daylight sky and its sun
+    pub fn set_sky(&mut self, sky: Sky, sun_irradiance: f32) {
+        self.lights.push(sky.sun(sun_irradiance));
+        self.background = Background::Sky(sky);
+    }
+
+    pub fn closest_intersection(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        RAYS_CAST.with(|rays| rays.set(rays.get() + 1));
+        let loose = closest_intersection(&self.objects, ray)
+            .map(|(index, location)| Intersection::new(location, &self.objects[index]));
+        let named = self.graph.objects();
+        let named = closest_intersection(named, ray)
+            .map(|(index, location)| Intersection::new(location, &named[index]));
+        match (loose, named) {
+            (Some(a), Some(b)) if b.location.distance < a.location.distance => Some(b),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Nearest intersections of up to `PACKET_SIZE` rays, exactly as
+    /// `closest_intersection` finds each, but testing them together. Lanes past the
+    /// last ray are `None`.
+    pub fn closest_intersections(&self, rays: &[Ray]) -> [Option<Intersection<'_>>; PACKET_SIZE] {
+        RAYS_CAST.with(|count| count.set(count.get() + rays.len() as u64));
+        let packet = RayPacket::new(rays);
+        let mut loose = closest_packet_intersection(&self.objects, &packet);
+        let named = self.graph.objects();
+        let mut named_hits = closest_packet_intersection(named, &packet);
+        std::array::from_fn(|lane| {
+            if lane >= rays.len() {
+                return None;
+            }
+            let loose = loose[lane]
+                .take()
+                .map(|(index, location)| Intersection::new(location, &self.objects[index]));
+            let named_hit = named_hits[lane]
+                .take()
+                .map(|(index, location)| Intersection::new(location, &named[index]));
+            match (loose, named_hit) {
+                (Some(a), Some(b)) if b.location.distance < a.location.distance => Some(b),
+                (a, b) => a.or(b),
+            }
+        })
+    }
+
+    /// Parts of `ray` in each medium before `max_distance`, where it hits a surface
+    fn media_spans(&self, ray: &Ray, max_distance: f32) -> Vec<(&Medium, f32, f32)> {
+        let mut spans = Vec::new();
+        if let Some(ref fog) = self.fog {
+            if max_distance.is_finite() {
+                spans.push((fog, 0.0, max_distance));
+            }
+        }
+        for volume in self.volumes.iter() {
+            for (near, far) in volume.spans(ray, max_distance) {
+                spans.push((&volume.medium, near, far));
+            }
+        }
+        spans
+    }
+
+    /// Nearest point along `ray` where light scatters or is absorbed in a medium, if
+    /// that happens before `max_distance`
+    pub fn medium_collision(
+        &self,
+        ray: &Ray,
+        max_distance: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(f32, &Medium)> {
+        // Overlapping media add up, so the nearest collision in any of them is the first
+        let mut closest = None;
+        let mut closest_distance = max_distance;
+        for (medium, near, far) in self.media_spans(ray, max_distance) {
+            if let Some(distance) =
+                medium.sample_collision(ray, near, far.min(closest_distance), sampler)
+            {
+                closest_distance = distance;
+                closest = Some((distance, medium));
+            }
+        }
+        closest
+    }
+
+    /// Fraction of light getting through the media along `ray` up to `max_distance`
+    pub fn transmittance(&self, ray: &Ray, max_distance: f32, sampler: &mut dyn Sampler) -> f32 {
+        self.media_spans(ray, max_distance)
+            .into_iter()
+            .map(|(medium, near, far)| medium.transmittance(ray, near, far, sampler))
+            .product()
+    }
+}
+
+thread_local! {
+    static RAYS_CAST: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Rays cast into scenes by this thread so far, camera, bounce and shadow rays alike
+pub fn rays_cast() -> u64 {
+    RAYS_CAST.with(|rays| rays.get())
+}
+
+/// Index of the nearest object hit by `ray`, and where it was hit
+pub fn closest_intersection(
+    objects: &[Object],
+    ray: &Ray,
+) -> Option<(usize, IntersectionLocation)> {
+    let mut closest_dist = f32::MAX;
+    let mut closest_isect = None;
+    for (index, object) in objects.iter().enumerate() {
+        if let Some(location) = object.intersection(ray) {
+            if location.distance < closest_dist {
+                closest_dist = location.distance;
+                closest_isect = Some((index, location));
+            }
+        }
+    }
+    closest_isect
+}
+
+/// `closest_intersection` for each of the packet's rays
+pub fn closest_packet_intersection(
+    objects: &[Object],
+    packet: &RayPacket,
+) -> [Option<(usize, IntersectionLocation)>; PACKET_SIZE] {
+    let mut closest_dist = [f32::MAX; PACKET_SIZE];
+    let mut closest_isect: [Option<(usize, IntersectionLocation)>; PACKET_SIZE] =
+        Default::default();
+    for (index, object) in objects.iter().enumerate() {
+        let locations = object.packet_intersection(packet);
+        // Most objects miss every ray, so don't go through the lanes for those
+        if locations.iter().all(Option::is_none) {
+            continue;
+        }
+        for (lane, location) in IntoIterator::into_iter(locations).enumerate() {
+            if let Some(location) = location {
+                if location.distance < closest_dist[lane] {
+                    closest_dist[lane] = location.distance;
+                    closest_isect[lane] = Some((index, location));
                 }
-                None => (),
             }
         }
-        closest_isect
+    }
+    closest_isect
+}
+
+pub enum Light {
+    /// Infinitely distant light, `direction` pointing towards it and `colour` its irradiance
+    Directional {
+        direction: Vector,
+        colour: ColourFloat,
+    },
+    /// Light shining equally in all directions from `position`, `colour` its intensity,
+    /// falling off with the square of distance
+    Point {
+        position: Point,
+        colour: ColourFloat,
+    },
+    /// Point light limited to a cone around `direction`, fading out between the cosines
+    /// of the inner and outer cone angles
+    Spot {
+        position: Point,
+        direction: Vector,
+        colour: ColourFloat,
+        cos_inner: f32,
+        cos_outer: f32,
+    },
+}
+
+impl Light {
+    /// Light reaching `position` from this light, and the unit direction it arrives from
+    pub fn illuminate(
+        &self,
+        position: Point,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Vector, ColourFloat)> {
+        let (dir, distance, colour) = match *self {
+            Light::Directional { direction, colour } => (direction, f32::INFINITY, colour),
+            Light::Point {
+                position: light_position,
+                colour,
+            } => {
+                let to_light = light_position - position;
+                let distance = to_light.magnitude();
+                (
+                    to_light / distance,
+                    distance,
+                    colour / (distance * distance),
+                )
+            }
+            Light::Spot {
+                position: light_position,
+                direction,
+                colour,
+                cos_inner,
+                cos_outer,
+            } => {
+                let to_light = light_position - position;
+                let distance = to_light.magnitude();
+                let dir = to_light / distance;
+                let cos_angle = -dir.dot(direction.normalize());
+                let falloff =
+                    ((cos_angle - cos_outer) / (cos_inner - cos_outer).max(1e-6)).clamp(0.0, 1.0);
+                if falloff <= 0.0 {
+                    return None;
+                }
+                (dir, distance, falloff * colour / (distance * distance))
+            }
+        };
+        let shadow_ray = Ray::new(position, dir);
+        if let Some(i) = scene.closest_intersection(&shadow_ray) {
+            if i.location.distance < distance {
+                return None;
+            }
+        }
+        let transmittance = scene.transmittance(&shadow_ray, distance, sampler);
+        if transmittance > 0.0 {
+            Some((dir, transmittance * colour))
+        } else {
+            None
+        }
     }
 }
 
 pub struct Ray {
     pub start: Point,
     pub dir: Vector,
+    pub differentials: Option<RayDifferentials>,
+}
+
+impl Ray {
+    pub fn new(start: Point, dir: Vector) -> Self {
+        Ray {
+            start,
+            dir,
+            differentials: None,
+        }
+    }
+}
+
+/// Rays offset by one pixel in x and y, tracking how the pixel footprint spreads.
+#[derive(Clone, Copy)]
+pub struct RayDifferentials {
+    pub rx_start: Point,
+    pub rx_dir: Vector,
+    pub ry_start: Point,
+    pub ry_dir: Vector,
+}
+
+impl RayDifferentials {
+    /// Shrink the offset rays towards the main ray, e.g. when taking several samples per pixel
+    pub fn scale(&self, ray: &Ray, scale: f32) -> Self {
+        RayDifferentials {
+            rx_start: ray.start + (self.rx_start - ray.start) * scale,
+            rx_dir: ray.dir + (self.rx_dir - ray.dir) * scale,
+            ry_start: ray.start + (self.ry_start - ray.start) * scale,
+            ry_dir: ray.dir + (self.ry_dir - ray.dir) * scale,
+        }
+    }
+
+    /// Where the offset rays hit the tangent plane at `position`
+    fn plane_hits(&self, position: Point, normal: Vector) -> (Point, Point) {
+        let plane_hit = |start: Point, dir: Vector| {
+            let distance = normal.dot(position - start) / normal.dot(dir);
+            start + distance * dir
+        };
+        (
+            plane_hit(self.rx_start, self.rx_dir),
+            plane_hit(self.ry_start, self.ry_dir),
+        )
+    }
+
+    pub fn footprint(&self, position: Point, normal: Vector) -> Footprint {
+        let (px, py) = self.plane_hits(position, normal);
+        Footprint {
+            dpdx: px - position,
+            dpdy: py - position,
+        }
+    }
+
+    /// Mirror the offset rays about the tangent plane at `position`
+    pub fn reflect(&self, position: Point, normal: Vector) -> Self {
+        let (px, py) = self.plane_hits(position, normal);
+        RayDifferentials {
+            rx_start: px,
+            rx_dir: reflect(&self.rx_dir, &normal),
+            ry_start: py,
+            ry_dir: reflect(&self.ry_dir, &normal),
+        }
+    }
+}
+
+/// Change in surface position between neighbouring pixels.
+#[derive(Clone, Copy)]
+pub struct Footprint {
+    pub dpdx: Vector,
+    pub dpdy: Vector,
+}
+
+impl Footprint {
+    /// No footprint, textures are point sampled
+    pub fn zero() -> Self {
+        Footprint {
+            dpdx: Vector::zero(),
+            dpdy: Vector::zero(),
+        }
+    }
+
+    /// Convert to texture space, given the derivatives of position with respect to `u` and `v`
+    pub fn uv_derivatives(&self, dpdu: Vector, dpdv: Vector) -> (Uv, Uv) {
+        // Least squares solution of dp = du * dpdu + dv * dpdv
+        let (a, b, c) = (dpdu.dot(dpdu), dpdu.dot(dpdv), dpdv.dot(dpdv));
+        let determinant = a * c - b * b;
+        if utils::is_zero(determinant) {
+            return (Uv::zero(), Uv::zero());
+        }
+        let solve = |dp: Vector| {
+            let (pu, pv) = (dpdu.dot(dp), dpdv.dot(dp));
+            Uv::new(c * pu - b * pv, a * pv - b * pu) / determinant
+        };
+        (solve(self.dpdx), solve(self.dpdy))
+    }
 }
 
 pub fn rotate(dir: &Vector, rotation_matrix: &RotationMatrix) -> Vector {
@@ -47,53 +378,227 @@ pub fn rotate(dir: &Vector, rotation_matrix: &RotationMatrix) -> Vector {
     utils::to_3(&rotated_dir)
 }
 
+/// Mirror `dir` about `normal`, which needn't be normalised. The result is as long as `dir`.
 pub fn reflect(dir: &Vector, normal: &Vector) -> Vector {
-    (dir - (2.0 * dir.dot(*normal) * normal)).normalize()
+    let normal = normal.normalize();
+    dir - (2.0 * dir.dot(normal) * normal)
 }
 
-pub fn diffuse(normal: &Vector) -> Vector {
-    loop {
-        let p = utils::rand_vector_range(-1.0, 1.0);
-        if p.magnitude2() < 1.0 {
-            return (normal + p).normalize();
+/// Light arriving at a Lambertian surface, choosing between a diffuse bounce and
+/// a direction sampled from the background, weighted by the combined pdf.
+fn trace_diffuse(
+    position: Point,
+    normal: Vector,
+    scene: &Scene,
+    depth: u32,
+    sampler: &mut dyn Sampler,
+) -> ColourFloat {
+    let start = position + (normal * 0.005);
+    let mut direct = ColourFloat::zero();
+    for light in scene.lights.iter() {
+        if let Some((dir, colour)) = light.illuminate(start, scene, sampler) {
+            direct += colour * normal.dot(dir).max(0.0) / std::f32::consts::PI;
         }
     }
+    LAMBERTIAN_ALBEDO * direct + trace_indirect(start, normal, scene, depth, sampler)
 }
 
-pub fn trace(ray: Ray, scene: &Scene, depth: u32) -> ColourFloat {
-    match scene.closest_intersection(&ray) {
+fn trace_indirect(
+    start: Point,
+    normal: Vector,
+    scene: &Scene,
+    depth: u32,
+    sampler: &mut dyn Sampler,
+) -> ColourFloat {
+    // One sample for either strategy, so each bounce takes the same dimensions
+    let choice = sampler.get_1d();
+    let [u, v] = sampler.get_2d();
+    let background_sample = if choice < 0.5 {
+        scene.background.sample(u, v)
+    } else {
+        None
+    };
+    let dir = match background_sample {
+        Some((dir, _)) => dir,
+        None => sampling::cosine_hemisphere(normal, [u, v]).0,
+    };
+    if !scene.background.is_sampled() {
+        // Nothing to importance sample, plain diffuse bounce
+        return LAMBERTIAN_ALBEDO * trace(Ray::new(start, dir), scene, depth - 1, sampler);
+    }
+    // Either strategy could have picked `dir`, even where the background's pdf is zero
+    let background_pdf = scene.background.pdf(dir);
+    let cos_pdf = sampling::cosine_hemisphere_pdf(normal, dir);
+    if cos_pdf <= 0.0 {
+        return ColourFloat::zero();
+    }
+    let weight = cos_pdf / (0.5 * cos_pdf + 0.5 * background_pdf);
+    LAMBERTIAN_ALBEDO * weight * trace(Ray::new(start, dir), scene, depth - 1, sampler)
+}
+
+/// Light scattered towards `-dir` by a medium at `position`, from the lights and
+/// a direction drawn from the phase function.
+fn trace_scattering(
+    position: Point,
+    dir: Vector,
+    medium: &Medium,
+    scene: &Scene,
+    depth: u32,
+    sampler: &mut dyn Sampler,
+) -> ColourFloat {
+    let mut direct = ColourFloat::zero();
+    for light in scene.lights.iter() {
+        if let Some((light_dir, colour)) = light.illuminate(position, scene, sampler) {
+            direct += colour * medium.phase.eval(light_dir.dot(dir));
+        }
+    }
+    // Sampling the phase function exactly, so its pdf cancels
+    let [u, v] = sampler.get_2d();
+    let scattered = medium.phase.sample(dir, u, v);
+    let indirect = trace(Ray::new(position, scattered), scene, depth - 1, sampler);
+    medium.albedo() * (direct + indirect)
+}
+
+/// Light leaving a translucent `object` after entering it at `position`, following a
+/// random walk inside it until it reaches the surface again. `mean_free_path` and
+/// `albedo` are those of its `Subsurface` material.
+fn trace_subsurface(
+    object: &Object,
+    position: Point,
+    normal: Vector,
+    (mean_free_path, albedo): (f32, ColourFloat),
+    scene: &Scene,
+    depth: u32,
+    sampler: &mut dyn Sampler,
+) -> ColourFloat {
+    let mut throughput = ColourFloat::new(1.0, 1.0, 1.0);
+    let mut start = position - (normal * 0.005);
+    let mut dir = sampling::cosine_hemisphere(-normal, sampler.get_2d()).0;
+    for _ in 0..MAX_SUBSURFACE_STEPS {
+        let step = -(1.0 - sampler.get_1d()).ln() * mean_free_path;
+        // Spans see the surface from inside too, unlike triangles' intersections
+        let crossing = object
+            .spans(&Ray::new(start, dir))
+            .into_iter()
+            .flat_map(|span| IntoIterator::into_iter([span.enter, span.exit]))
+            .find(|location| utils::is_positive(location.distance));
+        match crossing {
+            Some(location) if location.distance <= step => {
+                // Out through the surface, lit like a diffuse surface from there
+                let exit = start + location.distance * dir;
+                let mut exit_normal = object.get_normal(exit, &location);
+                if exit_normal.dot(dir) < 0.0 {
+                    exit_normal = -exit_normal;
+                }
+                let outgoing = trace_diffuse(exit, exit_normal, scene, depth, sampler);
+                return throughput.mul_element_wise(outgoing);
+            }
+            Some(_) => {
+                start += step * dir;
+                throughput = throughput.mul_element_wise(albedo);
+                dir = sampling::uniform_sphere(sampler.get_2d()).0;
+            }
+            // The object isn't closed, the light is lost
+            None => return ColourFloat::zero(),
+        }
+    }
+    ColourFloat::zero()
+}
+
+/// `normal` turned back towards where a ray travelling along `dir` came from. Triangles'
+/// normals point away from the side they're seen from, planes and discs are seen from
+/// both sides, and other surfaces can be seen from inside.
+fn facing(normal: Vector, dir: Vector) -> Vector {
+    if normal.dot(dir) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+pub fn trace(ray: Ray, scene: &Scene, depth: u32, sampler: &mut dyn Sampler) -> ColourFloat {
+    let intersection = scene.closest_intersection(&ray);
+    trace_hit(ray, intersection, scene, depth, sampler)
+}
+
+/// `trace` for a ray whose nearest intersection has already been found
+pub fn trace_hit(
+    ray: Ray,
+    intersection: Option<Intersection>,
+    scene: &Scene,
+    depth: u32,
+    sampler: &mut dyn Sampler,
+) -> ColourFloat {
+    let surface_distance = intersection
+        .as_ref()
+        .map_or(f32::INFINITY, |i| i.location.distance);
+    if let Some((distance, medium)) = scene.medium_collision(&ray, surface_distance, sampler) {
+        if depth == 0 {
+            return ColourFloat::zero();
+        }
+        let position = ray.start + distance * ray.dir;
+        return trace_scattering(position, ray.dir, medium, scene, depth, sampler);
+    }
+    match intersection {
         Some(i) => {
-            let isect_position: Point = i.location.distance * ray.dir;
+            let isect_position: Point = ray.start + i.location.distance * ray.dir;
             use Material::*;
-            match *i.object.get_material() {
-                Specular => {
-                    if depth > 0 {
-                        let normal = i.object.get_normal(isect_position);
-                        let reflected_ray = Ray {
-                            start: isect_position + (normal * 0.005),
-                            dir: reflect(&ray.dir, &normal),
-                        };
-                        0.9 * trace(reflected_ray, scene, depth - 1)
-                    } else {
-                        i.object.get_colour(i.location.texture_coords)
-                    }
+            match *i.object.get_material(&i.location) {
+                Specular if depth > 0 => {
+                    let normal = i.object.get_normal(isect_position, &i.location);
+                    let normal = facing(normal, ray.dir);
+                    let reflected_ray = Ray {
+                        start: isect_position + (normal * 0.005),
+                        dir: reflect(&ray.dir, &normal),
+                        differentials: ray.differentials.map(|d| d.reflect(isect_position, normal)),
+                    };
+                    0.9 * trace(reflected_ray, scene, depth - 1, sampler)
+                }
+                Specular | Diffuse => {
+                    let footprint = match ray.differentials {
+                        Some(differentials) => {
+                            let normal = i.object.get_normal(isect_position, &i.location);
+                            differentials.footprint(isect_position, normal)
+                        }
+                        None => Footprint::zero(),
+                    };
+                    i.object.get_colour(isect_position, &i.location, &footprint)
                 }
-                Diffuse => i.object.get_colour(i.location.texture_coords),
                 Lambertian => {
                     if depth > 0 {
-                        let normal = i.object.get_normal(isect_position);
-                        let diffuse_ray = Ray {
-                            start: isect_position + (normal * 0.005),
-                            dir: diffuse(&normal),
-                        };
-                        0.5 * trace(diffuse_ray, scene, depth - 1)
+                        let normal = i.object.get_normal(isect_position, &i.location);
+                        let normal = facing(normal, ray.dir);
+                        trace_diffuse(isect_position, normal, scene, depth, sampler)
                     } else {
                         ColourFloat::zero()
                     }
                 }
+                Subsurface {
+                    mean_free_path,
+                    albedo,
+                } => {
+                    if depth == 0 {
+                        return ColourFloat::zero();
+                    }
+                    let normal = i.object.get_normal(isect_position, &i.location);
+                    let normal = facing(normal, ray.dir);
+                    if !i.object.is_solid() {
+                        // No inside to walk through, e.g. triangles or open meshes
+                        return trace_diffuse(isect_position, normal, scene, depth, sampler);
+                    }
+                    trace_subsurface(
+                        i.object,
+                        isect_position,
+                        normal,
+                        (mean_free_path, albedo),
+                        scene,
+                        depth,
+                        sampler,
+                    )
+                }
             }
         }
-        None => ((1.0 - ray.dir.y) * BACKGROUND_TOP) + (ray.dir.y * BACKGROUND_BOTTOM),
+        None => scene.background.get_colour(ray.dir),
     }
 }
 
@@ -102,6 +607,10 @@ pub struct Camera {
     pub focal_length: f32,
     pub yaw: Degrees,
     pub rotation_matrix: RotationMatrix,
+    /// Radius of the lens, zero for a pinhole camera with everything in focus
+    pub aperture: f32,
+    /// Distance in front of the camera that's in focus with a lens
+    pub focus_distance: f32,
 }
 
 impl Camera {
@@ -114,6 +623,20 @@ impl Camera {
             focal_length,
             yaw,
             rotation_matrix,
+            aperture: 0.0,
+            focus_distance: 1.0,
+        }
+    }
+
+    /// Camera at `location` as it is, rather than rotated by `yaw`, e.g. a saved pose
+    pub fn posed(location: Point, focal_length: f32, yaw: Degrees) -> Self {
+        Camera {
+            location,
+            focal_length,
+            yaw,
+            rotation_matrix: RotationMatrix::from_angle_y(yaw),
+            aperture: 0.0,
+            focus_distance: 1.0,
         }
     }
 
@@ -139,6 +662,7 @@ impl Camera {
 
 pub struct Visualiser {
     pub screen: RgbImage,
+    #[allow(dead_code)]
     pub aspect_ratio: f32,
     pub camera: Camera,
 }
@@ -161,6 +685,62 @@ impl Visualiser {
     }
 
     pub fn create_camera_ray(&self, x: f32, y: f32) -> Ray {
+        let start = self.camera.location;
+        Ray {
+            start,
+            dir: self.camera_ray_dir(x, y),
+            differentials: Some(RayDifferentials {
+                rx_start: start,
+                rx_dir: self.camera_ray_dir(x + 1.0, y),
+                ry_start: start,
+                ry_dir: self.camera_ray_dir(x, y + 1.0),
+            }),
+        }
+    }
+
+    /// Camera ray through image position (x, y), where pixel (i, j) spans i to i + 1
+    /// across and j to j + 1 down, and from a sampled point on the lens if there is one.
+    /// Its differentials are narrowed to the spacing between `samples` rays per pixel.
+    pub fn sample_camera_ray(
+        &self,
+        [x, y]: [f32; 2],
+        samples: u32,
+        sampler: &mut dyn Sampler,
+    ) -> Ray {
+        let mut ray = self.create_camera_ray(x, y);
+        let lens_sample = sampler.get_2d();
+        if self.camera.aperture > 0.0 {
+            self.focus(&mut ray, lens_sample);
+        }
+        ray.differentials = ray
+            .differentials
+            .map(|d| d.scale(&ray, 1.0 / (samples as f32).sqrt()));
+        ray
+    }
+
+    /// Move the start of a pinhole `ray` to a point on the lens, keeping where it
+    /// crosses the plane in focus
+    fn focus(&self, ray: &mut Ray, sample: [f32; 2]) {
+        let camera = &self.camera;
+        let ([x, y], _) = sampling::concentric_disc(sample);
+        let offset = camera.aperture * Vector::new(x, y, 0.0);
+        let start = camera.location + rotate(&offset, &camera.rotation_matrix);
+        let forward = rotate(&-Vector::unit_z(), &camera.rotation_matrix);
+        let refocus = |dir: Vector| {
+            let target = camera.location + dir * (camera.focus_distance / dir.dot(forward));
+            (target - start).normalize()
+        };
+        ray.start = start;
+        ray.dir = refocus(ray.dir);
+        if let Some(ref mut d) = ray.differentials {
+            d.rx_start = start;
+            d.ry_start = start;
+            d.rx_dir = refocus(d.rx_dir);
+            d.ry_dir = refocus(d.ry_dir);
+        }
+    }
+
+    fn camera_ray_dir(&self, x: f32, y: f32) -> Vector {
         let x_screen = ((x + 0.5) / self.screen.width() as f32) * 2.0 - 1.0;
         let y_screen = 1.0 - ((y + 0.5) / self.screen.height() as f32) * 2.0;
         let ray_dir = Vector {
@@ -168,11 +748,7 @@ impl Visualiser {
             y: y_screen,
             z: -self.camera.focal_length,
         };
-
-        Ray {
-            start: self.camera.location,
-            dir: rotate(&ray_dir, &self.camera.rotation_matrix).normalize(),
-        }
+        rotate(&ray_dir, &self.camera.rotation_matrix).normalize()
     }
 
     pub fn put_pixel(&mut self, x: u32, y: u32, colour: Colour) {
@@ -193,11 +769,28 @@ pub enum Material {
     Specular,
     Diffuse,
     Lambertian,
+    /// Translucent material like wax, skin or milk, where light wanders inside before leaving.
+    /// `mean_free_path` is the average distance between scattering events, and `albedo` the
+    /// fraction of each colour that survives each one. Needs a closed object.
+    Subsurface {
+        mean_free_path: f32,
+        albedo: ColourFloat,
+    },
 }
 
 pub enum Object {
     Triangle(Triangle),
+    Mesh(Mesh),
     Sphere(Sphere),
+    Plane(Plane),
+    Disc(Disc),
+    Cuboid(Cuboid),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Torus(Torus),
+    Instance(Instance),
+    Group(Group),
+    Csg(Csg),
 }
 
 pub struct Triangle {
@@ -205,6 +798,10 @@ pub struct Triangle {
     pub v1: Point,
     pub v2: Point,
     pub colours: [ColourFloat; 3],
+    pub normals: Option<[Vector; 3]>,
+    pub uvs: Option<[Uv; 3]>,
+    pub texture: Option<Texture>,
+    pub normal_map: Option<NormalMap>,
     pub material: Material,
     normal: Vector,
 }
@@ -222,13 +819,17 @@ impl Triangle {
             v1,
             v2,
             colours,
+            normals: None,
+            uvs: None,
+            texture: None,
+            normal_map: None,
             material,
             normal: compute_normal(v0, v1, v2),
         }
     }
 }
 
-fn compute_normal(v0: Point, v1: Point, v2: Point) -> Vector {
+pub fn compute_normal(v0: Point, v1: Point, v2: Point) -> Vector {
     let v1v0 = v1 - v0;
     let v2v0 = v2 - v0;
     v2v0.cross(v1v0).normalize()
@@ -238,11 +839,17 @@ pub struct Sphere {
     pub centre: Point,
     pub radius: f32,
     pub colour: ColourFloat,
+    pub texture: Option<Texture>,
     pub material: Material,
 }
 
 pub trait Coloured {
-    fn get_colour(&self, texture_coords: TextureCoords) -> ColourFloat;
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat;
 }
 
 pub fn as_float(colour: Colour) -> ColourFloat {
@@ -258,51 +865,111 @@ pub fn as_int4(colour: ColourFloat) -> [u8; 4] {
 }
 
 impl Coloured for Triangle {
-    fn get_colour(&self, texture_coords: TextureCoords) -> ColourFloat {
-        use TextureCoords::*;
-        match texture_coords {
-            Barycentric(coords) => {
-                coords.u * self.colours[0] + coords.v * self.colours[1] + coords.w * self.colours[2]
-            }
-            None => panic!("Incorrect texture coord type specified for triangle."),
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        match location.texture_coords {
+            TextureCoords::Barycentric(ref coords) => self.shading().get_colour(
+                position,
+                coords,
+                self.colours,
+                self.texture.as_ref(),
+                footprint,
+            ),
+            _ => panic!("Incorrect texture coord type specified for triangle."),
         }
     }
 }
 
 impl Coloured for Sphere {
-    fn get_colour(&self, texture_coords: TextureCoords) -> ColourFloat {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
         use TextureCoords::*;
-        match texture_coords {
-            Barycentric(_) => panic!("Incorrect texture coord type specified for sphere."),
-            None => self.colour,
+        match (&location.texture_coords, &self.texture) {
+            (Barycentric(_), _) | (Uv(_), _) => {
+                panic!("Incorrect texture coord type specified for sphere.")
+            }
+            (None, Some(texture)) => {
+                let (dpdu, dpdv) = self.get_tangents(position);
+                let (duvdx, duvdy) = footprint.uv_derivatives(dpdu, dpdv);
+                texture.eval(&TexturePoint {
+                    position,
+                    uv: self.get_uv(position),
+                    duvdx,
+                    duvdy,
+                })
+            }
+            (None, Option::None) => self.colour,
         }
     }
 }
 
 impl Coloured for Object {
-    fn get_colour(&self, texture_coords: TextureCoords) -> ColourFloat {
+    fn get_colour(
+        &self,
+        position: Point,
+        location: &IntersectionLocation,
+        footprint: &Footprint,
+    ) -> ColourFloat {
         use Object::*;
         match *self {
-            Triangle(ref t) => t.get_colour(texture_coords),
-            Sphere(ref s) => s.get_colour(texture_coords),
+            Triangle(ref t) => t.get_colour(position, location, footprint),
+            Mesh(ref m) => m.get_colour(position, location, footprint),
+            Sphere(ref s) => s.get_colour(position, location, footprint),
+            Plane(ref p) => p.get_colour(position, location, footprint),
+            Disc(ref d) => d.get_colour(position, location, footprint),
+            Cuboid(ref c) => c.get_colour(position, location, footprint),
+            Cylinder(ref c) => c.get_colour(position, location, footprint),
+            Cone(ref c) => c.get_colour(position, location, footprint),
+            Torus(ref t) => t.get_colour(position, location, footprint),
+            Instance(ref i) => i.get_colour(position, location, footprint),
+            Group(ref g) => g.get_colour(position, location, footprint),
+            Csg(ref c) => c.get_colour(position, location, footprint),
         }
     }
 }
 
 impl Object {
-    pub fn get_material(&self) -> &Material {
+    pub fn get_material(&self, location: &IntersectionLocation) -> &Material {
         use Object::*;
         match *self {
             Triangle(ref t) => t.get_material(),
+            Mesh(ref m) => m.get_material(location),
             Sphere(ref s) => s.get_material(),
+            Plane(ref p) => p.get_material(),
+            Disc(ref d) => d.get_material(),
+            Cuboid(ref c) => c.get_material(),
+            Cylinder(ref c) => c.get_material(),
+            Cone(ref c) => c.get_material(),
+            Torus(ref t) => t.get_material(),
+            Instance(ref i) => i.get_material(location),
+            Group(ref g) => g.get_material(location),
+            Csg(ref c) => c.get_material(location),
         }
     }
 
-    pub fn get_normal(&self, location: Point) -> Vector {
+    pub fn get_normal(&self, position: Point, location: &IntersectionLocation) -> Vector {
         use Object::*;
         match *self {
-            Triangle(ref t) => t.get_normal(location),
-            Sphere(ref s) => s.get_normal(location),
+            Triangle(ref t) => t.get_normal(position, &location.texture_coords),
+            Mesh(ref m) => m.get_normal(position, location),
+            Sphere(ref s) => s.get_normal(position),
+            Plane(ref p) => p.get_normal(position, location),
+            Disc(ref d) => d.get_normal(position, location),
+            Cuboid(ref c) => c.get_normal(position, location),
+            Cylinder(ref c) => c.get_normal(position, location),
+            Cone(ref c) => c.get_normal(position, location),
+            Torus(ref t) => t.get_normal(position, location),
+            Instance(ref i) => i.get_normal(position, location),
+            Group(ref g) => g.get_normal(position, location),
+            Csg(ref c) => c.get_normal(position, location),
         }
     }
 }
@@ -312,9 +979,57 @@ impl Sphere {
         &self.material
     }
 
+    /// Distances along `ray`'s line where it enters and leaves the sphere, either may be behind it
+    pub fn chord(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let to_centre: Vector = self.centre - ray.start;
+        // Right angle triangle side lengths, with to_centre as the hypotenuse
+        let adjacent: f32 = ray.dir.dot(to_centre);
+        let opposite_squared: f32 = to_centre.dot(to_centre) - (adjacent * adjacent);
+        let radius_squared: f32 = self.radius * self.radius;
+
+        // No intersect if opposite is greater than radius^2
+        if utils::is_greater_than(opposite_squared, radius_squared) {
+            return None;
+        }
+
+        // Length of chord (section of ray that is intersecting), zero for a ray that just
+        // grazes the sphere
+        let half_chord: f32 = (radius_squared - opposite_squared).max(0.0).sqrt();
+        // Intersection distances
+        Some((adjacent - half_chord, adjacent + half_chord))
+    }
+
     pub fn get_normal(&self, location: Point) -> Vector {
         ((location - self.centre) / self.radius).normalize()
     }
+
+    /// Latitude/longitude texture coordinates of a point on the surface
+    pub fn get_uv(&self, location: Point) -> Uv {
+        let dir = self.get_normal(location);
+        Uv::new(
+            0.5 + dir.z.atan2(dir.x) / (2.0 * std::f32::consts::PI),
+            0.5 + dir.y.clamp(-1.0, 1.0).asin() / std::f32::consts::PI,
+        )
+    }
+
+    /// Derivatives of position with respect to the latitude/longitude texture coordinates
+    pub fn get_tangents(&self, location: Point) -> (Vector, Vector) {
+        use std::f32::consts::PI;
+        let d = location - self.centre;
+        let horizontal = (d.x * d.x + d.z * d.z).sqrt();
+        if utils::is_zero(horizontal) {
+            // At a pole, longitude is undefined
+            return (
+                Vector::new(2.0 * PI * self.radius, 0.0, 0.0),
+                Vector::zero(),
+            );
+        }
+        let (cos_phi, sin_phi) = (d.x / horizontal, d.z / horizontal);
+        (
+            2.0 * PI * Vector::new(-d.z, 0.0, d.x),
+            PI * Vector::new(-d.y * cos_phi, horizontal, -d.y * sin_phi),
+        )
+    }
 }
 
 impl Triangle {
@@ -322,8 +1037,114 @@ impl Triangle {
         &self.material
     }
 
-    pub fn get_normal(&self, _location: Point) -> Vector {
-        self.normal
+    fn shading(&self) -> TriangleShading<'_> {
+        TriangleShading {
+            vertices: [self.v0, self.v1, self.v2],
+            normal: self.normal,
+            normals: self.normals,
+            uvs: self.uvs,
+            normal_map: self.normal_map.as_ref(),
+        }
+    }
+
+    pub fn get_normal(&self, location: Point, texture_coords: &TextureCoords) -> Vector {
+        self.shading().get_normal(location, texture_coords)
+    }
+}
+
+/// What's needed to shade a triangle, whether it stands alone or is a face of a mesh.
+pub struct TriangleShading<'a> {
+    pub vertices: [Point; 3],
+    /// Face normal
+    pub normal: Vector,
+    pub normals: Option<[Vector; 3]>,
+    pub uvs: Option<[Uv; 3]>,
+    pub normal_map: Option<&'a NormalMap>,
+}
+
+impl<'a> TriangleShading<'a> {
+    pub fn get_normal(&self, location: Point, texture_coords: &TextureCoords) -> Vector {
+        let coords = match texture_coords {
+            TextureCoords::Barycentric(coords) => coords,
+            _ => return self.normal,
+        };
+        // Smooth shading from the vertex normals, kept on the same side as the face
+        let normal = match self.normals {
+            Some(normals) => {
+                let n = coords.interpolate(normals).normalize();
+                if n.dot(self.normal) < 0.0 {
+                    -n
+                } else {
+                    n
+                }
+            }
+            None => self.normal,
+        };
+        match self.normal_map {
+            Some(normal_map) => {
+                let (dpdu, dpdv) = self.get_tangents();
+                let point = TexturePoint {
+                    position: location,
+                    uv: self.get_uv(coords),
+                    duvdx: Uv::zero(),
+                    duvdy: Uv::zero(),
+                };
+                normal_map.apply(&point, normal, dpdu, dpdv)
+            }
+            None => normal,
+        }
+    }
+
+    pub fn get_uv(&self, coords: &BarycentricCoords) -> Uv {
+        match self.uvs {
+            Some(uvs) => coords.interpolate(uvs),
+            None => Uv::new(coords.u, coords.v),
+        }
+    }
+
+    /// Derivatives of position with respect to the texture coordinates
+    pub fn get_tangents(&self) -> (Vector, Vector) {
+        let uvs = self
+            .uvs
+            .unwrap_or([Uv::new(0.0, 0.0), Uv::new(1.0, 0.0), Uv::new(0.0, 1.0)]);
+        let [v0, v1, v2] = self.vertices;
+        let (dp1, dp2) = (v1 - v0, v2 - v0);
+        let (duv1, duv2) = (uvs[1] - uvs[0], uvs[2] - uvs[0]);
+        let determinant = duv1.x * duv2.y - duv1.y * duv2.x;
+        if utils::is_zero(determinant) {
+            // Degenerate UVs, any frame around the normal will do
+            let tangent = dp1.normalize();
+            return (tangent, self.normal.cross(tangent));
+        }
+        let inv_det = 1.0 / determinant;
+        (
+            (dp1 * duv2.y - dp2 * duv1.y) * inv_det,
+            (dp2 * duv1.x - dp1 * duv2.x) * inv_det,
+        )
+    }
+
+    /// Colour from `texture` if there is one, otherwise blended from the vertex colours
+    pub fn get_colour(
+        &self,
+        position: Point,
+        coords: &BarycentricCoords,
+        colours: [ColourFloat; 3],
+        texture: Option<&Texture>,
+        footprint: &Footprint,
+    ) -> ColourFloat {
+        match texture {
+            Some(texture) => {
+                let (dpdu, dpdv) = self.get_tangents();
+                let (duvdx, duvdy) = footprint.uv_derivatives(dpdu, dpdv);
+                texture.eval(&TexturePoint {
+                    position,
+                    uv: self.get_uv(coords),
+                    duvdx,
+                    duvdy,
+                })
+            }
+            None => coords.interpolate(colours),
+        }
     }
 }
 
@@ -341,16 +1162,37 @@ impl BarycentricCoords {
             w: 1.0 - u - v,
         }
     }
+
+    /// Blend per-vertex values, `u` and `v` weighting the second and third vertices
+    pub fn interpolate<V: VectorSpace<Scalar = f32>>(&self, values: [V; 3]) -> V {
+        values[0] * self.w + values[1] * self.u + values[2] * self.v
+    }
 }
 
 pub enum TextureCoords {
     Barycentric(BarycentricCoords),
+    Uv(Uv),
     None,
 }
 
 pub struct IntersectionLocation {
     pub distance: f32,
     pub texture_coords: TextureCoords,
+    /// Which part of a composite object was hit
+    pub primitive: usize,
+    /// Where that part was hit, if it is itself an object
+    pub inner: Option<Box<IntersectionLocation>>,
+}
+
+impl IntersectionLocation {
+    pub fn new(distance: f32, texture_coords: TextureCoords) -> Self {
+        IntersectionLocation {
+            distance,
+            texture_coords,
+            primitive: 0,
+            inner: None,
+        }
+    }
 }
 
 pub struct Intersection<'a> {
@@ -366,6 +1208,15 @@ impl<'a> Intersection<'a> {
 
 pub trait Intersectable {
     fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation>;
+
+    /// Where each of the packet's rays hits, exactly as `intersection` gives for each.
+    /// Tests them one at a time unless there's a faster way.
+    fn packet_intersection(&self, packet: &RayPacket) -> PacketIntersections
+    where
+        Self: Sized,
+    {
+        packet::intersect_each(self, packet)
+    }
 }
 
 impl Intersectable for Object {
@@ -373,84 +1224,126 @@ impl Intersectable for Object {
         match *self {
             Object::Sphere(ref s) => s.intersection(ray),
             Object::Triangle(ref t) => t.intersection(ray),
+            Object::Mesh(ref m) => m.intersection(ray),
+            Object::Plane(ref p) => p.intersection(ray),
+            Object::Disc(ref d) => d.intersection(ray),
+            Object::Cuboid(ref c) => c.intersection(ray),
+            Object::Cylinder(ref c) => c.intersection(ray),
+            Object::Cone(ref c) => c.intersection(ray),
+            Object::Torus(ref t) => t.intersection(ray),
+            Object::Instance(ref i) => i.intersection(ray),
+            Object::Group(ref g) => g.intersection(ray),
+            Object::Csg(ref c) => c.intersection(ray),
+        }
+    }
+
+    fn packet_intersection(&self, packet: &RayPacket) -> PacketIntersections {
+        match *self {
+            Object::Sphere(ref s) => s.packet_intersection(packet),
+            Object::Triangle(ref t) => t.packet_intersection(packet),
+            Object::Mesh(ref m) => m.packet_intersection(packet),
+            Object::Plane(ref p) => p.packet_intersection(packet),
+            Object::Disc(ref d) => d.packet_intersection(packet),
+            Object::Cuboid(ref c) => c.packet_intersection(packet),
+            Object::Cylinder(ref c) => c.packet_intersection(packet),
+            Object::Cone(ref c) => c.packet_intersection(packet),
+            Object::Torus(ref t) => t.packet_intersection(packet),
+            Object::Instance(ref i) => i.packet_intersection(packet),
+            Object::Group(ref g) => g.packet_intersection(packet),
+            Object::Csg(ref c) => c.packet_intersection(packet),
         }
     }
 }
 
 impl Intersectable for Sphere {
     fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
-        let to_centre: Vector = self.centre - ray.start;
-        // Right angle triangle side lengths, with to_centre as the hypotenuse
-        let adjacent: f32 = ray.dir.dot(to_centre);
-        let opposite_squared: f32 = to_centre.dot(to_centre) - (adjacent * adjacent);
-        let radius_squared: f32 = self.radius * self.radius;
-
-        // No intersect if opposite is greater than radius^2
-        if utils::is_greater_than(opposite_squared, radius_squared) {
-            return None;
-        }
-
-        // Length of chord (section of ray that is intersecting)
-        let half_chord: f32 = (radius_squared - opposite_squared).sqrt();
-        // Intersection distances
-        let isect0 = adjacent - half_chord;
-        let isect1 = adjacent + half_chord;
+        let (isect0, isect1) = self.chord(ray)?;
 
         // Dont return intersections behind camera
         if isect0.is_sign_negative() && isect1.is_sign_negative() {
             return None;
         }
 
-        let loc = IntersectionLocation {
-            distance: if isect0 < isect1 { isect0 } else { isect1 },
-            texture_coords: TextureCoords::None,
+        // From inside the sphere the nearest hit is behind the ray
+        let distance = if isect0.is_sign_negative() {
+            isect1
+        } else {
+            isect0
         };
+        let loc = IntersectionLocation::new(distance, TextureCoords::None);
 
         Some(loc)
     }
+
+    fn packet_intersection(&self, packet: &RayPacket) -> PacketIntersections {
+        packet::intersect_sphere(self, packet)
+    }
 }
 
 impl Intersectable for Triangle {
     fn intersection(&self, ray: &Ray) -> Option<IntersectionLocation> {
-        // Moller-Trumbore intersection algorithm
-
-        let v0v1: Vector = self.v1 - self.v0;
-        let v0v2: Vector = self.v2 - self.v0;
+        intersect_triangle(self.v0, self.v1, self.v2, ray)
+    }
 
-        let pvec: Vector = ray.dir.cross(v0v2);
-        let determinant: f32 = v0v1.dot(pvec);
+    fn packet_intersection(&self, packet: &RayPacket) -> PacketIntersections {
+        packet::intersect_triangle(self.v0, self.v1, self.v2, packet, packet.active)
+    }
+}
 
+/// Where `ray` hits the front of the triangle `v0`, `v1`, `v2`, with barycentric texture coords
+pub fn intersect_triangle(
+    v0: Point,
+    v1: Point,
+    v2: Point,
+    ray: &Ray,
+) -> Option<IntersectionLocation> {
+    match intersect_triangle_line(v0, v1, v2, ray)? {
         // cull backfacing triangles
-        if utils::is_negative(determinant) {
-            return None;
-        }
-        // avoid parallel rays
-        if utils::is_zero(determinant) {
-            return None;
-        }
-
-        // compute 'u' barycentric coord
-        let inv_det: f32 = 1.0 / determinant;
-        let tvec: Vector = ray.start - self.v0;
-        let u: f32 = tvec.dot(pvec) * inv_det;
-        if utils::is_negative(u) || utils::is_greater_than(u, 1.0) {
-            return None;
-        }
-
-        // compute 'v' barycentric coord
-        let qvec: Vector = tvec.cross(v0v1);
-        let v: f32 = ray.dir.dot(qvec) * inv_det;
-        if utils::is_negative(v) || utils::is_greater_than(u + v, 1.0) {
-            return None;
-        }
+        (location, true) if utils::is_positive(location.distance) => Some(location),
+        _ => None,
+    }
+}
 
-        let distance: f32 = v0v2.dot(qvec) * inv_det;
+/// Where the line through `ray` crosses the triangle `v0`, `v1`, `v2` from either side,
+/// including behind the ray's start, and whether it hits the front.
+pub fn intersect_triangle_line(
+    v0: Point,
+    v1: Point,
+    v2: Point,
+    ray: &Ray,
+) -> Option<(IntersectionLocation, bool)> {
+    // Moller-Trumbore intersection algorithm
+
+    let v0v1: Vector = v1 - v0;
+    let v0v2: Vector = v2 - v0;
+
+    let pvec: Vector = ray.dir.cross(v0v2);
+    let determinant: f32 = v0v1.dot(pvec);
+
+    // avoid parallel rays
+    if utils::is_zero(determinant) {
+        return None;
+    }
 
-        let loc = IntersectionLocation {
-            distance,
-            texture_coords: TextureCoords::Barycentric(BarycentricCoords::new(u, v)),
-        };
+    // compute 'u' barycentric coord
+    let inv_det: f32 = 1.0 / determinant;
+    let tvec: Vector = ray.start - v0;
+    let u: f32 = tvec.dot(pvec) * inv_det;
+    if utils::is_negative(u) || utils::is_greater_than(u, 1.0) {
+        return None;
+    }
 
-        Some(loc)
+    // compute 'v' barycentric coord
+    let qvec: Vector = tvec.cross(v0v1);
+    let v: f32 = ray.dir.dot(qvec) * inv_det;
+    if utils::is_negative(v) || utils::is_greater_than(u + v, 1.0) {
+        return None;
     }
+
+    let distance: f32 = v0v2.dot(qvec) * inv_det;
+    let location = IntersectionLocation::new(
+        distance,
+        TextureCoords::Barycentric(BarycentricCoords::new(u, v)),
+    );
+    Some((location, determinant > 0.0))
 }
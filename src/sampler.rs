@@ -0,0 +1,490 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::utils::{mix, Rng};
+
+/// Source of the random numbers for a pixel's samples, one dimension at a time.
+///
+/// Every decision a sample makes (where in the pixel, where on the lens, which way to
+/// bounce...) takes the next dimension. Samplers better than independent random numbers
+/// spread each dimension's values evenly over the samples of a pixel, so images converge
+/// in fewer samples. Values depend only on the seed, pixel, sample and dimension, so
+/// images don't depend on the order pixels are rendered in.
+pub trait Sampler {
+    /// Begin sample `index` of pixel (x, y), from the first dimension
+    fn start(&mut self, x: u32, y: u32, index: u32);
+    /// Next dimension, in [0, 1)
+    fn get_1d(&mut self) -> f32;
+    /// Next two dimensions, well spread over the unit square together
+    fn get_2d(&mut self) -> [f32; 2];
+    /// Pass over the next `count` pairs of dimensions, as if drawing them with `get_2d`
+    fn skip_2d(&mut self, count: u32) {
+        for _ in 0..count {
+            self.get_2d();
+        }
+    }
+}
+
+/// A bare generator carries on with its own sequence wherever it's started, for
+/// sampling outside of rendering pixels
+impl Sampler for Rng {
+    fn start(&mut self, _x: u32, _y: u32, _index: u32) {}
+
+    fn get_1d(&mut self) -> f32 {
+        self.f32()
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        [self.f32(), self.f32()]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerKind {
+    Independent,
+    /// Jittered strata, permuted differently for each dimension
+    Stratified,
+    /// Halton sequence, scrambled differently for each pixel
+    Halton,
+    /// Owen-scrambled Sobol sequence, shuffled differently for each pair of dimensions
+    Sobol,
+    /// Blue noise across pixels, each pixel's samples spread by a rank-1 lattice
+    BlueNoise,
+}
+
+impl SamplerKind {
+    /// Sampler for `samples` per pixel. Only the stratified sampler needs to know how
+    /// many there will be, the others are good for any number.
+    pub fn create(self, seed: u64, samples: u32) -> Box<dyn Sampler + Send> {
+        let state = SampleState::new(seed);
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler { state }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler {
+                state,
+                samples: samples.max(1),
+            }),
+            SamplerKind::Halton => Box::new(HaltonSampler { state }),
+            SamplerKind::Sobol => Box::new(SobolSampler { state }),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler { state }),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+            SamplerKind::BlueNoise => "blue-noise",
+        }
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        use SamplerKind::*;
+        [Independent, Stratified, Halton, Sobol, BlueNoise]
+            .iter()
+            .find(|kind| kind.name() == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown sampler {}", name))
+    }
+}
+
+/// What every sampler keeps track of
+struct SampleState {
+    seed: u64,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+    /// Independent random numbers for this sample, for jitter and spare dimensions
+    rng: Rng,
+}
+
+impl SampleState {
+    fn new(seed: u64) -> Self {
+        SampleState {
+            seed,
+            x: 0,
+            y: 0,
+            index: 0,
+            dimension: 0,
+            rng: Rng::for_sample(seed, 0, 0, 0),
+        }
+    }
+
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.x = x;
+        self.y = y;
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Rng::for_sample(self.seed, x, y, index);
+    }
+
+    fn next_dimensions(&mut self, count: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += count;
+        dimension
+    }
+
+    /// Random bits shared by every sample of this pixel in `dimension`
+    fn pixel_hash(&self, dimension: u32) -> u32 {
+        let pixel = ((self.y as u64) << 32) | self.x as u64;
+        mix(self.seed ^ mix(pixel ^ mix(dimension as u64))) as u32
+    }
+
+    /// Random bits shared by every pixel in `dimension`
+    fn dimension_hash(&self, dimension: u32) -> u32 {
+        mix(self.seed ^ mix(dimension as u64 + 1)) as u32
+    }
+}
+
+/// Top 24 bits as a float in [0, 1)
+fn to_unit(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1 << 24) as f32
+}
+
+struct IndependentSampler {
+    state: SampleState,
+}
+
+impl Sampler for IndependentSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.state.rng.f32()
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        [self.state.rng.f32(), self.state.rng.f32()]
+    }
+}
+
+/// Kensler's hashed permutation of 0..length, so the `i`th element can be found
+/// without storing the rest. See "Correlated Multi-Jittered Sampling" (2013).
+fn permute(mut i: u32, length: u32, p: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    i.wrapping_add(p) % length
+}
+
+struct StratifiedSampler {
+    state: SampleState,
+    samples: u32,
+}
+
+impl Sampler for StratifiedSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimensions(1);
+        let p = self.state.pixel_hash(dimension);
+        let stratum = permute(self.state.index % self.samples, self.samples, p);
+        (stratum as f32 + self.state.rng.f32()) / self.samples as f32
+    }
+
+    /// Correlated multi-jittering: stratified in 2D, and in x and y separately
+    fn get_2d(&mut self) -> [f32; 2] {
+        let dimension = self.state.next_dimensions(2);
+        let p = self.state.pixel_hash(dimension);
+        let n = self.samples;
+        let columns = ((n as f32).sqrt() as u32).max(1);
+        let rows = n.div_ceil(columns);
+        let s = permute(self.state.index % n, n, p.wrapping_mul(0x51633e2d));
+        let sx = permute(s % columns, columns, p.wrapping_mul(0xa511e9b3));
+        let sy = permute(s / columns, rows, p.wrapping_mul(0x63d83595));
+        let jx = self.state.rng.f32();
+        let jy = self.state.rng.f32();
+        [
+            ((s % columns) as f32 + (sy as f32 + jx) / rows as f32) / columns as f32,
+            ((s / columns) as f32 + (sx as f32 + jy) / columns as f32) / rows as f32,
+        ]
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Digits of `index` in `base` mirrored about the point, each digit permuted according
+/// to the ones before it (Owen scrambling), so the points are randomised but stay as
+/// evenly spread
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u32) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut result = 0.0;
+    let mut prefix = seed as u64;
+    // Every digit that affects a float is scrambled, even past the last of `index`
+    while scale > 1e-8 {
+        let digit = index % base;
+        index /= base;
+        result += permute(digit, base, mix(prefix) as u32) as f64 * scale;
+        prefix = mix(prefix ^ (digit as u64 + 1));
+        scale *= inverse_base;
+    }
+    (result as f32).min(1.0 - f32::EPSILON)
+}
+
+struct HaltonSampler {
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    fn halton(&mut self, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let seed = self.state.pixel_hash(dimension);
+                scrambled_radical_inverse(base, self.state.index, seed)
+            }
+            // The high dimensions of Halton are poor, and little is gained there anyway
+            None => self.state.rng.f32(),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimensions(1);
+        self.halton(dimension)
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        let dimension = self.state.next_dimensions(2);
+        [self.halton(dimension), self.halton(dimension + 1)]
+    }
+}
+
+/// The first two dimensions of the Sobol sequence, as 32 bit fractions
+fn sobol(index: u32) -> [u32; 2] {
+    let mut y = 0;
+    let mut direction = 1 << 31;
+    let mut bits = index;
+    while bits != 0 {
+        if bits & 1 != 0 {
+            y ^= direction;
+        }
+        bits >>= 1;
+        direction ^= direction >> 1;
+    }
+    [index.reverse_bits(), y]
+}
+
+/// Owen scrambling of a 32 bit fraction, after Burley's "Practical Hash-based Owen
+/// Scrambling" (2020)
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    // Laine and Karras' permutation
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    /// Each pair of dimensions gets its own shuffle of the first two Sobol dimensions,
+    /// which are good together, rather than using poorer higher dimensions
+    fn sobol_2d(&mut self, dimension: u32) -> [f32; 2] {
+        let hash = self.state.pixel_hash(dimension);
+        let index = owen_scramble(self.state.index, hash);
+        let [x, y] = sobol(index);
+        [
+            to_unit(owen_scramble(x, mix(hash as u64 + 1) as u32)),
+            to_unit(owen_scramble(y, mix(hash as u64 + 2) as u32)),
+        ]
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimensions(1);
+        self.sobol_2d(dimension)[0]
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        let dimension = self.state.next_dimensions(2);
+        self.sobol_2d(dimension)
+    }
+
+    fn skip_2d(&mut self, count: u32) {
+        self.state.next_dimensions(2 * count);
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Tile of values in [0, 1) where each is far from others of similar value, made with
+/// Ulichney's void-and-cluster method (1993). Neighbouring pixels given these get very
+/// different values, so the noise left in an image is fine grained and hard to see.
+fn blue_noise() -> &'static [f32] {
+    static TILE: OnceLock<Vec<f32>> = OnceLock::new();
+    TILE.get_or_init(|| {
+        let size = BLUE_NOISE_SIZE;
+        let count = size * size;
+        // Gaussian energy of each pixel in a point, wrapping around the tile
+        let sigma = 1.9_f32;
+        let kernel: Vec<f32> = (0..count)
+            .map(|i| {
+                let wrap = |d: usize| d.min(size - d) as f32;
+                let (dx, dy) = (wrap(i % size), wrap(i / size));
+                (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        let mut set = vec![false; count];
+        let mut energy = vec![0.0_f32; count];
+        let toggle = |set: &mut [bool], energy: &mut [f32], p: usize| {
+            set[p] = !set[p];
+            let sign = if set[p] { 1.0 } else { -1.0 };
+            let (px, py) = (p % size, p / size);
+            for (i, e) in energy.iter_mut().enumerate() {
+                let dx = (i % size + size - px) % size;
+                let dy = (i / size + size - py) % size;
+                *e += sign * kernel[dy * size + dx];
+            }
+        };
+        // Densest point, or emptiest space
+        let extreme = |set: &[bool], energy: &[f32], want_set: bool| -> usize {
+            let candidates = (0..count).filter(|&i| set[i] == want_set);
+            if want_set {
+                candidates
+                    .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+                    .unwrap()
+            } else {
+                candidates
+                    .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+                    .unwrap()
+            }
+        };
+
+        // Start from random points, evened out by moving each tightest cluster into
+        // the largest void until that changes nothing
+        let mut rng = Rng::new(0, 0);
+        let initial = count / 10;
+        while set.iter().filter(|&&s| s).count() < initial {
+            let p = (rng.next_u32() as usize) % count;
+            if !set[p] {
+                toggle(&mut set, &mut energy, p);
+            }
+        }
+        loop {
+            let cluster = extreme(&set, &energy, true);
+            toggle(&mut set, &mut energy, cluster);
+            let void = extreme(&set, &energy, false);
+            toggle(&mut set, &mut energy, void);
+            if void == cluster {
+                break;
+            }
+        }
+
+        // Rank the initial points by removing tightest clusters, then fill the rest of
+        // the tile in, largest void first
+        let mut ranks = vec![0; count];
+        let (initial_set, initial_energy) = (set.clone(), energy.clone());
+        for rank in (0..initial).rev() {
+            let cluster = extreme(&set, &energy, true);
+            toggle(&mut set, &mut energy, cluster);
+            ranks[cluster] = rank;
+        }
+        let (mut set, mut energy) = (initial_set, initial_energy);
+        for rank in initial..count {
+            let void = extreme(&set, &energy, false);
+            toggle(&mut set, &mut energy, void);
+            ranks[void] = rank;
+        }
+        ranks
+            .into_iter()
+            .map(|rank| (rank as f32 + 0.5) / count as f32)
+            .collect()
+    })
+}
+
+// Generalised golden ratios, whose multiples spread evenly over one and two dimensions
+const PHI_1: f32 = 0.618_034;
+const PHI_2: [f32; 2] = [0.754_877_7, 0.569_840_3];
+
+struct BlueNoiseSampler {
+    state: SampleState,
+}
+
+impl BlueNoiseSampler {
+    /// Tile value at this pixel, with the tile shifted differently for each dimension
+    fn tile(&self, dimension: u32) -> f32 {
+        let hash = self.state.dimension_hash(dimension) as usize;
+        let size = BLUE_NOISE_SIZE;
+        let x = (self.state.x as usize + hash) % size;
+        let y = (self.state.y as usize + (hash >> 16)) % size;
+        blue_noise()[y * size + x]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimensions(1);
+        (self.tile(dimension) + self.state.index as f32 * PHI_1).fract()
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        let dimension = self.state.next_dimensions(2);
+        let index = self.state.index as f32;
+        [
+            (self.tile(dimension) + index * PHI_2[0]).fract(),
+            (self.tile(dimension + 1) + index * PHI_2[1]).fract(),
+        ]
+    }
+
+    fn skip_2d(&mut self, count: u32) {
+        self.state.next_dimensions(2 * count);
+    }
+}
@@ -0,0 +1,128 @@
+//! Warps from 2D samples in the unit square to points and directions distributed over
+//! common shapes, each with the probability density it was drawn with.
+//!
+//! Directions' pdfs are per unit solid angle, and points' per unit area. Evenly spread
+//! samples stay evenly spread through the warps, so they suit any `Sampler`.
+//!
+//! The uniform hemisphere, cone and triangle warps have no caller in the renderer yet,
+//! but are kept and tested for light sampling to build on.
+
+use cgmath::prelude::*;
+use std::f32::consts::{FRAC_PI_4, PI};
+
+use crate::primitives::Frame;
+use crate::raytracing::*;
+
+/// Direction spread uniformly over the unit sphere
+pub fn uniform_sphere([u, v]: [f32; 2]) -> (Vector, f32) {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    (
+        Vector::new(r * phi.cos(), r * phi.sin(), z),
+        uniform_sphere_pdf(),
+    )
+}
+
+pub fn uniform_sphere_pdf() -> f32 {
+    1.0 / (4.0 * PI)
+}
+
+/// Direction spread uniformly over the hemisphere around `normal`
+#[allow(dead_code)]
+pub fn uniform_hemisphere(normal: Vector, [u, v]: [f32; 2]) -> (Vector, f32) {
+    let cos_theta = u;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    let dir = around(
+        normal,
+        sin_theta * phi.cos(),
+        cos_theta,
+        sin_theta * phi.sin(),
+    );
+    (dir, uniform_hemisphere_pdf(normal, dir))
+}
+
+#[allow(dead_code)]
+pub fn uniform_hemisphere_pdf(normal: Vector, dir: Vector) -> f32 {
+    if normal.dot(dir) > 0.0 {
+        1.0 / (2.0 * PI)
+    } else {
+        0.0
+    }
+}
+
+/// Direction around `normal` with density proportional to the cosine of its angle to
+/// it, which is how a Lambertian surface scatters light. Points spread over the disc
+/// are lifted up onto the hemisphere (Malley's method).
+pub fn cosine_hemisphere(normal: Vector, sample: [f32; 2]) -> (Vector, f32) {
+    let ([x, z], _) = concentric_disc(sample);
+    let y = (1.0 - x * x - z * z).max(0.0).sqrt();
+    let dir = around(normal, x, y, z);
+    (dir, cosine_hemisphere_pdf(normal, dir))
+}
+
+pub fn cosine_hemisphere_pdf(normal: Vector, dir: Vector) -> f32 {
+    normal.dot(dir).max(0.0) / PI
+}
+
+/// Point in the unit disc, from Shirley and Chiu's concentric mapping, which squashes
+/// the square far less than picking a radius and angle
+pub fn concentric_disc([u, v]: [f32; 2]) -> ([f32; 2], f32) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    let point = if a == 0.0 && b == 0.0 {
+        [0.0, 0.0]
+    } else if a.abs() > b.abs() {
+        let theta = FRAC_PI_4 * (b / a);
+        [a * theta.cos(), a * theta.sin()]
+    } else {
+        let theta = 2.0 * FRAC_PI_4 - FRAC_PI_4 * (a / b);
+        [b * theta.cos(), b * theta.sin()]
+    };
+    (point, 1.0 / PI)
+}
+
+/// Direction spread uniformly over the cone of directions within an angle of `axis`
+/// whose cosine is `cos_max`, e.g. towards a distant sphere
+#[allow(dead_code)]
+pub fn uniform_cone(axis: Vector, cos_max: f32, [u, v]: [f32; 2]) -> (Vector, f32) {
+    let cos_theta = 1.0 - u * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    let dir = around(
+        axis,
+        sin_theta * phi.cos(),
+        cos_theta,
+        sin_theta * phi.sin(),
+    );
+    (dir, uniform_cone_pdf(cos_max))
+}
+
+/// Density of `uniform_cone`'s directions within the cone
+#[allow(dead_code)]
+pub fn uniform_cone_pdf(cos_max: f32) -> f32 {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
+/// Point spread uniformly over the triangle v0, v1, v2, and its barycentric weights of
+/// v1 and v2
+#[allow(dead_code)]
+pub fn uniform_triangle(
+    v0: Point,
+    v1: Point,
+    v2: Point,
+    [u, v]: [f32; 2],
+) -> (Point, [f32; 2], f32) {
+    let root = u.sqrt();
+    let (b1, b2) = (1.0 - root, v * root);
+    let point = v0 + b1 * (v1 - v0) + b2 * (v2 - v0);
+    let area = 0.5 * (v1 - v0).cross(v2 - v0).magnitude();
+    (point, [b1, b2], 1.0 / area)
+}
+
+/// Direction with coordinates `x`, `y` and `z` in a frame whose y axis is `axis`
+fn around(axis: Vector, x: f32, y: f32, z: f32) -> Vector {
+    Frame::new(Point::zero(), axis)
+        .to_world_vector(Vector::new(x, y, z))
+        .normalize()
+}
@@ -0,0 +1,1304 @@
+//! The project's own text scene format, which can be written out from any scene built
+//! in code and edited by hand.
+//!
+//! A file is a list of top-level entries, each a keyword followed by its fields:
+//!
+//! ```text
+//! # Comments run to the end of the line
+//! camera { location 0 0 25 focal_length 1 yaw 0 }
+//! background gradient { top 1 1 1 bottom 0.5 0.7 1 }
+//! light point { position 0 9 0 colour 50 50 50 }
+//! object sphere {
+//!     centre 0 0 -5
+//!     radius 1
+//!     colour 0 0 255
+//!     material lambertian
+//! }
+//! ```
+//!
+//! Fields are always written in the same order and must be read back in that order, with
+//! optional ones such as `texture` left out when unused. Objects shared between instances
+//! are written once per instance, and image textures and environment maps are referred to
+//! by the file they were loaded from. An axis-aligned `cuboid` can be given by its `min`
+//! and `max` corners instead of its centre, half extents and axes. A volume's `grid` can
+//! take a `density` texture in place of its `densities`, which is evaluated at each voxel.
+//!
+//! Scene graph nodes are written as `node "name" { ... }`, with their children inside them.
+//!
+//! A `background sky { ... }` ending in `sun IRRADIANCE` also lights the scene with the
+//! sky's sun. That light is written back out as the directional light it is.
+
+use cgmath::prelude::*;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::background::*;
+use crate::csg::*;
+use crate::instance::{Group, Instance, Transform};
+use crate::medium::*;
+use crate::mesh::*;
+use crate::mipmap::*;
+use crate::primitives::*;
+use crate::raytracing::*;
+use crate::scene_graph::*;
+use crate::sky::Sky;
+use crate::texture::*;
+
+/// Load a scene file, with its camera if it has one
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<(Scene, Option<Camera>)> {
+    read(&std::fs::read_to_string(path)?)
+}
+
+/// Save `scene`, and `camera` if given, so that `load` gives them back
+pub fn save<P: AsRef<Path>>(path: P, scene: &Scene, camera: Option<&Camera>) -> io::Result<()> {
+    std::fs::write(path, write(scene, camera)?)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// Writing
+
+struct Writer {
+    out: String,
+    indent: usize,
+}
+
+fn vector(v: Vector) -> String {
+    format!("{} {} {}", v.x, v.y, v.z)
+}
+
+impl Writer {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, header: &str) {
+        self.line(&format!("{} {{", header));
+        self.indent += 1;
+    }
+
+    fn close(&mut self) {
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn list<T>(&mut self, key: &str, items: &[T], item: impl Fn(&T) -> String) {
+        self.open(key);
+        for i in items {
+            self.line(&item(i));
+        }
+        self.close();
+    }
+
+    fn camera(&mut self, camera: &Camera) {
+        let lens = if camera.aperture > 0.0 {
+            format!(
+                " lens aperture {} focus_distance {}",
+                camera.aperture, camera.focus_distance
+            )
+        } else {
+            String::new()
+        };
+        self.line(&format!(
+            "camera {{ location {} focal_length {} yaw {}{} }}",
+            vector(camera.location),
+            camera.focal_length,
+            camera.yaw.0,
+            lens
+        ));
+    }
+
+    fn background(&mut self, background: &Background) -> io::Result<()> {
+        match *background {
+            Background::Constant(colour) => {
+                self.line(&format!("background constant {}", vector(colour)))
+            }
+            Background::Gradient { top, bottom } => self.line(&format!(
+                "background gradient {{ top {} bottom {} }}",
+                vector(top),
+                vector(bottom)
+            )),
+            Background::Environment(ref map) => {
+                let path = map.path.as_ref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Environment maps can only be saved if they were loaded from a file",
+                    )
+                })?;
+                self.line(&format!(
+                    "background environment {{ path {} rotation {} intensity {} }}",
+                    quote(path)?,
+                    map.rotation.0,
+                    map.intensity
+                ))
+            }
+            Background::Sky(ref sky) => self.line(&format!(
+                "background sky {{ sun_elevation {} sun_azimuth {} turbidity {} intensity {} }}",
+                sky.sun_elevation.0, sky.sun_azimuth.0, sky.turbidity, sky.intensity
+            )),
+        }
+        Ok(())
+    }
+
+    fn light(&mut self, light: &Light) {
+        self.line(&match *light {
+            Light::Directional { direction, colour } => format!(
+                "light directional {{ direction {} colour {} }}",
+                vector(direction),
+                vector(colour)
+            ),
+            Light::Point { position, colour } => format!(
+                "light point {{ position {} colour {} }}",
+                vector(position),
+                vector(colour)
+            ),
+            Light::Spot {
+                position,
+                direction,
+                colour,
+                cos_inner,
+                cos_outer,
+            } => format!(
+                "light spot {{ position {} direction {} colour {} cos_inner {} cos_outer {} }}",
+                vector(position),
+                vector(direction),
+                vector(colour),
+                cos_inner,
+                cos_outer
+            ),
+        });
+    }
+
+    fn medium(&mut self, key: &str, medium: &Medium) {
+        self.open(key);
+        self.line(&format!("absorption {}", medium.absorption));
+        self.line(&format!("scattering {}", medium.scattering));
+        self.line(&format!("g {}", medium.phase.g));
+        if let Density::Grid(ref grid) = medium.density {
+            let [x, y, z] = grid.resolution();
+            self.open("grid");
+            self.line(&format!("min {}", vector(grid.min)));
+            self.line(&format!("max {}", vector(grid.max)));
+            self.line(&format!("resolution {} {} {}", x, y, z));
+            self.list(
+                "densities",
+                &grid.densities().chunks(x).collect::<Vec<_>>(),
+                |row| {
+                    row.iter()
+                        .map(|d| d.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                },
+            );
+            self.close();
+        }
+        self.close();
+    }
+
+    fn space(&mut self, space: TextureSpace) {
+        self.line(match space {
+            TextureSpace::World => "space world",
+            TextureSpace::Uv => "space uv",
+        });
+    }
+
+    fn texture(&mut self, key: &str, texture: &Texture) -> io::Result<()> {
+        use Texture::*;
+        match *texture {
+            Solid(colour) => {
+                self.line(&format!("{} solid {}", key, vector(colour)));
+                return Ok(());
+            }
+            Image(ref image) => {
+                let path = image.path.as_ref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Image textures can only be saved if they were loaded from a file",
+                    )
+                })?;
+                let filter = match image.filter {
+                    TextureFilter::Nearest => "nearest".to_string(),
+                    TextureFilter::Bilinear => "bilinear".to_string(),
+                    TextureFilter::Trilinear => "trilinear".to_string(),
+                    TextureFilter::Anisotropic(ratio) => format!("anisotropic {}", ratio),
+                };
+                self.line(&format!(
+                    "{} image {{ path {} filter {} }}",
+                    key,
+                    quote(path)?,
+                    filter
+                ));
+                return Ok(());
+            }
+            Checker {
+                ref even,
+                ref odd,
+                scale,
+                space,
+            } => {
+                self.open(&format!("{} checker", key));
+                self.texture("even", even)?;
+                self.texture("odd", odd)?;
+                self.line(&format!("scale {}", scale));
+                self.space(space);
+            }
+            Noise { scale, space } => {
+                self.open(&format!("{} noise", key));
+                self.line(&format!("scale {}", scale));
+                self.space(space);
+            }
+            Turbulence {
+                scale,
+                octaves,
+                space,
+            } => {
+                self.open(&format!("{} turbulence", key));
+                self.line(&format!("scale {}", scale));
+                self.line(&format!("octaves {}", octaves));
+                self.space(space);
+            }
+            Marble {
+                scale,
+                turbulence,
+                octaves,
+                space,
+            } => {
+                self.open(&format!("{} marble", key));
+                self.line(&format!("scale {}", scale));
+                self.line(&format!("turbulence {}", turbulence));
+                self.line(&format!("octaves {}", octaves));
+                self.space(space);
+            }
+            Wood {
+                scale,
+                turbulence,
+                space,
+            } => {
+                self.open(&format!("{} wood", key));
+                self.line(&format!("scale {}", scale));
+                self.line(&format!("turbulence {}", turbulence));
+                self.space(space);
+            }
+            Voronoi { scale, space } => {
+                self.open(&format!("{} voronoi", key));
+                self.line(&format!("scale {}", scale));
+                self.space(space);
+            }
+            Gradient {
+                origin,
+                direction,
+                space,
+            } => {
+                self.open(&format!("{} gradient", key));
+                self.line(&format!("origin {}", vector(origin)));
+                self.line(&format!("direction {}", vector(direction)));
+                self.space(space);
+            }
+            Ramp {
+                ref input,
+                ref stops,
+            } => {
+                self.open(&format!("{} ramp", key));
+                self.texture("input", input)?;
+                self.list("stops", stops, |(position, colour)| {
+                    format!("{} {}", position, vector(*colour))
+                });
+            }
+            Mix {
+                ref a,
+                ref b,
+                ref factor,
+            } => {
+                self.open(&format!("{} mix", key));
+                self.texture("a", a)?;
+                self.texture("b", b)?;
+                self.texture("factor", factor)?;
+            }
+            Multiply(ref a, ref b) => {
+                self.open(&format!("{} multiply", key));
+                self.texture("a", a)?;
+                self.texture("b", b)?;
+            }
+            Remap {
+                ref input,
+                from,
+                to,
+            } => {
+                self.open(&format!("{} remap", key));
+                self.texture("input", input)?;
+                self.line(&format!("from {} {}", from.0, from.1));
+                self.line(&format!("to {} {}", to.0, to.1));
+            }
+        }
+        // Everything but solid colours and images opened a block
+        self.close();
+        Ok(())
+    }
+
+    fn surface(
+        &mut self,
+        colour: ColourFloat,
+        material: &Material,
+        texture: &Option<Texture>,
+    ) -> io::Result<()> {
+        self.line(&format!("colour {}", vector(colour)));
+        self.line(&format!("material {}", material_string(material)));
+        if let Some(ref texture) = *texture {
+            self.texture("texture", texture)?;
+        }
+        Ok(())
+    }
+
+    fn normal_map(&mut self, normal_map: &Option<NormalMap>) -> io::Result<()> {
+        match *normal_map {
+            Some(NormalMap::TangentSpace(ref texture)) => {
+                self.open("normal_map tangent_space");
+                self.texture("texture", texture)?;
+                self.close();
+            }
+            Some(NormalMap::Bump {
+                ref height,
+                strength,
+            }) => {
+                self.open("normal_map bump");
+                self.texture("height", height)?;
+                self.line(&format!("strength {}", strength));
+                self.close();
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn object(&mut self, key: &str, object: &Object) -> io::Result<()> {
+        match *object {
+            Object::Triangle(ref t) => {
+                self.open(&format!("{} triangle", key));
+                self.list("vertices", &[t.v0, t.v1, t.v2], |v| vector(*v));
+                self.list("colours", &t.colours, |c| vector(*c));
+                self.line(&format!("material {}", material_string(&t.material)));
+                if let Some(ref normals) = t.normals {
+                    self.list("normals", normals, |n| vector(*n));
+                }
+                if let Some(ref uvs) = t.uvs {
+                    self.list("uvs", uvs, |uv| format!("{} {}", uv.x, uv.y));
+                }
+                if let Some(ref texture) = t.texture {
+                    self.texture("texture", texture)?;
+                }
+                self.normal_map(&t.normal_map)?;
+            }
+            Object::Mesh(ref mesh) => {
+                self.open(&format!("{} mesh", key));
+                self.list("positions", mesh.positions(), |p| vector(*p));
+                self.list("faces", mesh.faces(), |f| {
+                    let [a, b, c] = f.indices;
+                    format!("{} {} {} {}", a, b, c, f.material)
+                });
+                self.line(&format!("colour {}", vector(mesh.colour)));
+                self.list("materials", &mesh.materials, material_string);
+                if let Some(ref normals) = mesh.normals {
+                    self.list("normals", normals, |n| vector(*n));
+                }
+                if let Some(ref uvs) = mesh.uvs {
+                    self.list("uvs", uvs, |uv| format!("{} {}", uv.x, uv.y));
+                }
+                if let Some(ref colours) = mesh.colours {
+                    self.list("colours", colours, |c| vector(*c));
+                }
+                if let Some(ref texture) = mesh.texture {
+                    self.texture("texture", texture)?;
+                }
+                self.normal_map(&mesh.normal_map)?;
+            }
+            Object::Sphere(ref s) => {
+                self.open(&format!("{} sphere", key));
+                self.line(&format!("centre {}", vector(s.centre)));
+                self.line(&format!("radius {}", s.radius));
+                self.surface(s.colour, &s.material, &s.texture)?;
+            }
+            Object::Plane(ref p) => {
+                self.open(&format!("{} plane", key));
+                self.line(&format!("point {}", vector(p.frame().origin)));
+                self.line(&format!("normal {}", vector(p.frame().y)));
+                self.surface(p.colour, &p.material, &p.texture)?;
+            }
+            Object::Disc(ref d) => {
+                self.open(&format!("{} disc", key));
+                self.line(&format!("centre {}", vector(d.frame().origin)));
+                self.line(&format!("normal {}", vector(d.frame().y)));
+                self.line(&format!("radius {}", d.radius));
+                self.surface(d.colour, &d.material, &d.texture)?;
+            }
+            Object::Cuboid(ref c) => {
+                let frame = c.frame();
+                self.open(&format!("{} cuboid", key));
+                self.line(&format!("centre {}", vector(frame.origin)));
+                self.line(&format!("half_extents {}", vector(c.half_extents)));
+                self.list("axes", &[frame.x, frame.y, frame.z], |a| vector(*a));
+                self.surface(c.colour, &c.material, &c.texture)?;
+            }
+            Object::Cylinder(ref c) => {
+                self.open(&format!("{} cylinder", key));
+                self.round(c.frame(), c.radius, c.height, c.capped);
+                self.surface(c.colour, &c.material, &c.texture)?;
+            }
+            Object::Cone(ref c) => {
+                self.open(&format!("{} cone", key));
+                self.round(c.frame(), c.radius, c.height, c.capped);
+                self.surface(c.colour, &c.material, &c.texture)?;
+            }
+            Object::Torus(ref t) => {
+                self.open(&format!("{} torus", key));
+                self.line(&format!("centre {}", vector(t.frame().origin)));
+                self.line(&format!("axis {}", vector(t.frame().y)));
+                self.line(&format!("major_radius {}", t.major_radius));
+                self.line(&format!("minor_radius {}", t.minor_radius));
+                self.surface(t.colour, &t.material, &t.texture)?;
+            }
+            Object::Instance(ref instance) => {
+                self.open(&format!("{} instance", key));
+                self.transform(instance.get_transform());
+                self.object("object", &instance.object)?;
+            }
+            Object::Group(ref group) => {
+                self.open(&format!("{} group", key));
+                for object in group.objects.iter() {
+                    self.object("object", object)?;
+                }
+            }
+            Object::Csg(ref csg) => {
+                self.open(&format!("{} csg", key));
+                self.line(match csg.operation {
+                    CsgOperation::Union => "operation union",
+                    CsgOperation::Intersection => "operation intersection",
+                    CsgOperation::Difference => "operation difference",
+                });
+                self.object("left", &csg.left)?;
+                self.object("right", &csg.right)?;
+            }
+        }
+        self.close();
+        Ok(())
+    }
+
+    /// Written as its four columns
+    fn transform(&mut self, transform: &Transform) {
+        let columns: &[[f32; 4]; 4] = transform.as_ref();
+        self.list("transform", columns, |c| {
+            format!("{} {} {} {}", c[0], c[1], c[2], c[3])
+        });
+    }
+
+    fn node(&mut self, node: &Node) -> io::Result<()> {
+        self.open(&format!("node {}", quote(&node.name)?));
+        self.transform(&node.transform);
+        self.line(&format!("visible {}", node.visible));
+        if let Some(ref object) = node.object {
+            self.object("object", object)?;
+        }
+        for child in node.children.iter() {
+            self.node(child)?;
+        }
+        self.close();
+        Ok(())
+    }
+
+    /// Shared fields of cylinders and cones
+    fn round(&mut self, frame: &Frame, radius: f32, height: f32, capped: bool) {
+        self.line(&format!("base {}", vector(frame.origin)));
+        self.line(&format!("axis {}", vector(frame.y)));
+        self.line(&format!("radius {}", radius));
+        self.line(&format!("height {}", height));
+        self.line(&format!("capped {}", capped));
+    }
+}
+
+fn material_string(material: &Material) -> String {
+    match *material {
+        Material::Specular => "specular".to_string(),
+        Material::Diffuse => "diffuse".to_string(),
+        Material::Lambertian => "lambertian".to_string(),
+        Material::Subsurface {
+            mean_free_path,
+            albedo,
+        } => format!(
+            "subsurface {{ mean_free_path {} albedo {} }}",
+            mean_free_path,
+            vector(albedo)
+        ),
+    }
+}
+
+fn quote(text: &str) -> io::Result<String> {
+    if text.contains('"') || text.contains('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't save the name {}", text),
+        ));
+    }
+    Ok(format!("\"{}\"", text))
+}
+
+/// The text of a scene file for `scene`. Fails if it uses images that weren't loaded
+/// from files, as there is nothing to refer to them by.
+pub fn write(scene: &Scene, camera: Option<&Camera>) -> io::Result<String> {
+    let mut writer = Writer {
+        out: String::new(),
+        indent: 0,
+    };
+    if let Some(camera) = camera {
+        writer.camera(camera);
+    }
+    writer.background(&scene.background)?;
+    for light in scene.lights.iter() {
+        writer.light(light);
+    }
+    if let Some(ref fog) = scene.fog {
+        writer.medium("fog", fog);
+    }
+    for volume in scene.volumes.iter() {
+        writer.open("volume");
+        writer.object("boundary", &volume.boundary)?;
+        writer.medium("medium", &volume.medium);
+        writer.close();
+    }
+    for object in scene.objects.iter() {
+        writer.object("object", object)?;
+    }
+    for node in scene.graph.nodes() {
+        writer.node(node)?;
+    }
+    Ok(writer.out)
+}
+
+// Reading
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+fn tokenise(text: &str) -> io::Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') {
+                break;
+            }
+            let end = if let Some(quoted) = rest.strip_prefix('"') {
+                // Quoted strings keep their quotes, which `Parser::string` removes
+                quoted
+                    .find('"')
+                    .map(|end| end + 2)
+                    .ok_or_else(|| invalid(format!("line {}: unterminated string", line_number)))?
+            } else if rest.starts_with('{') || rest.starts_with('}') {
+                1
+            } else {
+                rest.find(|c: char| c.is_whitespace() || c == '{' || c == '}' || c == '#')
+                    .unwrap_or(rest.len())
+            };
+            tokens.push(Token {
+                text: &rest[..end],
+                line: line_number,
+            });
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> io::Error {
+        match self.tokens.get(self.position) {
+            Some(token) => invalid(format!(
+                "line {}: {}, found '{}'",
+                token.line, message, token.text
+            )),
+            None => invalid(format!("{} at the end of the file", message)),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|t| t.text)
+    }
+
+    fn next(&mut self) -> io::Result<&'a str> {
+        let text = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        self.position += 1;
+        Ok(text)
+    }
+
+    fn expect(&mut self, expected: &str) -> io::Result<()> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Skip past `key` if it comes next, for optional fields
+    fn optional(&mut self, key: &str) -> bool {
+        let present = self.peek() == Some(key);
+        if present {
+            self.position += 1;
+        }
+        present
+    }
+
+    fn parse<T: std::str::FromStr>(&mut self, what: &str) -> io::Result<T> {
+        let value = self
+            .peek()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| self.error(&format!("expected {}", what)))?;
+        self.position += 1;
+        Ok(value)
+    }
+
+    fn number(&mut self) -> io::Result<f32> {
+        self.parse("a number")
+    }
+
+    fn vector(&mut self) -> io::Result<Vector> {
+        Ok(Vector::new(self.number()?, self.number()?, self.number()?))
+    }
+
+    fn uv(&mut self) -> io::Result<Uv> {
+        Ok(Uv::new(self.number()?, self.number()?))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        match self.peek() {
+            Some(text) if text.len() >= 2 && text.starts_with('"') => {
+                self.position += 1;
+                Ok(text[1..text.len() - 1].to_string())
+            }
+            _ => Err(self.error("expected a quoted string")),
+        }
+    }
+
+    /// `key` followed by a value
+    fn field<T>(
+        &mut self,
+        key: &str,
+        value: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.expect(key)?;
+        value(self)
+    }
+
+    /// `key`, then items up to the closing brace
+    fn list<T>(
+        &mut self,
+        key: &str,
+        mut item: impl FnMut(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Vec<T>> {
+        self.expect(key)?;
+        self.expect("{")?;
+        let mut items = Vec::new();
+        while self.peek() != Some("}") {
+            items.push(item(self)?);
+        }
+        self.position += 1;
+        Ok(items)
+    }
+
+    /// A list of exactly three items
+    fn three<T: Copy>(
+        &mut self,
+        key: &str,
+        item: impl FnMut(&mut Self) -> io::Result<T>,
+    ) -> io::Result<[T; 3]> {
+        match self.list(key, item)?[..] {
+            [a, b, c] => Ok([a, b, c]),
+            _ => Err(self.error(&format!("expected three {}", key))),
+        }
+    }
+
+    /// A list with one item per mesh vertex
+    fn per_vertex<T>(
+        &mut self,
+        key: &str,
+        vertices: usize,
+        item: impl FnMut(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Vec<T>> {
+        let items = self.list(key, item)?;
+        if items.len() != vertices {
+            return Err(self.error(&format!("expected {} {}", vertices, key)));
+        }
+        Ok(items)
+    }
+
+    fn camera(&mut self) -> io::Result<Camera> {
+        self.expect("{")?;
+        let location = self.field("location", Self::vector)?;
+        let focal_length = self.field("focal_length", Self::number)?;
+        let yaw = self.field("yaw", Self::number)?;
+        let mut camera = Camera::posed(location, focal_length, cgmath::Deg(yaw));
+        if self.optional("lens") {
+            camera.aperture = self.field("aperture", Self::number)?;
+            camera.focus_distance = self.field("focus_distance", Self::number)?;
+        }
+        self.expect("}")?;
+        Ok(camera)
+    }
+
+    /// Sets the scene's background, adding the sun's light for a sky given `sun`
+    fn background(&mut self, scene: &mut Scene) -> io::Result<()> {
+        let kind = self.next()?;
+        if kind == "constant" {
+            scene.background = Background::Constant(self.vector()?);
+            return Ok(());
+        }
+        self.expect("{")?;
+        let background = match kind {
+            "gradient" => Background::Gradient {
+                top: self.field("top", Self::vector)?,
+                bottom: self.field("bottom", Self::vector)?,
+            },
+            "environment" => {
+                let path = self.field("path", Self::string)?;
+                let rotation = cgmath::Deg(self.field("rotation", Self::number)?);
+                let intensity = self.field("intensity", Self::number)?;
+                Background::Environment(
+                    EnvironmentMap::load(&path, rotation, intensity)
+                        .map_err(|e| invalid(format!("Problem loading {}: {}", path, e)))?,
+                )
+            }
+            "sky" => {
+                let sky = Sky::new(
+                    cgmath::Deg(self.field("sun_elevation", Self::number)?),
+                    cgmath::Deg(self.field("sun_azimuth", Self::number)?),
+                    self.field("turbidity", Self::number)?,
+                    self.field("intensity", Self::number)?,
+                );
+                if self.optional("sun") {
+                    let irradiance = self.number()?;
+                    self.expect("}")?;
+                    scene.set_sky(sky, irradiance);
+                    return Ok(());
+                }
+                Background::Sky(sky)
+            }
+            _ => {
+                self.position -= 2;
+                return Err(self.error("unknown background"));
+            }
+        };
+        self.expect("}")?;
+        scene.background = background;
+        Ok(())
+    }
+
+    fn light(&mut self) -> io::Result<Light> {
+        let kind = self.next()?;
+        self.expect("{")?;
+        let light = match kind {
+            "directional" => Light::Directional {
+                direction: self.field("direction", Self::vector)?,
+                colour: self.field("colour", Self::vector)?,
+            },
+            "point" => Light::Point {
+                position: self.field("position", Self::vector)?,
+                colour: self.field("colour", Self::vector)?,
+            },
+            "spot" => Light::Spot {
+                position: self.field("position", Self::vector)?,
+                direction: self.field("direction", Self::vector)?,
+                colour: self.field("colour", Self::vector)?,
+                cos_inner: self.field("cos_inner", Self::number)?,
+                cos_outer: self.field("cos_outer", Self::number)?,
+            },
+            _ => {
+                self.position -= 2;
+                return Err(self.error("unknown light"));
+            }
+        };
+        self.expect("}")?;
+        Ok(light)
+    }
+
+    fn medium(&mut self) -> io::Result<Medium> {
+        self.expect("{")?;
+        let mut medium = Medium::homogeneous(
+            self.field("absorption", Self::number)?,
+            self.field("scattering", Self::number)?,
+            self.field("g", Self::number)?,
+        );
+        if self.optional("grid") {
+            self.expect("{")?;
+            let min = self.field("min", Self::vector)?;
+            let max = self.field("max", Self::vector)?;
+            self.expect("resolution")?;
+            let resolution = [
+                self.parse("a resolution")?,
+                self.parse("a resolution")?,
+                self.parse("a resolution")?,
+            ];
+            if resolution.contains(&0) {
+                self.position -= 1;
+                return Err(self.error("grid resolutions must be at least 1"));
+            }
+            let grid = if self.optional("density") {
+                // Filled from a texture's red channel at the centre of each voxel
+                let texture = self.texture()?;
+                VoxelGrid::from_fn(min, max, resolution, |position| {
+                    texture
+                        .eval(&TexturePoint {
+                            position,
+                            uv: Uv::zero(),
+                            duvdx: Uv::zero(),
+                            duvdy: Uv::zero(),
+                        })
+                        .x
+                })
+            } else {
+                let densities = self.list("densities", Self::number)?;
+                if densities.len() != resolution.iter().product::<usize>() {
+                    return Err(self.error("expected one density per voxel"));
+                }
+                VoxelGrid::new(min, max, resolution, densities)
+            };
+            self.expect("}")?;
+            medium.density = Density::Grid(grid);
+        }
+        self.expect("}")?;
+        Ok(medium)
+    }
+
+    fn material(&mut self) -> io::Result<Material> {
+        Ok(match self.next()? {
+            "specular" => Material::Specular,
+            "diffuse" => Material::Diffuse,
+            "lambertian" => Material::Lambertian,
+            "subsurface" => {
+                self.expect("{")?;
+                let material = Material::Subsurface {
+                    mean_free_path: self.field("mean_free_path", Self::number)?,
+                    albedo: self.field("albedo", Self::vector)?,
+                };
+                self.expect("}")?;
+                material
+            }
+            _ => {
+                self.position -= 1;
+                return Err(self.error("unknown material"));
+            }
+        })
+    }
+
+    fn texture(&mut self) -> io::Result<Texture> {
+        let kind = self.next()?;
+        if kind == "solid" {
+            return Ok(Texture::Solid(self.vector()?));
+        }
+        self.expect("{")?;
+        let boxed = |parser: &mut Self| parser.texture().map(Box::new);
+        let texture = match kind {
+            "image" => {
+                let path = self.field("path", Self::string)?;
+                let filter = match self.field("filter", Self::next)? {
+                    "nearest" => TextureFilter::Nearest,
+                    "bilinear" => TextureFilter::Bilinear,
+                    "trilinear" => TextureFilter::Trilinear,
+                    "anisotropic" => TextureFilter::Anisotropic(self.parse("a ratio")?),
+                    _ => {
+                        self.position -= 1;
+                        return Err(self.error("unknown texture filter"));
+                    }
+                };
+                Texture::Image(Arc::new(
+                    ImageTexture::load(&path, filter)
+                        .map_err(|e| invalid(format!("Problem loading {}: {}", path, e)))?,
+                ))
+            }
+            "checker" => Texture::Checker {
+                even: self.field("even", boxed)?,
+                odd: self.field("odd", boxed)?,
+                scale: self.field("scale", Self::number)?,
+                space: self.field("space", Self::space)?,
+            },
+            "noise" => Texture::Noise {
+                scale: self.field("scale", Self::number)?,
+                space: self.optional_space()?,
+            },
+            "turbulence" => Texture::Turbulence {
+                scale: self.field("scale", Self::number)?,
+                octaves: self.field("octaves", |p| p.parse("a number of octaves"))?,
+                space: self.optional_space()?,
+            },
+            "marble" => Texture::Marble {
+                scale: self.field("scale", Self::number)?,
+                turbulence: self.field("turbulence", Self::number)?,
+                octaves: self.field("octaves", |p| p.parse("a number of octaves"))?,
+                space: self.optional_space()?,
+            },
+            "wood" => Texture::Wood {
+                scale: self.field("scale", Self::number)?,
+                turbulence: self.field("turbulence", Self::number)?,
+                space: self.optional_space()?,
+            },
+            "voronoi" => Texture::Voronoi {
+                scale: self.field("scale", Self::number)?,
+                space: self.optional_space()?,
+            },
+            "gradient" => Texture::Gradient {
+                origin: self.field("origin", Self::vector)?,
+                direction: self.field("direction", Self::vector)?,
+                space: self.optional_space()?,
+            },
+            "ramp" => Texture::Ramp {
+                input: self.field("input", boxed)?,
+                stops: self.list("stops", |p| Ok((p.number()?, p.vector()?)))?,
+            },
+            "mix" => Texture::Mix {
+                a: self.field("a", boxed)?,
+                b: self.field("b", boxed)?,
+                factor: self.field("factor", boxed)?,
+            },
+            "multiply" => Texture::Multiply(self.field("a", boxed)?, self.field("b", boxed)?),
+            "remap" => {
+                let input = self.field("input", boxed)?;
+                let from = self.field("from", |p| Ok((p.number()?, p.number()?)))?;
+                if from.0 == from.1 {
+                    self.position -= 1;
+                    return Err(self.error("remap ranges can't be empty"));
+                }
+                Texture::Remap {
+                    input,
+                    from,
+                    to: self.field("to", |p| Ok((p.number()?, p.number()?)))?,
+                }
+            }
+            _ => {
+                self.position -= 2;
+                return Err(self.error("unknown texture"));
+            }
+        };
+        self.expect("}")?;
+        Ok(texture)
+    }
+
+    fn normal_map(&mut self) -> io::Result<Option<NormalMap>> {
+        if !self.optional("normal_map") {
+            return Ok(None);
+        }
+        let kind = self.next()?;
+        self.expect("{")?;
+        let normal_map = match kind {
+            "tangent_space" => NormalMap::TangentSpace(self.field("texture", Self::texture)?),
+            "bump" => NormalMap::Bump {
+                height: self.field("height", Self::texture)?,
+                strength: self.field("strength", Self::number)?,
+            },
+            _ => {
+                self.position -= 2;
+                return Err(self.error("unknown normal map"));
+            }
+        };
+        self.expect("}")?;
+        Ok(Some(normal_map))
+    }
+
+    fn space(&mut self) -> io::Result<TextureSpace> {
+        match self.next()? {
+            "world" => Ok(TextureSpace::World),
+            "uv" => Ok(TextureSpace::Uv),
+            _ => {
+                self.position -= 1;
+                Err(self.error("unknown texture space"))
+            }
+        }
+    }
+
+    /// Space a pattern is evaluated in, world space unless given
+    fn optional_space(&mut self) -> io::Result<TextureSpace> {
+        if self.optional("space") {
+            self.space()
+        } else {
+            Ok(TextureSpace::World)
+        }
+    }
+
+    fn optional_texture(&mut self) -> io::Result<Option<Texture>> {
+        if self.optional("texture") {
+            Ok(Some(self.texture()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Colour, material and texture, shared by the analytic primitives
+    fn surface(&mut self) -> io::Result<(ColourFloat, Material, Option<Texture>)> {
+        Ok((
+            self.field("colour", Self::vector)?,
+            self.field("material", Self::material)?,
+            self.optional_texture()?,
+        ))
+    }
+
+    /// Shared fields of cylinders and cones
+    fn round(&mut self) -> io::Result<(Point, Vector, f32, f32, bool)> {
+        Ok((
+            self.field("base", Self::vector)?,
+            self.field("axis", Self::vector)?,
+            self.field("radius", Self::number)?,
+            self.field("height", Self::number)?,
+            self.field("capped", |p| p.parse("true or false"))?,
+        ))
+    }
+
+    fn transform(&mut self) -> io::Result<Transform> {
+        let columns = self.list("transform", |p| {
+            Ok(cgmath::Vector4::new(
+                p.number()?,
+                p.number()?,
+                p.number()?,
+                p.number()?,
+            ))
+        })?;
+        let transform = match columns[..] {
+            [x, y, z, w] => Transform::from_cols(x, y, z, w),
+            _ => return Err(self.error("expected four transform columns")),
+        };
+        if transform.invert().is_none() {
+            return Err(self.error("transforms must be invertible"));
+        }
+        Ok(transform)
+    }
+
+    fn node(&mut self) -> io::Result<Node> {
+        let mut node = Node::new(&self.string()?);
+        self.expect("{")?;
+        node.transform = self.transform()?;
+        node.visible = self.field("visible", |p| p.parse("true or false"))?;
+        if self.optional("object") {
+            node.object = Some(Arc::new(self.object()?));
+        }
+        while self.optional("node") {
+            node.children.push(self.node()?);
+        }
+        self.expect("}")?;
+        Ok(node)
+    }
+
+    fn object(&mut self) -> io::Result<Object> {
+        let kind = self.next()?;
+        self.expect("{")?;
+        let object = match kind {
+            "triangle" => {
+                let [v0, v1, v2] = self.three("vertices", Self::vector)?;
+                let colours = self.three("colours", Self::vector)?;
+                let material = self.field("material", Self::material)?;
+                let mut triangle = Triangle::new(v0, v1, v2, colours, material);
+                if self.peek() == Some("normals") {
+                    triangle.normals = Some(self.three("normals", Self::vector)?);
+                }
+                if self.peek() == Some("uvs") {
+                    triangle.uvs = Some(self.three("uvs", Self::uv)?);
+                }
+                triangle.texture = self.optional_texture()?;
+                triangle.normal_map = self.normal_map()?;
+                Object::Triangle(triangle)
+            }
+            "mesh" => {
+                let positions = self.list("positions", Self::vector)?;
+                let faces = self.list("faces", |p| {
+                    Ok(Face {
+                        indices: [
+                            p.parse("a vertex index")?,
+                            p.parse("a vertex index")?,
+                            p.parse("a vertex index")?,
+                        ],
+                        material: p.parse("a material index")?,
+                    })
+                })?;
+                let colour = self.field("colour", Self::vector)?;
+                let materials = self.list("materials", Self::material)?;
+                if faces.iter().any(|face| {
+                    face.material >= materials.len()
+                        || face.indices.iter().any(|&i| i >= positions.len())
+                }) {
+                    return Err(self.error("mesh face refers to a missing vertex or material"));
+                }
+                let vertices = positions.len();
+                let mut mesh = Mesh::new(positions, faces, colour, materials);
+                if self.peek() == Some("normals") {
+                    mesh.normals = Some(self.per_vertex("normals", vertices, Self::vector)?);
+                }
+                if self.peek() == Some("uvs") {
+                    mesh.uvs = Some(self.per_vertex("uvs", vertices, Self::uv)?);
+                }
+                if self.peek() == Some("colours") {
+                    mesh.colours = Some(self.per_vertex("colours", vertices, Self::vector)?);
+                }
+                mesh.texture = self.optional_texture()?;
+                mesh.normal_map = self.normal_map()?;
+                Object::Mesh(mesh)
+            }
+            "sphere" => {
+                let centre = self.field("centre", Self::vector)?;
+                let radius = self.field("radius", Self::number)?;
+                let (colour, material, texture) = self.surface()?;
+                Object::Sphere(Sphere {
+                    centre,
+                    radius,
+                    colour,
+                    texture,
+                    material,
+                })
+            }
+            "plane" => {
+                let point = self.field("point", Self::vector)?;
+                let normal = self.field("normal", Self::vector)?;
+                let (colour, material, texture) = self.surface()?;
+                let mut plane = Plane::new(point, normal, colour, material);
+                plane.texture = texture;
+                Object::Plane(plane)
+            }
+            "disc" => {
+                let centre = self.field("centre", Self::vector)?;
+                let normal = self.field("normal", Self::vector)?;
+                let radius = self.field("radius", Self::number)?;
+                let (colour, material, texture) = self.surface()?;
+                let mut disc = Disc::new(centre, normal, radius, colour, material);
+                disc.texture = texture;
+                Object::Disc(disc)
+            }
+            "cuboid" => {
+                // Axis-aligned boxes can be given by their corners instead
+                let (mut cuboid, texture) = if self.peek() == Some("min") {
+                    let min = self.field("min", Self::vector)?;
+                    let max = self.field("max", Self::vector)?;
+                    let (colour, material, texture) = self.surface()?;
+                    (Cuboid::axis_aligned(min, max, colour, material), texture)
+                } else {
+                    let centre = self.field("centre", Self::vector)?;
+                    let half_extents = self.field("half_extents", Self::vector)?;
+                    let [x, y, z] = self.three("axes", Self::vector)?;
+                    let (colour, material, texture) = self.surface()?;
+                    let axes = cgmath::Matrix3::from_cols(x, y, z);
+                    let cuboid = Cuboid::oriented(centre, half_extents, axes, colour, material);
+                    (cuboid, texture)
+                };
+                cuboid.texture = texture;
+                Object::Cuboid(cuboid)
+            }
+            "cylinder" => {
+                let (base, axis, radius, height, capped) = self.round()?;
+                let (colour, material, texture) = self.surface()?;
+                let mut cylinder = Cylinder::new(base, axis, radius, height, colour, material);
+                cylinder.capped = capped;
+                cylinder.texture = texture;
+                Object::Cylinder(cylinder)
+            }
+            "cone" => {
+                let (base, axis, radius, height, capped) = self.round()?;
+                let (colour, material, texture) = self.surface()?;
+                let mut cone = Cone::new(base, axis, radius, height, colour, material);
+                cone.capped = capped;
+                cone.texture = texture;
+                Object::Cone(cone)
+            }
+            "torus" => {
+                let centre = self.field("centre", Self::vector)?;
+                let axis = self.field("axis", Self::vector)?;
+                let major_radius = self.field("major_radius", Self::number)?;
+                let minor_radius = self.field("minor_radius", Self::number)?;
+                let (colour, material, texture) = self.surface()?;
+                let mut torus =
+                    Torus::new(centre, axis, major_radius, minor_radius, colour, material);
+                torus.texture = texture;
+                Object::Torus(torus)
+            }
+            "instance" => {
+                let transform = self.transform()?;
+                let object = self.field("object", Self::object)?;
+                Object::Instance(Instance::new(Arc::new(object), transform))
+            }
+            "group" => {
+                let mut objects = Vec::new();
+                while self.optional("object") {
+                    objects.push(self.object()?);
+                }
+                Object::Group(Group::new(objects))
+            }
+            "csg" => {
+                let operation = match self.field("operation", Self::next)? {
+                    "union" => CsgOperation::Union,
+                    "intersection" => CsgOperation::Intersection,
+                    "difference" => CsgOperation::Difference,
+                    _ => {
+                        self.position -= 1;
+                        return Err(self.error("unknown CSG operation"));
+                    }
+                };
+                let left = self.field("left", Self::object)?;
+                let right = self.field("right", Self::object)?;
+                if !left.is_solid() || !right.is_solid() {
+                    return Err(self.error("CSG children must be closed objects"));
+                }
+                Object::Csg(Csg::new(operation, left, right))
+            }
+            _ => {
+                self.position -= 2;
+                return Err(self.error("unknown object"));
+            }
+        };
+        self.expect("}")?;
+        Ok(object)
+    }
+}
+
+/// Parse the text of a scene file
+pub fn read(text: &str) -> io::Result<(Scene, Option<Camera>)> {
+    let mut parser = Parser {
+        tokens: tokenise(text)?,
+        position: 0,
+    };
+    let mut scene = Scene::new(Vec::new());
+    let mut camera = None;
+    while let Some(keyword) = parser.peek() {
+        parser.position += 1;
+        match keyword {
+            "camera" => camera = Some(parser.camera()?),
+            "background" => parser.background(&mut scene)?,
+            "light" => scene.lights.push(parser.light()?),
+            "fog" => scene.fog = Some(parser.medium()?),
+            "volume" => {
+                parser.expect("{")?;
+                let boundary = parser.field("boundary", Parser::object)?;
+                if !boundary.is_solid() {
+                    return Err(parser.error("volume boundaries must be closed objects"));
+                }
+                let medium = parser.field("medium", Parser::medium)?;
+                parser.expect("}")?;
+                scene.volumes.push(Volume::new(boundary, medium));
+            }
+            "object" => scene.objects.push(parser.object()?),
+            "node" => scene.graph.add(parser.node()?),
+            _ => {
+                parser.position -= 1;
+                return Err(parser.error("unknown scene entry"));
+            }
+        }
+    }
+    Ok((scene, camera))
+}
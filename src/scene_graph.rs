@@ -0,0 +1,213 @@
+use cgmath::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::instance::{Instance, Transform};
+use crate::raytracing::*;
+
+/// A named node in the scene graph. Its transform is relative to its parent, so moving
+/// a node moves everything below it, and hiding a node hides everything below it too.
+pub struct Node {
+    pub name: String,
+    pub transform: Transform,
+    pub visible: bool,
+    pub object: Option<Arc<Object>>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// Empty node, for grouping others under
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.to_string(),
+            transform: Transform::identity(),
+            visible: true,
+            object: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_object(name: &str, object: Object) -> Self {
+        let mut node = Node::new(name);
+        node.object = Some(Arc::new(object));
+        node
+    }
+
+    /// Place the visible objects at or below this node into the world
+    fn flatten(
+        &self,
+        parent: Transform,
+        parent_path: &str,
+        objects: &mut Vec<Object>,
+        paths: &mut Vec<String>,
+    ) {
+        if !self.visible {
+            return;
+        }
+        let transform = parent * self.transform;
+        let path = join(parent_path, &self.name);
+        if let Some(ref object) = self.object {
+            objects.push(Object::Instance(Instance::new(
+                Arc::clone(object),
+                transform,
+            )));
+            paths.push(path.clone());
+        }
+        for child in self.children.iter() {
+            child.flatten(transform, &path, objects, paths);
+        }
+    }
+
+    /// How many objects `flatten` places
+    fn object_count(&self) -> usize {
+        if !self.visible {
+            return 0;
+        }
+        let own = if self.object.is_some() { 1 } else { 0 };
+        own + self.children.iter().map(Node::object_count).sum::<usize>()
+    }
+
+    /// Record where each name at or below this node is first found, reached through `route`
+    fn index_names(&self, route: &mut Vec<usize>, routes: &mut HashMap<String, Vec<usize>>) {
+        routes
+            .entry(self.name.clone())
+            .or_insert_with(|| route.clone());
+        for (i, child) in self.children.iter().enumerate() {
+            route.push(i);
+            child.index_names(route, routes);
+            route.pop();
+        }
+    }
+
+    fn names(&self) -> Vec<&str> {
+        let mut names = vec![self.name.as_str()];
+        for child in self.children.iter() {
+            names.extend(child.names());
+        }
+        names
+    }
+}
+
+fn join(parent_path: &str, name: &str) -> String {
+    if parent_path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent_path, name)
+    }
+}
+
+/// Hierarchy of named nodes in a scene.
+///
+/// The visible objects are kept placed in the world ready for rendering, so nodes are
+/// changed through `edit` to have that brought up to date. Only the edited node's objects
+/// are placed again.
+#[derive(Default)]
+pub struct SceneGraph {
+    nodes: Vec<Node>,
+    objects: Vec<Object>,
+    // Path of the node each object belongs to, e.g. "table/leg"
+    paths: Vec<String>,
+    // Child indices leading to the first node with each name, depth first
+    routes: HashMap<String, Vec<usize>>,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        SceneGraph::default()
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn add(&mut self, node: Node) {
+        // Last in depth first order, so its objects go at the end
+        node.flatten(
+            Transform::identity(),
+            "",
+            &mut self.objects,
+            &mut self.paths,
+        );
+        node.index_names(&mut vec![self.nodes.len()], &mut self.routes);
+        self.nodes.push(node);
+    }
+
+    /// First node called `name`, searching each top-level node depth first
+    pub fn find(&self, name: &str) -> Option<&Node> {
+        let route = self.routes.get(name)?;
+        let mut node = &self.nodes[route[0]];
+        for &i in route[1..].iter() {
+            node = &node.children[i];
+        }
+        Some(node)
+    }
+
+    /// Change the node called `name`, e.g. to move or hide it. Returns false if there
+    /// is no such node.
+    pub fn edit(&mut self, name: &str, edit: impl FnOnce(&mut Node)) -> bool {
+        let route = match self.routes.get(name) {
+            Some(route) => route.clone(),
+            None => return false,
+        };
+
+        // Find where the node's objects start, and how its ancestors place it
+        let mut transform = Transform::identity();
+        let mut path = String::new();
+        let mut visible = true;
+        let mut start = 0;
+        let mut siblings = &mut self.nodes;
+        for &i in route[..route.len() - 1].iter() {
+            start += siblings[..i].iter().map(Node::object_count).sum::<usize>();
+            let parent = &mut siblings[i];
+            visible &= parent.visible;
+            if parent.visible && parent.object.is_some() {
+                start += 1;
+            }
+            transform = transform * parent.transform;
+            path = join(&path, &parent.name);
+            siblings = &mut parent.children;
+        }
+        let i = route[route.len() - 1];
+        start += siblings[..i].iter().map(Node::object_count).sum::<usize>();
+        let node = &mut siblings[i];
+
+        let old_count = node.object_count();
+        let old_names: Vec<String> = node.names().into_iter().map(String::from).collect();
+        edit(node);
+        let renamed = node.names() != old_names;
+        if visible {
+            let mut objects = Vec::new();
+            let mut paths = Vec::new();
+            node.flatten(transform, &path, &mut objects, &mut paths);
+            let end = start + old_count;
+            self.objects.splice(start..end, objects);
+            self.paths.splice(start..end, paths);
+        }
+        if renamed {
+            self.routes.clear();
+            for (i, node) in self.nodes.iter().enumerate() {
+                node.index_names(&mut vec![i], &mut self.routes);
+            }
+        }
+        true
+    }
+
+    /// Visible objects, placed in the world
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+
+    /// Path of the node that one of `objects` belongs to, e.g. to name a picked object
+    pub fn path(&self, object: &Object) -> Option<&str> {
+        // Worked out from where `object` sits in `objects`, rather than searching them
+        let offset =
+            (object as *const Object as usize).checked_sub(self.objects.as_ptr() as usize)?;
+        let size = std::mem::size_of::<Object>();
+        let index = offset / size;
+        if offset % size == 0 && index < self.objects.len() {
+            Some(self.paths[index].as_str())
+        } else {
+            None
+        }
+    }
+}
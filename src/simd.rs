@@ -0,0 +1,333 @@
+//! Four `f32`s worked on at once, with SSE on x86-64 and an array of them elsewhere.
+//!
+//! Each lane gives exactly what the same scalar arithmetic would, including `min` and
+//! `max` ignoring NaNs like `f32::min` and `f32::max`, so code written with these gets
+//! the same results as its scalar version.
+
+use std::ops::{Add, BitAnd, BitOr, Div, Mul, Not, Sub};
+
+#[cfg(target_arch = "x86_64")]
+mod lanes {
+    use std::arch::x86_64::*;
+
+    /// Four `f32` lanes
+    #[derive(Clone, Copy)]
+    pub struct F32x4(pub(super) __m128);
+
+    /// Which of four lanes a comparison held for
+    #[derive(Clone, Copy)]
+    pub struct Mask4(pub(super) __m128);
+
+    // SSE and SSE2 are part of x86-64, so these are always available
+    impl F32x4 {
+        #[inline]
+        pub fn splat(value: f32) -> Self {
+            F32x4(unsafe { _mm_set1_ps(value) })
+        }
+
+        #[inline]
+        pub fn from_array(values: [f32; 4]) -> Self {
+            F32x4(unsafe { _mm_loadu_ps(values.as_ptr()) })
+        }
+
+        #[inline]
+        pub fn to_array(self) -> [f32; 4] {
+            let mut values = [0.0; 4];
+            unsafe { _mm_storeu_ps(values.as_mut_ptr(), self.0) };
+            values
+        }
+
+        #[inline]
+        pub fn sqrt(self) -> Self {
+            F32x4(unsafe { _mm_sqrt_ps(self.0) })
+        }
+
+        #[inline]
+        pub fn min(self, other: Self) -> Self {
+            // `_mm_min_ps` gives its second operand if either is NaN
+            unsafe {
+                let min = _mm_min_ps(other.0, self.0);
+                let nan = _mm_cmpunord_ps(self.0, self.0);
+                F32x4(_mm_or_ps(_mm_and_ps(nan, other.0), _mm_andnot_ps(nan, min)))
+            }
+        }
+
+        #[inline]
+        pub fn max(self, other: Self) -> Self {
+            unsafe {
+                let max = _mm_max_ps(other.0, self.0);
+                let nan = _mm_cmpunord_ps(self.0, self.0);
+                F32x4(_mm_or_ps(_mm_and_ps(nan, other.0), _mm_andnot_ps(nan, max)))
+            }
+        }
+
+        #[inline]
+        pub fn lt(self, other: Self) -> Mask4 {
+            Mask4(unsafe { _mm_cmplt_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub fn gt(self, other: Self) -> Mask4 {
+            Mask4(unsafe { _mm_cmpgt_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub(super) fn add(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_add_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub(super) fn sub(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_sub_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub(super) fn mul(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_mul_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub(super) fn div(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_div_ps(self.0, other.0) })
+        }
+    }
+
+    impl Mask4 {
+        #[inline]
+        pub fn from_bits(bits: u32) -> Self {
+            let lane = |i: u32| if bits & (1 << i) != 0 { -1 } else { 0 };
+            Mask4(unsafe { _mm_castsi128_ps(_mm_setr_epi32(lane(0), lane(1), lane(2), lane(3))) })
+        }
+
+        /// Bit `i` set for each lane `i` that's set
+        #[inline]
+        pub fn bits(self) -> u32 {
+            unsafe { _mm_movemask_ps(self.0) as u32 }
+        }
+
+        #[inline]
+        pub(super) fn and(self, other: Self) -> Self {
+            Mask4(unsafe { _mm_and_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub(super) fn or(self, other: Self) -> Self {
+            Mask4(unsafe { _mm_or_ps(self.0, other.0) })
+        }
+
+        #[inline]
+        pub(super) fn not(self) -> Self {
+            Mask4(unsafe { _mm_xor_ps(self.0, _mm_castsi128_ps(_mm_set1_epi32(-1))) })
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod lanes {
+    /// Four `f32` lanes
+    #[derive(Clone, Copy)]
+    pub struct F32x4(pub(super) [f32; 4]);
+
+    /// Which of four lanes a comparison held for
+    #[derive(Clone, Copy)]
+    pub struct Mask4(u32);
+
+    impl F32x4 {
+        #[inline]
+        pub fn splat(value: f32) -> Self {
+            F32x4([value; 4])
+        }
+
+        #[inline]
+        pub fn from_array(values: [f32; 4]) -> Self {
+            F32x4(values)
+        }
+
+        #[inline]
+        pub fn to_array(self) -> [f32; 4] {
+            self.0
+        }
+
+        #[inline]
+        fn map(self, other: Self, f: impl Fn(f32, f32) -> f32) -> Self {
+            F32x4([0, 1, 2, 3].map(|i| f(self.0[i], other.0[i])))
+        }
+
+        #[inline]
+        fn compare(self, other: Self, f: impl Fn(f32, f32) -> bool) -> Mask4 {
+            Mask4((0..4).fold(0, |bits, i| bits | (f(self.0[i], other.0[i]) as u32) << i))
+        }
+
+        #[inline]
+        pub fn sqrt(self) -> Self {
+            F32x4(self.0.map(f32::sqrt))
+        }
+
+        #[inline]
+        pub fn min(self, other: Self) -> Self {
+            self.map(other, f32::min)
+        }
+
+        #[inline]
+        pub fn max(self, other: Self) -> Self {
+            self.map(other, f32::max)
+        }
+
+        #[inline]
+        pub fn lt(self, other: Self) -> Mask4 {
+            self.compare(other, |a, b| a < b)
+        }
+
+        #[inline]
+        pub fn gt(self, other: Self) -> Mask4 {
+            self.compare(other, |a, b| a > b)
+        }
+
+        #[inline]
+        pub(super) fn add(self, other: Self) -> Self {
+            self.map(other, |a, b| a + b)
+        }
+
+        #[inline]
+        pub(super) fn sub(self, other: Self) -> Self {
+            self.map(other, |a, b| a - b)
+        }
+
+        #[inline]
+        pub(super) fn mul(self, other: Self) -> Self {
+            self.map(other, |a, b| a * b)
+        }
+
+        #[inline]
+        pub(super) fn div(self, other: Self) -> Self {
+            self.map(other, |a, b| a / b)
+        }
+    }
+
+    impl Mask4 {
+        #[inline]
+        pub fn from_bits(bits: u32) -> Self {
+            Mask4(bits & 0b1111)
+        }
+
+        /// Bit `i` set for each lane `i` that's set
+        #[inline]
+        pub fn bits(self) -> u32 {
+            self.0
+        }
+
+        #[inline]
+        pub(super) fn and(self, other: Self) -> Self {
+            Mask4(self.0 & other.0)
+        }
+
+        #[inline]
+        pub(super) fn or(self, other: Self) -> Self {
+            Mask4(self.0 | other.0)
+        }
+
+        #[inline]
+        pub(super) fn not(self) -> Self {
+            Mask4::from_bits(!self.0)
+        }
+    }
+}
+
+pub use lanes::{F32x4, Mask4};
+
+impl Mask4 {
+    #[inline]
+    pub fn any(self) -> bool {
+        self.bits() != 0
+    }
+
+    #[inline]
+    pub fn lane(self, i: usize) -> bool {
+        self.bits() & (1 << i) != 0
+    }
+}
+
+impl Add for F32x4 {
+    type Output = Self;
+    #[inline]
+    fn add(self, other: Self) -> Self {
+        F32x4::add(self, other)
+    }
+}
+
+impl Sub for F32x4 {
+    type Output = Self;
+    #[inline]
+    fn sub(self, other: Self) -> Self {
+        F32x4::sub(self, other)
+    }
+}
+
+impl Mul for F32x4 {
+    type Output = Self;
+    #[inline]
+    fn mul(self, other: Self) -> Self {
+        F32x4::mul(self, other)
+    }
+}
+
+impl Div for F32x4 {
+    type Output = Self;
+    #[inline]
+    fn div(self, other: Self) -> Self {
+        F32x4::div(self, other)
+    }
+}
+
+impl BitAnd for Mask4 {
+    type Output = Self;
+    #[inline]
+    fn bitand(self, other: Self) -> Self {
+        Mask4::and(self, other)
+    }
+}
+
+impl BitOr for Mask4 {
+    type Output = Self;
+    #[inline]
+    fn bitor(self, other: Self) -> Self {
+        Mask4::or(self, other)
+    }
+}
+
+impl Not for Mask4 {
+    type Output = Self;
+    #[inline]
+    fn not(self) -> Self {
+        Mask4::not(self)
+    }
+}
+
+/// Three lanes of vectors, one per axis
+pub type Vector4 = [F32x4; 3];
+
+/// The same vector in every lane
+#[inline]
+pub fn splat(vector: crate::raytracing::Vector) -> Vector4 {
+    [vector.x, vector.y, vector.z].map(F32x4::splat)
+}
+
+#[inline]
+pub fn sub(a: Vector4, b: Vector4) -> Vector4 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+/// Summed in the same order as `cgmath`'s
+#[inline]
+pub fn dot(a: Vector4, b: Vector4) -> F32x4 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[inline]
+pub fn cross(a: Vector4, b: Vector4) -> Vector4 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
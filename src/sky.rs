@@ -0,0 +1,156 @@
+use cgmath::prelude::*;
+use std::f32::consts::PI;
+
+use crate::raytracing::*;
+
+/// Preetham et al.'s analytic daylight sky, with a sun at the given position.
+///
+/// Luminance is in kcd/m^2 before `intensity` is applied, so an intensity of about 0.1
+/// brings a clear midday sky into the same range as the default gradient background.
+pub struct Sky {
+    pub sun_elevation: Degrees,
+    /// Compass direction of the sun, clockwise from -z (into the screen) towards +x
+    pub sun_azimuth: Degrees,
+    /// Haziness of the atmosphere, from about 2 (clear) to 10 (hazy)
+    pub turbidity: f32,
+    pub intensity: f32,
+    sun_direction: Vector,
+    zenith: [f32; 3],
+    // Perez distribution coefficients A-E for each of Y, x and y
+    perez: [[f32; 5]; 3],
+}
+
+impl Sky {
+    pub fn new(
+        sun_elevation: Degrees,
+        sun_azimuth: Degrees,
+        turbidity: f32,
+        intensity: f32,
+    ) -> Self {
+        let t = turbidity;
+        let theta_s = PI / 2.0 - cgmath::Rad::from(sun_elevation).0.clamp(0.0, PI / 2.0);
+        let azimuth = cgmath::Rad::from(sun_azimuth).0;
+        let sun_direction = Vector::new(
+            theta_s.sin() * azimuth.sin(),
+            theta_s.cos(),
+            -theta_s.sin() * azimuth.cos(),
+        );
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let thetas = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+        let chromaticity = |m: [[f32; 4]; 3]| {
+            let row = |r: [f32; 4]| r.iter().zip(thetas.iter()).map(|(a, b)| a * b).sum::<f32>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        Sky {
+            sun_elevation,
+            sun_azimuth,
+            turbidity,
+            intensity,
+            sun_direction,
+            zenith: [zenith_luminance, zenith_x, zenith_y],
+            perez,
+        }
+    }
+
+    /// Unit vector pointing at the sun
+    pub fn sun_direction(&self) -> Vector {
+        self.sun_direction
+    }
+
+    pub fn get_colour(&self, dir: Vector) -> ColourFloat {
+        // The model is only defined above the horizon, continue the horizon colour below it
+        let cos_theta = dir.y.max(0.01);
+        let cos_gamma = dir.dot(self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        let theta_s = self.sun_direction.y.clamp(-1.0, 1.0).acos();
+
+        let mut xyy = [0.0; 3];
+        for (i, value) in xyy.iter_mut().enumerate() {
+            let p = self.perez[i];
+            let f = |cos_theta: f32, gamma: f32| {
+                (1.0 + p[0] * (p[1] / cos_theta).exp())
+                    * (1.0 + p[2] * (p[3] * gamma).exp() + p[4] * gamma.cos().powi(2))
+            };
+            *value = self.zenith[i] * f(cos_theta, gamma) / f(1.0, theta_s);
+        }
+        self.intensity * xyy_to_rgb(xyy[1], xyy[2], xyy[0])
+    }
+
+    /// Directional light for the sun, dimmed and reddened by the atmosphere.
+    /// `irradiance` is the sun's brightness before it enters the atmosphere.
+    pub fn sun(&self, irradiance: f32) -> Light {
+        let direction = self.sun_direction();
+        let theta_s = direction.y.clamp(-1.0, 1.0).acos();
+        // Kasten and Young's relative optical mass
+        let optical_mass =
+            1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).max(0.01).powf(-1.253));
+        // Angstrom turbidity coefficient for aerosols
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength_um: f32| {
+            let rayleigh = 0.008735 * wavelength_um.powf(-4.08);
+            let aerosol = beta * wavelength_um.powf(-1.3);
+            (-(rayleigh + aerosol) * optical_mass).exp()
+        };
+        Light::Directional {
+            direction,
+            colour: irradiance
+                * ColourFloat::new(
+                    transmittance(0.68),
+                    transmittance(0.55),
+                    transmittance(0.44),
+                ),
+        }
+    }
+}
+
+/// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> ColourFloat {
+    if y <= 0.0 {
+        return ColourFloat::zero();
+    }
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    ColourFloat::new(
+        3.2406 * cx - 1.5372 * luminance - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * luminance + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * luminance + 1.0570 * cz,
+    )
+    .map(|c| c.max(0.0))
+}
@@ -1,24 +1,2517 @@
 use super::*;
+use crate::texture::*;
+use cgmath::assert_relative_eq;
+use cgmath::prelude::*;
+use cgmath::Deg;
+use utils::Rng;
+
 #[test]
 fn test_reflect_straight() {
     // Straight on
     let start = Point::new(0.0, 0.0, 0.0);
     let dir = Vector::new(0.0, 0.0, -1.0);
     let normal = Vector::new(0.0, 0.0, 1.0);
-    let ray = Ray { start, dir };
+    let ray = Ray::new(start, dir);
     assert_eq!(reflect(&ray.dir, &normal), Vector::new(0.0, 0.0, 1.0));
 
     // 45 degree incident ray
     let start = Point::new(0.0, 0.0, 0.0);
     let dir = Vector::new(1.0, 0.0, -1.0);
     let normal = Vector::new(0.0, 0.0, 1.0);
-    let ray = Ray { start, dir };
+    let ray = Ray::new(start, dir);
     assert_eq!(reflect(&ray.dir, &normal), Vector::new(1.0, 0.0, 1.0));
 
     // 45 degree normal
     let start = Point::new(0.0, 0.0, 0.0);
     let dir = Vector::new(0.0, 0.0, -1.0);
     let normal = Vector::new(1.0, 0.0, 1.0);
-    let ray = Ray { start, dir };
-    assert_eq!(reflect(&ray.dir, &normal), Vector::new(1.0, 0.0, 0.0));
+    let ray = Ray::new(start, dir);
+    // Normalising the normal leaves a rounding error
+    assert_relative_eq!(reflect(&ray.dir, &normal), Vector::new(1.0, 0.0, 0.0));
+}
+
+#[test]
+fn test_procedural_textures() {
+    let at = |x: f32, y: f32, z: f32| TexturePoint {
+        position: Point::new(x, y, z),
+        uv: Uv::new(0.0, 0.0),
+        duvdx: Uv::zero(),
+        duvdy: Uv::zero(),
+    };
+
+    // Adjacent checker cells alternate
+    let checker = Texture::Checker {
+        even: Box::new(Texture::Solid(ColourFloat::new(255.0, 255.0, 255.0))),
+        odd: Box::new(Texture::Solid(ColourFloat::zero())),
+        scale: 1.0,
+        space: TextureSpace::World,
+    };
+    assert_eq!(checker.eval(&at(0.5, 0.5, 0.5)).x, 255.0);
+    assert_eq!(checker.eval(&at(1.5, 0.5, 0.5)).x, 0.0);
+    assert_eq!(checker.eval(&at(-0.5, 0.5, 0.5)).x, 0.0);
+
+    // Noise is continuous, zero on lattice points and stays in range
+    assert_relative_eq!(perlin(Point::new(3.0, -2.0, 7.0)), 0.0);
+    let noise = Texture::Noise {
+        scale: 3.7,
+        space: TextureSpace::World,
+    };
+    for i in 0..100 {
+        let t = i as f32 * 0.173;
+        let value = noise.eval(&at(t, 1.0 - t, 0.5 * t)).x;
+        assert!((0.0..=1.0).contains(&value));
+    }
+
+    // Patterns in texture space follow the uv, wherever the point is
+    let marble = Texture::Marble {
+        scale: 2.0,
+        turbulence: 3.0,
+        octaves: 4,
+        space: TextureSpace::Uv,
+    };
+    let on_surface = |x: f32, u: f32, v: f32| TexturePoint {
+        position: Point::new(x, 0.0, 0.0),
+        uv: Uv::new(u, v),
+        duvdx: Uv::zero(),
+        duvdy: Uv::zero(),
+    };
+    assert_eq!(
+        marble.eval(&on_surface(0.0, 0.3, 0.6)),
+        marble.eval(&on_surface(5.0, 0.3, 0.6))
+    );
+    assert_ne!(
+        marble.eval(&on_surface(0.0, 0.3, 0.6)),
+        marble.eval(&on_surface(0.0, 0.7, 0.1))
+    );
+
+    // A ramp driven by a gradient interpolates between its stops
+    let ramp = Texture::Ramp {
+        input: Box::new(Texture::Gradient {
+            origin: Point::zero(),
+            direction: Vector::new(10.0, 0.0, 0.0),
+            space: TextureSpace::World,
+        }),
+        stops: vec![
+            (0.0, ColourFloat::zero()),
+            (1.0, ColourFloat::new(200.0, 100.0, 0.0)),
+        ],
+    };
+    assert_relative_eq!(
+        ramp.eval(&at(2.5, 0.0, 0.0)),
+        ColourFloat::new(50.0, 25.0, 0.0)
+    );
+    assert_relative_eq!(
+        ramp.eval(&at(20.0, 0.0, 0.0)),
+        ColourFloat::new(200.0, 100.0, 0.0)
+    );
+
+    // Gradients can be laid out in texture space too
+    let along_u = Texture::Gradient {
+        origin: Point::zero(),
+        direction: Vector::new(1.0, 0.0, 0.0),
+        space: TextureSpace::Uv,
+    };
+    assert_relative_eq!(along_u.eval(&on_surface(5.0, 0.25, 0.6)).x, 0.25);
+}
+
+#[test]
+fn test_interpolated_normals() {
+    let mut triangle = Triangle::new(
+        Point::new(0.0, 0.0, 0.0),
+        Point::new(0.0, 1.0, 0.0),
+        Point::new(1.0, 0.0, 0.0),
+        [ColourFloat::zero(); 3],
+        Material::Diffuse,
+    );
+    let face_normal = Vector::new(0.0, 0.0, 1.0);
+    let centre = Point::new(0.25, 0.25, 0.0);
+    let at_v0 = TextureCoords::Barycentric(BarycentricCoords::new(0.0, 0.0));
+    let at_v1 = TextureCoords::Barycentric(BarycentricCoords::new(1.0, 0.0));
+    assert_relative_eq!(triangle.get_normal(centre, &at_v0), face_normal);
+
+    // Vertex normals are blended with the barycentric coordinates
+    let tilted = Vector::new(1.0, 0.0, 1.0).normalize();
+    triangle.normals = Some([face_normal, tilted, face_normal]);
+    assert_relative_eq!(triangle.get_normal(centre, &at_v0), face_normal);
+    assert_relative_eq!(triangle.get_normal(centre, &at_v1), tilted);
+
+    // A flat height field leaves the normal alone, a slope tilts it
+    triangle.normals = None;
+    triangle.normal_map = Some(NormalMap::Bump {
+        height: Texture::Solid(ColourFloat::new(0.5, 0.5, 0.5)),
+        strength: 1.0,
+    });
+    assert_relative_eq!(triangle.get_normal(centre, &at_v0), face_normal);
+    triangle.normal_map = Some(NormalMap::Bump {
+        height: Texture::Gradient {
+            origin: Point::zero(),
+            direction: Vector::new(1.0, 0.0, 0.0),
+            space: TextureSpace::World,
+        },
+        strength: 1.0,
+    });
+    let bumped = triangle.get_normal(centre, &at_v0);
+    assert!(bumped.x < -0.1 && bumped.z > 0.1);
+
+    // A flat tangent-space map points straight along the normal
+    triangle.normal_map = Some(NormalMap::TangentSpace(Texture::Solid(ColourFloat::new(
+        0.5, 0.5, 1.0,
+    ))));
+    assert_relative_eq!(triangle.get_normal(centre, &at_v0), face_normal);
+
+    // Vertex colours are blended with the same weights
+    let blue = ColourFloat::new(0.0, 0.0, 255.0);
+    triangle.colours = [ColourFloat::zero(), blue, ColourFloat::zero()];
+    let point_sample = Footprint {
+        dpdx: Vector::zero(),
+        dpdy: Vector::zero(),
+    };
+    let colour = |coords| {
+        triangle.get_colour(
+            centre,
+            &IntersectionLocation::new(1.0, coords),
+            &point_sample,
+        )
+    };
+    assert_relative_eq!(colour(at_v1), blue);
+    assert_relative_eq!(colour(at_v0), ColourFloat::zero());
+}
+
+#[test]
+fn test_mipmapped_texture() {
+    use crate::mipmap::*;
+    // 4x4 black and white checkerboard, one texel per cell
+    let image = image::RgbImage::from_fn(4, 4, |x, y| {
+        if (x + y) % 2 == 0 {
+            image::Rgb([255, 255, 255])
+        } else {
+            image::Rgb([0, 0, 0])
+        }
+    });
+    let mut texture = ImageTexture::new(&image, TextureFilter::Trilinear);
+    assert_eq!(texture.mip_levels(), 3);
+
+    // A tiny footprint reads a single texel, a huge one the average of the image
+    let centre_of_texel = Uv::new(0.125, 0.875);
+    let small = Uv::new(0.001, 0.0);
+    assert_relative_eq!(
+        texture.lookup(centre_of_texel, small, small),
+        ColourFloat::new(255.0, 255.0, 255.0)
+    );
+    let large = Uv::new(2.0, 0.0);
+    assert_relative_eq!(
+        texture.lookup(centre_of_texel, large, large),
+        ColourFloat::new(127.5, 127.5, 127.5)
+    );
+
+    // Anisotropic filtering averages along the long axis of the footprint
+    texture.filter = TextureFilter::Anisotropic(8);
+    let across = texture.lookup(centre_of_texel, Uv::new(0.5, 0.0), Uv::new(0.0, 0.01));
+    assert_relative_eq!(across, ColourFloat::new(127.5, 127.5, 127.5));
+}
+
+#[test]
+fn test_environment_map_sampling() {
+    use crate::background::*;
+    use std::f32::consts::PI;
+
+    // A uniform map samples the whole sphere evenly
+    let uniform = EnvironmentMap::new(
+        8,
+        4,
+        vec![ColourFloat::new(1.0, 1.0, 1.0); 32],
+        Deg(0.0),
+        1.0,
+    );
+    for &(u, v) in &[(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)] {
+        let (dir, pdf) = uniform.sample(u, v);
+        assert_relative_eq!(dir.magnitude(), 1.0, epsilon = 1e-5);
+        // Piecewise constant in theta, so only roughly 1 / 4pi
+        assert!((pdf * 4.0 * PI - 1.0).abs() < 0.3);
+    }
+
+    // All samples head for the single bright texel, which looks straight up
+    let mut texels = vec![ColourFloat::zero(); 32];
+    texels[3] = ColourFloat::new(10.0, 10.0, 10.0);
+    let spot = EnvironmentMap::new(8, 4, texels, Deg(0.0), 2.0);
+    for i in 0..10 {
+        let (dir, pdf) = spot.sample(i as f32 / 10.0, 0.5);
+        assert!(dir.y > 0.7);
+        assert!(pdf > 0.0);
+        assert_relative_eq!(spot.get_colour(dir), ColourFloat::new(20.0, 20.0, 20.0));
+    }
+    assert_eq!(spot.pdf(Vector::new(0.0, -1.0, 0.0)), 0.0);
+
+    // A floor lit by the spot and by a glowing sphere where the map is black gets the
+    // sum of what each alone gives it, none of the sphere's light being lost
+    let floor_brightness = |spot: bool, glow: bool| {
+        let mut objects = vec![Object::Plane(crate::primitives::Plane::new(
+            Point::new(0.0, 0.0, 0.0),
+            Vector::unit_y(),
+            ColourFloat::new(255.0, 255.0, 255.0),
+            Material::Lambertian,
+        ))];
+        if glow {
+            objects.push(Object::Sphere(Sphere {
+                centre: Point::new(2.0, 1.0, 0.0),
+                radius: 1.0,
+                colour: ColourFloat::new(100.0, 100.0, 100.0),
+                texture: None,
+                material: Material::Diffuse,
+            }));
+        }
+        let mut scene = Scene::new(objects);
+        let mut texels = vec![ColourFloat::zero(); 32];
+        if spot {
+            texels[3] = ColourFloat::new(10.0, 10.0, 10.0);
+        }
+        scene.background =
+            Background::Environment(EnvironmentMap::new(8, 4, texels, Deg(0.0), 2.0));
+        let mut rng = Rng::new(7, 0);
+        let down = || Ray::new(Point::new(0.0, 2.0, 0.0), -Vector::unit_y());
+        let samples = 20000;
+        (0..samples)
+            .map(|_| trace(down(), &scene, 1, &mut rng).x)
+            .sum::<f32>()
+            / samples as f32
+    };
+    let both = floor_brightness(true, true);
+    let separately = floor_brightness(true, false) + floor_brightness(false, true);
+    assert_relative_eq!(both, separately, max_relative = 0.1);
+}
+
+#[test]
+fn test_daylight_sky() {
+    use crate::sky::*;
+
+    let noon = Sky::new(Deg(80.0), Deg(90.0), 3.0, 1.0);
+    assert!(noon.sun_direction().x > 0.1 && noon.sun_direction().y > 0.9);
+
+    // Brighter near the sun than opposite it, and a blue sky overhead
+    let towards_sun = noon.get_colour(Vector::new(0.3, 0.5, 0.0).normalize());
+    let away_from_sun = noon.get_colour(Vector::new(-0.3, 0.5, 0.0).normalize());
+    assert!(towards_sun.y > away_from_sun.y);
+    let zenith = noon.get_colour(Vector::new(0.0, 1.0, 0.0));
+    assert!(zenith.z > zenith.x);
+
+    // The low sun loses more blue than red passing through the atmosphere
+    let sunset = Sky::new(Deg(5.0), Deg(90.0), 3.0, 1.0);
+    let sun_colour = |sky: &Sky| match sky.sun(1.0) {
+        Light::Directional { colour, .. } => colour,
+        _ => panic!("The sun should be a directional light"),
+    };
+    let (noon_sun, sunset_sun) = (sun_colour(&noon), sun_colour(&sunset));
+    assert!(sunset_sun.x < noon_sun.x);
+    assert!(sunset_sun.z / sunset_sun.x < noon_sun.z / noon_sun.x);
+
+    // A sky in a scene file can bring its sun, which is saved as the light it is
+    let text =
+        "background sky {\n    sun_elevation 80 sun_azimuth 90 turbidity 3 intensity 1\n    \
+                sun 1\n}";
+    let (scene, _) = crate::scene_file::read(text).unwrap();
+    let (saved, _) =
+        crate::scene_file::read(&crate::scene_file::write(&scene, None).unwrap()).unwrap();
+    for scene in [scene, saved].iter() {
+        assert_eq!(scene.lights.len(), 1);
+        match scene.lights[0] {
+            Light::Directional { direction, colour } => {
+                assert_relative_eq!(direction, noon.sun_direction());
+                assert_relative_eq!(colour, noon_sun, max_relative = 1e-5);
+            }
+            _ => panic!("The sun should be a directional light"),
+        }
+    }
+}
+
+#[test]
+fn test_lit_triangles() {
+    use crate::background::Background;
+    use crate::mesh::*;
+
+    // A point light in front of a triangle and a mesh lights the side facing it
+    let (v0, v1, v2) = (
+        Point::new(0.0, 0.0, 0.0),
+        Point::new(0.0, 1.0, 0.0),
+        Point::new(1.0, 0.0, 0.0),
+    );
+    let white = ColourFloat::new(255.0, 255.0, 255.0);
+    let triangle = Object::Triangle(Triangle::new(v0, v1, v2, [white; 3], Material::Lambertian));
+    let mesh = Object::Mesh(Mesh::new(
+        vec![v0, v1, v2],
+        vec![Face {
+            indices: [0, 1, 2],
+            material: 0,
+        }],
+        white,
+        vec![Material::Lambertian],
+    ));
+    for object in IntoIterator::into_iter([triangle, mesh]) {
+        let mut scene = Scene::new(vec![object]);
+        scene.background = Background::Constant(ColourFloat::zero());
+        scene.lights.push(Light::Point {
+            position: Point::new(0.25, 0.25, -3.0),
+            colour: ColourFloat::new(100.0, 100.0, 100.0),
+        });
+        let ray = Ray::new(Point::new(0.25, 0.25, -5.0), Vector::unit_z());
+        assert!(scene.closest_intersection(&ray).is_some());
+        let colour = trace(ray, &scene, 1, &mut Rng::new(3, 0));
+        assert!(colour.x > 1.0);
+    }
+}
+
+#[test]
+fn test_instanced_sphere() {
+    use crate::instance::*;
+    use std::sync::Arc;
+
+    let unit_sphere = Arc::new(Object::Sphere(Sphere {
+        centre: Point::zero(),
+        radius: 1.0,
+        colour: ColourFloat::new(255.0, 0.0, 0.0),
+        texture: None,
+        material: Material::Diffuse,
+    }));
+    // Stretched to 2 along x, then moved to x = 10
+    let transform = Transform::from_translation(Vector::new(10.0, 0.0, 0.0))
+        * Transform::from_nonuniform_scale(2.0, 1.0, 1.0);
+    let instances: Vec<Object> = (0..3)
+        .map(|_| Object::Instance(Instance::new(Arc::clone(&unit_sphere), transform)))
+        .collect();
+    assert_eq!(Arc::strong_count(&unit_sphere), 4);
+
+    let ray = Ray::new(Point::zero(), Vector::new(1.0, 0.0, 0.0));
+    let location = instances[0].intersection(&ray).unwrap();
+    assert_relative_eq!(location.distance, 8.0, epsilon = 1e-4);
+    let position = ray.start + location.distance * ray.dir;
+    assert_relative_eq!(
+        instances[0].get_normal(position, &location),
+        Vector::new(-1.0, 0.0, 0.0),
+        epsilon = 1e-4
+    );
+
+    // Normals stay perpendicular to the stretched surface
+    let ray = Ray::new(
+        Point::new(10.0 + 2.0_f32.sqrt(), 5.0, 0.0),
+        Vector::new(0.0, -1.0, 0.0),
+    );
+    let location = instances[1].intersection(&ray).unwrap();
+    let position = ray.start + location.distance * ray.dir;
+    let normal = instances[1].get_normal(position, &location);
+    assert_relative_eq!(
+        normal,
+        Vector::new(1.0, 2.0, 0.0).normalize(),
+        epsilon = 1e-4
+    );
+
+    // Groups report the child that was hit
+    let group = Object::Group(Group::new(instances));
+    let location = group.intersection(&ray).unwrap();
+    assert_eq!(location.primitive, 0);
+    assert!(location.inner.is_some());
+}
+
+#[test]
+fn test_analytic_primitives() {
+    use crate::background::Background;
+    use crate::csg::*;
+    use crate::primitives::*;
+
+    let colour = ColourFloat::new(255.0, 255.0, 255.0);
+    let down = Ray::new(Point::new(0.0, 5.0, 0.0), Vector::new(0.0, -1.0, 0.0));
+    let hit = |object: &Object, ray: &Ray| {
+        let location = object.intersection(ray).unwrap();
+        let position = ray.start + location.distance * ray.dir;
+        (location.distance, object.get_normal(position, &location))
+    };
+
+    let plane = Object::Plane(Plane::new(
+        Point::zero(),
+        Vector::unit_y(),
+        colour,
+        Material::Diffuse,
+    ));
+    let (distance, normal) = hit(&plane, &down);
+    assert_relative_eq!(distance, 5.0, epsilon = 1e-4);
+    assert_relative_eq!(normal, Vector::unit_y(), epsilon = 1e-4);
+
+    let disc = Object::Disc(Disc::new(
+        Point::new(3.0, 0.0, 0.0),
+        Vector::unit_y(),
+        1.0,
+        colour,
+        Material::Diffuse,
+    ));
+    assert!(disc.intersection(&down).is_none());
+
+    // A box rotated 45 degrees about y presents an edge to a ray along x
+    let rotation = cgmath::Matrix3::from_angle_y(Deg(45.0));
+    let cuboid = Object::Cuboid(Cuboid::oriented(
+        Point::zero(),
+        Vector::new(1.0, 1.0, 1.0),
+        rotation,
+        colour,
+        Material::Diffuse,
+    ));
+    let along_x = Ray::new(Point::new(-5.0, 0.0, 0.1), Vector::unit_x());
+    let (distance, _) = hit(&cuboid, &along_x);
+    assert_relative_eq!(distance, 5.0 - 2.0_f32.sqrt() + 0.1, epsilon = 1e-3);
+    let (distance, normal) = hit(&cuboid, &down);
+    assert_relative_eq!(distance, 4.0, epsilon = 1e-4);
+    assert_relative_eq!(normal, Vector::unit_y(), epsilon = 1e-4);
+
+    // Capped cylinder along y from 0 to 2
+    let cylinder = Object::Cylinder(Cylinder::new(
+        Point::zero(),
+        Vector::unit_y(),
+        1.0,
+        2.0,
+        colour,
+        Material::Diffuse,
+    ));
+    let (distance, normal) = hit(&cylinder, &down);
+    assert_relative_eq!(distance, 3.0, epsilon = 1e-4);
+    assert_relative_eq!(normal, Vector::unit_y(), epsilon = 1e-4);
+    let (distance, normal) = hit(&cylinder, &along_x);
+    assert_relative_eq!(distance, 5.0 - (1.0_f32 - 0.01).sqrt(), epsilon = 1e-4);
+    assert!(normal.x < -0.99);
+
+    // Cone of radius 1 and height 1, hit halfway up its side
+    let cone = Object::Cone(Cone::new(
+        Point::zero(),
+        Vector::unit_y(),
+        1.0,
+        1.0,
+        colour,
+        Material::Diffuse,
+    ));
+    let ray = Ray::new(Point::new(-5.0, 0.5, 0.0), Vector::unit_x());
+    let (distance, normal) = hit(&cone, &ray);
+    assert_relative_eq!(distance, 4.5, epsilon = 1e-4);
+    assert_relative_eq!(
+        normal,
+        Vector::new(-1.0, 1.0, 0.0).normalize(),
+        epsilon = 1e-4
+    );
+
+    // Torus lying flat, the ray passes through the hole
+    let torus = Object::Torus(Torus::new(
+        Point::zero(),
+        Vector::unit_y(),
+        2.0,
+        0.5,
+        colour,
+        Material::Diffuse,
+    ));
+    assert!(torus.intersection(&down).is_none());
+    // The ray passes 0.1 beside the centre, so crosses each circle this far either side of it
+    let across = |radius: f32| (radius * radius - 0.1 * 0.1).sqrt();
+    let (distance, normal) = hit(&torus, &along_x);
+    assert_relative_eq!(distance, 5.0 - across(2.5), epsilon = 1e-4);
+    assert!(normal.x < -0.99);
+    let location = torus.intersection(&along_x).unwrap();
+    match location.texture_coords {
+        TextureCoords::Uv(uv) => {
+            assert!((0.0..=1.0).contains(&uv.x) && (0.0..=1.0).contains(&uv.y))
+        }
+        _ => panic!("Torus should report UV coordinates"),
+    }
+
+    // The torus is closed, so it has a span through each side of the tube
+    assert!(torus.is_solid());
+    let spans = torus.spans(&along_x);
+    assert_eq!(spans.len(), 2);
+    assert_relative_eq!(spans[0].enter.distance, 5.0 - across(2.5), epsilon = 1e-4);
+    assert_relative_eq!(spans[0].exit.distance, 5.0 - across(1.5), epsilon = 1e-4);
+    assert_relative_eq!(spans[1].enter.distance, 5.0 + across(1.5), epsilon = 1e-4);
+    assert_relative_eq!(spans[1].exit.distance, 5.0 + across(2.5), epsilon = 1e-4);
+    // Starting inside the tube
+    let behind = Ray::new(Point::new(2.0, 0.0, 0.0), Vector::unit_x());
+    let spans = torus.spans(&behind);
+    assert_eq!(spans.len(), 2);
+    assert_relative_eq!(spans[1].enter.distance, -0.5, epsilon = 1e-4);
+    assert_relative_eq!(spans[1].exit.distance, 0.5, epsilon = 1e-4);
+    // Only just clipping the outside of the tube, where the chord is short
+    let grazing = Ray::new(Point::new(2.499, 0.0, 5.0), -Vector::unit_z());
+    let half_chord = (2.5_f32 * 2.5 - 2.499 * 2.499).sqrt();
+    let spans = torus.spans(&grazing);
+    assert_eq!(spans.len(), 1);
+    assert_relative_eq!(spans[0].enter.distance, 5.0 - half_chord, epsilon = 1e-4);
+    assert_relative_eq!(spans[0].exit.distance, 5.0 + half_chord, epsilon = 1e-4);
+    // Hits land on the surface, not short of it
+    let mut rng = Rng::new(9, 0);
+    for _ in 0..200 {
+        let start = Point::new(rng.f32_range(-3.0, 3.0), rng.f32_range(-1.0, 1.0), 5.0);
+        let ray = Ray::new(start, -Vector::unit_z());
+        if let Some(location) = torus.intersection(&ray) {
+            let hit = ray.start + location.distance * ray.dir;
+            let radial = (hit.x * hit.x + hit.z * hit.z).sqrt();
+            let tube = ((radial - 2.0).powi(2) + hit.y * hit.y).sqrt();
+            assert_relative_eq!(tube, 0.5, epsilon = 1e-4);
+        }
+    }
+
+    // A mirror plane seen from below reflects the background below it
+    let mirror = Object::Plane(Plane::new(
+        Point::zero(),
+        Vector::unit_y(),
+        ColourFloat::zero(),
+        Material::Specular,
+    ));
+    let mut scene = Scene::new(vec![mirror]);
+    scene.background = Background::Constant(colour);
+    let up = Ray::new(
+        Point::new(0.0, -1.0, 0.0),
+        Vector::new(0.0, 1.0, 1.0).normalize(),
+    );
+    let reflected = trace(up, &scene, 3, &mut Rng::new(1, 0));
+    assert_relative_eq!(reflected, 0.9 * colour, max_relative = 1e-4);
+}
+
+#[test]
+fn test_csg_drilled_sphere() {
+    use crate::csg::*;
+    use crate::primitives::*;
+
+    let colour = ColourFloat::new(255.0, 255.0, 255.0);
+    let sphere = || {
+        Object::Sphere(Sphere {
+            centre: Point::zero(),
+            radius: 1.0,
+            colour,
+            texture: None,
+            material: Material::Diffuse,
+        })
+    };
+    let drill = Object::Cylinder(Cylinder::new(
+        Point::new(0.0, -2.0, 0.0),
+        Vector::unit_y(),
+        0.5,
+        4.0,
+        colour,
+        Material::Diffuse,
+    ));
+    let drilled = Object::Csg(Csg::new(CsgOperation::Difference, sphere(), drill));
+    let hit = |object: &Object, ray: &Ray| {
+        let location = object.intersection(ray).unwrap();
+        let position = ray.start + location.distance * ray.dir;
+        (location.distance, object.get_normal(position, &location))
+    };
+
+    // Straight down the hole
+    let down = Ray::new(Point::new(0.0, 5.0, 0.0), -Vector::unit_y());
+    assert!(drilled.intersection(&down).is_none());
+    let (distance, _) = hit(
+        &drilled,
+        &Ray::new(Point::new(0.75, 5.0, 0.0), -Vector::unit_y()),
+    );
+    assert_relative_eq!(distance, 5.0 - 0.4375_f32.sqrt(), epsilon = 1e-4);
+
+    // Across the sphere, in one side and out into the hole
+    let across = Ray::new(Point::new(-5.0, 0.0, 0.0), Vector::unit_x());
+    let (distance, normal) = hit(&drilled, &across);
+    assert_relative_eq!(distance, 4.0, epsilon = 1e-4);
+    assert_relative_eq!(normal, -Vector::unit_x(), epsilon = 1e-4);
+    let spans = drilled.spans(&across);
+    assert_eq!(spans.len(), 2);
+    assert_relative_eq!(spans[0].exit.distance, 4.5, epsilon = 1e-4);
+
+    // Walls of the hole face into it
+    let from_hole = Ray::new(Point::zero(), Vector::unit_x());
+    let (distance, normal) = hit(&drilled, &from_hole);
+    assert_relative_eq!(distance, 0.5, epsilon = 1e-4);
+    assert_relative_eq!(normal, -Vector::unit_x(), epsilon = 1e-4);
+
+    // Intersecting with a box keeps only the top of the sphere
+    let slab = Object::Cuboid(Cuboid::axis_aligned(
+        Point::new(-2.0, 0.5, -2.0),
+        Point::new(2.0, 2.0, 2.0),
+        colour,
+        Material::Diffuse,
+    ));
+    let cap = Object::Csg(Csg::new(CsgOperation::Intersection, sphere(), slab));
+    assert!(cap.intersection(&across).is_none());
+    let (distance, normal) = hit(
+        &cap,
+        &Ray::new(Point::new(0.0, -5.0, 0.0), Vector::unit_y()),
+    );
+    assert_relative_eq!(distance, 5.5, epsilon = 1e-4);
+    assert_relative_eq!(normal, -Vector::unit_y(), epsilon = 1e-4);
+
+    // A ray grazing the top of the sphere touches it without going in
+    let grazing = Ray::new(Point::new(-5.0, 1.000002, 0.0), Vector::unit_x());
+    let union = Object::Csg(Csg::new(CsgOperation::Union, sphere(), sphere()));
+    for span in union.spans(&grazing) {
+        assert_relative_eq!(span.enter.distance, 5.0, epsilon = 1e-2);
+        assert_relative_eq!(span.exit.distance, 5.0, epsilon = 1e-2);
+    }
+}
+
+#[test]
+fn test_participating_media() {
+    use crate::medium::*;
+    use crate::primitives::*;
+
+    // Henyey-Greenstein samples have a mean cosine of g
+    let mut rng = Rng::new(1, 0);
+    let phase = HenyeyGreenstein { g: 0.6 };
+    let n = 20000;
+    let mut mean_cos = 0.0;
+    for i in 0..n {
+        let u = (i as f32 + 0.5) / n as f32;
+        let dir = phase.sample(Vector::unit_z(), u, rng.f32());
+        assert_relative_eq!(dir.magnitude(), 1.0, epsilon = 1e-4);
+        mean_cos += dir.z / n as f32;
+    }
+    assert_relative_eq!(mean_cos, 0.6, epsilon = 0.02);
+    assert_relative_eq!(
+        HenyeyGreenstein { g: 0.0 }.eval(0.3),
+        0.25 / std::f32::consts::PI
+    );
+
+    // Delta tracking through a uniform grid collides as often as the homogeneous medium
+    let ray = Ray::new(Point::zero(), Vector::unit_x());
+    let homogeneous = Medium::homogeneous(0.5, 0.5, 0.0);
+    let grid = Medium {
+        density: Density::Grid(VoxelGrid::from_fn(
+            Point::new(-1.0, -1.0, -1.0),
+            Point::new(3.0, 1.0, 1.0),
+            [8, 4, 4],
+            |_| 0.5,
+        )),
+        ..Medium::homogeneous(1.0, 1.0, 0.0)
+    };
+    let expected = 1.0 - (-2.0_f32).exp();
+    for medium in [homogeneous, grid].iter() {
+        let collisions = (0..n)
+            .filter(|_| medium.sample_collision(&ray, 0.0, 2.0, &mut rng).is_some())
+            .count();
+        assert_relative_eq!(collisions as f32 / n as f32, expected, epsilon = 0.02);
+        let transmittance: f32 = (0..n)
+            .map(|_| medium.transmittance(&ray, 0.0, 2.0, &mut rng))
+            .sum::<f32>()
+            / n as f32;
+        assert_relative_eq!(transmittance, 1.0 - expected, epsilon = 0.02);
+    }
+
+    // Sunlight through a cube of smoke is dimmed, light that misses it is not
+    let mut scene = Scene::new(Vec::new());
+    scene.volumes.push(Volume::new(
+        Object::Cuboid(Cuboid::axis_aligned(
+            Point::new(-1.0, 1.0, -1.0),
+            Point::new(1.0, 3.0, 1.0),
+            ColourFloat::zero(),
+            Material::Diffuse,
+        )),
+        Medium::homogeneous(0.5, 0.0, 0.0),
+    ));
+    let sun = Light::Directional {
+        direction: Vector::unit_y(),
+        colour: ColourFloat::new(1.0, 1.0, 1.0),
+    };
+    let (_, shaded) = sun.illuminate(Point::zero(), &scene, &mut rng).unwrap();
+    assert_relative_eq!(shaded.x, (-1.0_f32).exp(), epsilon = 1e-4);
+    let (_, lit) = sun
+        .illuminate(Point::new(5.0, 0.0, 0.0), &scene, &mut rng)
+        .unwrap();
+    assert_relative_eq!(lit.x, 1.0);
+}
+
+#[test]
+fn test_subsurface_random_walk() {
+    use crate::background::Background;
+    use crate::csg::Solid;
+    use crate::mesh::*;
+
+    let translucent = |albedo: ColourFloat| {
+        let mut scene = Scene::new(vec![Object::Sphere(Sphere {
+            centre: Point::zero(),
+            radius: 1.0,
+            colour: ColourFloat::zero(),
+            texture: None,
+            material: Material::Subsurface {
+                mean_free_path: 0.1,
+                albedo,
+            },
+        })]);
+        scene.background = Background::Constant(ColourFloat::new(1.0, 1.0, 1.0));
+        scene
+    };
+    let ray = || Ray::new(Point::new(0.0, 0.0, 5.0), -Vector::unit_z());
+    let mut rng = Rng::new(2, 0);
+
+    // Nothing is absorbed, so every walk comes back out and is lit by the background
+    let scene = translucent(ColourFloat::new(1.0, 1.0, 1.0));
+    for _ in 0..20 {
+        assert_relative_eq!(
+            trace(ray(), &scene, 2, &mut rng),
+            ColourFloat::new(0.5, 0.5, 0.5),
+            epsilon = 1e-4
+        );
+    }
+
+    // Red is absorbed at the first scattering event, which nearly every walk reaches
+    let scene = translucent(ColourFloat::new(0.0, 1.0, 0.9));
+    let n = 200;
+    let colour = (0..n)
+        .map(|_| trace(ray(), &scene, 2, &mut rng))
+        .sum::<ColourFloat>()
+        / n as f32;
+    assert!(colour.x < 0.05);
+    assert_relative_eq!(colour.y, 0.5, epsilon = 1e-4);
+    assert!(colour.z < colour.y);
+
+    // A closed mesh cube is walked through too, its faces crossed from inside
+    let corners = (0..8)
+        .map(|i| Point::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2) as f32) * 2.0)
+        .map(|p| p - Vector::new(1.0, 1.0, 1.0))
+        .collect::<Vec<_>>();
+    let quads = [
+        [0, 2, 3, 1],
+        [4, 5, 7, 6],
+        [0, 4, 6, 2],
+        [1, 3, 7, 5],
+        [0, 1, 5, 4],
+        [2, 6, 7, 3],
+    ];
+    let cube = |quads: &[[usize; 4]]| {
+        let faces = quads
+            .iter()
+            .flat_map(|&[a, b, c, d]| {
+                IntoIterator::into_iter([[a, b, c], [a, c, d]]).map(|indices| Face {
+                    indices,
+                    material: 0,
+                })
+            })
+            .collect();
+        let material = Material::Subsurface {
+            mean_free_path: 0.1,
+            albedo: ColourFloat::new(0.0, 1.0, 0.9),
+        };
+        Object::Mesh(Mesh::new(
+            corners.clone(),
+            faces,
+            ColourFloat::zero(),
+            vec![material],
+        ))
+    };
+    assert!(!cube(&quads[..5]).is_solid());
+    let mesh = cube(&quads);
+    assert!(mesh.is_solid());
+    let spans = mesh.spans(&Ray::new(Point::new(0.1, 0.2, 0.0), -Vector::unit_z()));
+    assert_eq!(spans.len(), 1);
+    assert_relative_eq!(spans[0].enter.distance, -1.0, epsilon = 1e-4);
+    assert_relative_eq!(spans[0].exit.distance, 1.0, epsilon = 1e-4);
+
+    let mut scene = Scene::new(vec![mesh]);
+    scene.background = Background::Constant(ColourFloat::new(1.0, 1.0, 1.0));
+    let colour = (0..n)
+        .map(|_| trace(ray(), &scene, 2, &mut rng))
+        .sum::<ColourFloat>()
+        / n as f32;
+    assert!(colour.x < 0.05);
+    assert_relative_eq!(colour.y, 0.5, epsilon = 1e-4);
+}
+
+#[test]
+fn test_indexed_mesh() {
+    use crate::mesh::*;
+
+    // Bumpy 8x8 grid of quads, two triangles each, sharing vertices
+    let size = 9;
+    let mut positions = Vec::new();
+    let mut colours = Vec::new();
+    for y in 0..size {
+        for x in 0..size {
+            let height = ((x * 7 + y * 3) % 5) as f32 * 0.2;
+            positions.push(Point::new(x as f32, y as f32, height));
+            colours.push(ColourFloat::new(x as f32 * 10.0, y as f32 * 10.0, 0.0));
+        }
+    }
+    let mut faces = Vec::new();
+    for y in 0..size - 1 {
+        for x in 0..size - 1 {
+            let i = y * size + x;
+            faces.push(Face {
+                indices: [i, i + 1, i + size],
+                material: 0,
+            });
+            faces.push(Face {
+                indices: [i + 1, i + size + 1, i + size],
+                material: 1,
+            });
+        }
+    }
+    let triangles: Vec<Object> = faces
+        .iter()
+        .map(|face| {
+            let [a, b, c] = face.indices;
+            Object::Triangle(Triangle::new(
+                positions[a],
+                positions[b],
+                positions[c],
+                [colours[a], colours[b], colours[c]],
+                Material::Diffuse,
+            ))
+        })
+        .collect();
+    let mut mesh = Mesh::new(
+        positions,
+        faces,
+        ColourFloat::zero(),
+        vec![Material::Diffuse, Material::Lambertian],
+    );
+    mesh.colours = Some(colours);
+    assert_eq!(mesh.positions().len(), 81);
+    assert_eq!(mesh.faces().len(), 128);
+    let mesh = Object::Mesh(mesh);
+
+    // The BVH finds the same hits as testing every triangle
+    let mut rng = Rng::new(3, 0);
+    let mut hits = 0;
+    for _ in 0..500 {
+        let start = Point::new(rng.f32_range(-1.0, 9.0), rng.f32_range(-1.0, 9.0), 5.0);
+        let dir = Vector::new(rng.f32_range(-0.5, 0.5), rng.f32_range(-0.5, 0.5), -1.0).normalize();
+        let ray = Ray::new(start, dir);
+        let expected = closest_intersection(&triangles, &ray);
+        let location = mesh.intersection(&ray);
+        assert_eq!(expected.is_some(), location.is_some());
+        if let (Some((index, expected)), Some(location)) = (expected, location) {
+            hits += 1;
+            assert_eq!(location.primitive, index);
+            assert_relative_eq!(location.distance, expected.distance, epsilon = 1e-4);
+            let position = ray.start + location.distance * ray.dir;
+            let footprint = Footprint::zero();
+            assert_relative_eq!(
+                mesh.get_colour(position, &location, &footprint),
+                triangles[index].get_colour(position, &expected, &footprint),
+                epsilon = 1e-2
+            );
+            assert_relative_eq!(
+                mesh.get_normal(position, &location),
+                triangles[index].get_normal(position, &expected),
+                epsilon = 1e-4
+            );
+            let lambertian = matches!(mesh.get_material(&location), Material::Lambertian);
+            assert_eq!(lambertian, index % 2 == 1);
+        }
+    }
+    assert!(hits > 100);
+
+    // Bad vertices, e.g. from an imported file, don't stop the BVH being built
+    let mut positions = Vec::new();
+    let mut faces = Vec::new();
+    for i in 0..10 {
+        let x = i as f32 * 2.0;
+        positions.push(Point::new(x, 0.0, 0.0));
+        positions.push(Point::new(x + 1.0, 0.0, 0.0));
+        positions.push(Point::new(x, 1.0, 0.0));
+        faces.push(Face {
+            indices: [3 * i, 3 * i + 1, 3 * i + 2],
+            material: 0,
+        });
+    }
+    // Bounds from minus to plus infinity, so centred on NaN
+    positions[3].x = f32::NEG_INFINITY;
+    positions[4].x = f32::INFINITY;
+    let mesh = Object::Mesh(Mesh::new(
+        positions,
+        faces,
+        ColourFloat::zero(),
+        vec![Material::Diffuse],
+    ));
+    let ray = Ray::new(Point::new(10.2, 0.2, 1.0), -Vector::unit_z());
+    let location = mesh.intersection(&ray).unwrap();
+    assert_eq!(location.primitive, 5);
+}
+
+#[test]
+fn test_gltf_import() {
+    use crate::gltf_import;
+    use std::sync::Arc;
+
+    // One red triangle used by two nodes, a camera and a point light
+    let json = r#"{
+        "asset": {"version": "2.0"},
+        "extensionsUsed": ["KHR_lights_punctual"],
+        "extensions": {"KHR_lights_punctual": {"lights": [
+            {"type": "point", "color": [1, 1, 1], "intensity": 10}
+        ]}},
+        "scene": 0,
+        "scenes": [{"nodes": [0, 1, 2]}],
+        "nodes": [
+            {"mesh": 0, "translation": [0, 0, -5], "children": [3]},
+            {"camera": 0, "translation": [0, 0, 5]},
+            {"extensions": {"KHR_lights_punctual": {"light": 0}}, "translation": [0, 4, 0]},
+            {"mesh": 0, "translation": [3, 0, 0]}
+        ],
+        "cameras": [{"type": "perspective", "perspective": {"yfov": 1.5707964, "znear": 0.1}}],
+        "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+        "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0}}],
+        "buffers": [{"byteLength": 44}],
+        "bufferViews": [
+            {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+            {"buffer": 0, "byteOffset": 36, "byteLength": 6}
+        ],
+        "accessors": [
+            {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+             "min": [-1, -1, 0], "max": [1, 1, 0]},
+            {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+        ]
+    }"#;
+    let mut bin = Vec::new();
+    for value in &[-1.0_f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0] {
+        bin.extend_from_slice(&value.to_le_bytes());
+    }
+    for index in &[0_u16, 1, 2, 0] {
+        bin.extend_from_slice(&index.to_le_bytes());
+    }
+    let mut json = json.as_bytes().to_vec();
+    while !json.len().is_multiple_of(4) {
+        json.push(b' ');
+    }
+    let mut glb = Vec::new();
+    glb.extend_from_slice(b"glTF");
+    glb.extend_from_slice(&2_u32.to_le_bytes());
+    glb.extend_from_slice(&((12 + 8 + json.len() + 8 + bin.len()) as u32).to_le_bytes());
+    glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"JSON");
+    glb.extend_from_slice(&json);
+    glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"BIN\0");
+    glb.extend_from_slice(&bin);
+
+    // Given on the command line, a .glb file is read whole
+    let path = std::env::temp_dir().join("raytracer_test_scene.glb");
+    std::fs::write(&path, &glb).unwrap();
+    let (scene, camera) = crate::read_scene(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(scene.graph.objects().len(), 2);
+    assert!(camera.is_some());
+
+    let imported = gltf_import::load_slice(&glb).unwrap();
+    let scene = imported.scene;
+    assert!(scene.objects.is_empty());
+    assert_eq!(scene.graph.nodes().len(), 3);
+    let objects = scene.graph.objects();
+    assert_eq!(objects.len(), 2);
+    match (&objects[0], &objects[1]) {
+        (Object::Instance(a), Object::Instance(b)) => assert!(Arc::ptr_eq(&a.object, &b.object)),
+        _ => panic!("glTF nodes should be instances"),
+    }
+    match scene.lights[..] {
+        [Light::Point { position, colour }] => {
+            assert_relative_eq!(position, Point::new(0.0, 4.0, 0.0));
+            assert_relative_eq!(colour, ColourFloat::new(10.0, 10.0, 10.0));
+        }
+        _ => panic!("Expected one point light"),
+    }
+
+    let camera = imported.camera.unwrap();
+    assert_relative_eq!(camera.location, Point::new(0.0, 0.0, 5.0), epsilon = 1e-4);
+    assert_relative_eq!(camera.focal_length, 1.0, epsilon = 1e-4);
+
+    // Looking straight ahead at the first triangle, the child is offset by its parent
+    for (x, object, path) in [(0.0, 0, "node0"), (3.0, 1, "node0/node3")].iter() {
+        let ray = Ray::new(Point::new(*x, 0.0, 5.0), -Vector::unit_z());
+        let i = scene.closest_intersection(&ray).unwrap();
+        assert_relative_eq!(i.location.distance, 10.0, epsilon = 1e-4);
+        assert!(std::ptr::eq(i.object, &objects[*object]));
+        assert_eq!(scene.graph.path(i.object), Some(*path));
+        assert!(matches!(
+            i.object.get_material(&i.location),
+            Material::Lambertian
+        ));
+        let position = ray.start + i.location.distance * ray.dir;
+        assert_relative_eq!(
+            i.object
+                .get_colour(position, &i.location, &Footprint::zero()),
+            ColourFloat::new(255.0, 0.0, 0.0)
+        );
+    }
+
+    // Rendered, each triangle is lit by the point light on top of the sky
+    let render = |scene: &Scene, x: f32| {
+        let ray = Ray::new(Point::new(x, 0.0, 5.0), -Vector::unit_z());
+        trace(ray, scene, 1, &mut Rng::new(1, 0))
+    };
+    let lit = [render(&scene, 0.0), render(&scene, 3.0)];
+    let mut scene = scene;
+    scene.lights.clear();
+    for (x, lit) in [0.0_f32, 3.0].iter().zip(lit.iter()) {
+        let to_light = Vector::new(-x, 4.0, 5.0);
+        let cos = to_light.normalize().z;
+        let direct = 0.5 * 10.0 / to_light.magnitude2() * cos / std::f32::consts::PI;
+        let unlit = render(&scene, *x);
+        assert_relative_eq!(
+            lit - unlit,
+            ColourFloat::new(direct, direct, direct),
+            max_relative = 1e-2
+        );
+    }
+}
+
+#[test]
+fn test_ply_and_stl_import() {
+    use crate::mesh_import;
+
+    // A unit square facing +z, split into two triangles, with a colour per corner
+    let corners = [
+        ([0.0_f32, 0.0, 0.0], [255_u8, 0, 0]),
+        ([1.0, 0.0, 0.0], [0, 255, 0]),
+        ([1.0, 1.0, 0.0], [0, 0, 255]),
+        ([0.0, 1.0, 0.0], [255, 255, 255]),
+    ];
+    let header = |format: &str| {
+        format!(
+            "ply\nformat {} 1.0\ncomment test square\nelement vertex 4\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format
+        )
+    };
+    let mut ascii = header("ascii");
+    for ([x, y, z], [r, g, b]) in corners.iter() {
+        ascii += &format!("{} {} {} {} {} {}\n", x, y, z, r, g, b);
+    }
+    ascii += "4 0 1 2 3\n";
+    let mut binary = header("binary_big_endian").into_bytes();
+    for (position, colour) in corners.iter() {
+        for value in position {
+            binary.extend_from_slice(&value.to_be_bytes());
+        }
+        binary.extend_from_slice(colour);
+    }
+    binary.push(4);
+    for index in 0_i32..4 {
+        binary.extend_from_slice(&index.to_be_bytes());
+    }
+
+    let material = || Material::Lambertian;
+    let plys = [ascii.into_bytes(), binary];
+    for bytes in plys.iter() {
+        let mesh = mesh_import::read_ply(bytes, ColourFloat::zero(), material()).unwrap();
+        assert_eq!(mesh.positions().len(), 4);
+        assert_eq!(mesh.faces().len(), 2);
+
+        // The centre of the first triangle blends the colours of its corners
+        let centre = Point::new(2.0 / 3.0, 1.0 / 3.0, 0.0);
+        let ray = Ray::new(centre + Vector::unit_z(), -Vector::unit_z());
+        let i = mesh.intersection(&ray).unwrap();
+        assert_relative_eq!(i.distance, 1.0, epsilon = 1e-5);
+        assert_relative_eq!(
+            mesh.get_colour(centre, &i, &Footprint::zero()),
+            ColourFloat::new(85.0, 85.0, 85.0),
+            epsilon = 1e-3
+        );
+    }
+
+    let facets = [[0, 1, 2], [0, 2, 3]];
+    let mut ascii = String::from("solid square\n");
+    let mut binary = vec![0_u8; 80];
+    binary.extend_from_slice(&(facets.len() as u32).to_le_bytes());
+    for facet in facets.iter() {
+        ascii += "facet normal 0 0 1\nouter loop\n";
+        binary.extend_from_slice(&[0; 12]);
+        for &corner in facet {
+            let [x, y, z] = corners[corner].0;
+            ascii += &format!("vertex {} {} {}\n", x, y, z);
+            for value in &[x, y, z] {
+                binary.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        ascii += "endloop\nendfacet\n";
+        binary.extend_from_slice(&[0; 2]);
+    }
+    ascii += "endsolid square\n";
+
+    let stls = [ascii.into_bytes(), binary];
+    for bytes in stls.iter() {
+        let mesh =
+            mesh_import::read_stl(bytes, ColourFloat::new(0.0, 0.0, 255.0), material()).unwrap();
+        // Shared corners are welded together
+        assert_eq!(mesh.positions().len(), 4);
+        assert_eq!(mesh.faces().len(), 2);
+        let ray = Ray::new(Point::new(0.25, 0.75, 1.0), -Vector::unit_z());
+        assert!(mesh.intersection(&ray).is_some());
+    }
+
+    assert!(
+        mesh_import::read_ply(b"ply\nformat ascii 1.0\n", ColourFloat::zero(), material()).is_err()
+    );
+    assert!(mesh_import::read_stl(b"not a mesh", ColourFloat::zero(), material()).is_err());
+
+    // Given on the command line, each is loaded as a scene of one mesh named after its file
+    for (name, bytes) in [("square.ply", &plys[0]), ("square.stl", &stls[1])].iter() {
+        let path = std::env::temp_dir().join(format!("raytracer_test_{}", name));
+        std::fs::write(&path, bytes).unwrap();
+        let (scene, camera) = crate::read_scene(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(camera.is_none());
+        match scene.graph.objects() {
+            [object @ Object::Instance(ref instance)] => match *instance.object {
+                Object::Mesh(ref mesh) => {
+                    assert_eq!(mesh.faces().len(), 2);
+                    assert_eq!(scene.graph.path(object), Some("raytracer_test_square"));
+                }
+                _ => panic!("Expected {} to load as a mesh", name),
+            },
+            _ => panic!("Expected {} to load as one node", name),
+        }
+    }
+}
+
+#[test]
+fn test_scene_file_round_trip() {
+    use crate::csg::*;
+    use crate::instance::*;
+    use crate::medium::*;
+    use crate::primitives::*;
+    use crate::scene_file;
+    use crate::sky::Sky;
+    use std::sync::Arc;
+
+    let mut scene = crate::cornell_box::get_scene();
+    let mut torus = Torus::new(
+        Point::new(0.0, 2.0, -3.0),
+        Vector::new(0.2, 1.0, 0.0),
+        1.5,
+        0.25,
+        ColourFloat::new(200.0, 100.0, 0.0),
+        Material::Subsurface {
+            mean_free_path: 0.1,
+            albedo: ColourFloat::new(0.9, 0.8, 0.7),
+        },
+    );
+    torus.texture = Some(Texture::Mix {
+        a: Box::new(Texture::Solid(ColourFloat::new(255.0, 0.0, 0.0))),
+        b: Box::new(Texture::Checker {
+            even: Box::new(Texture::Solid(ColourFloat::zero())),
+            odd: Box::new(Texture::Marble {
+                scale: 2.0,
+                turbulence: 5.0,
+                octaves: 4,
+                space: TextureSpace::World,
+            }),
+            scale: 0.5,
+            space: TextureSpace::Uv,
+        }),
+        factor: Box::new(Texture::Wood {
+            scale: 3.0,
+            turbulence: 0.5,
+            space: TextureSpace::Uv,
+        }),
+    });
+    let drilled = Csg::new(
+        CsgOperation::Difference,
+        Object::Cuboid(Cuboid::axis_aligned(
+            Point::new(-1.0, -1.0, -1.0),
+            Point::new(1.0, 1.0, 1.0),
+            ColourFloat::new(255.0, 255.0, 255.0),
+            Material::Lambertian,
+        )),
+        Object::Cylinder(Cylinder::new(
+            Point::new(0.0, -2.0, 0.0),
+            Vector::unit_y(),
+            0.5,
+            4.0,
+            ColourFloat::new(255.0, 255.0, 255.0),
+            Material::Lambertian,
+        )),
+    );
+    let transform = Transform::from_translation(Vector::new(4.0, -6.0, -2.0))
+        * Transform::from_angle_y(Deg(30.0));
+    scene.objects.push(Object::Torus(torus));
+    scene.objects.push(Object::Instance(Instance::new(
+        Arc::new(Object::Csg(drilled)),
+        transform,
+    )));
+    scene.set_sky(Sky::new(Deg(30.0), Deg(45.0), 3.0, 0.1), 2.0);
+    scene.lights.push(Light::Point {
+        position: Point::new(0.0, 9.0, 0.0),
+        colour: ColourFloat::new(50.0, 50.0, 50.0),
+    });
+    scene.fog = Some(Medium::homogeneous(0.01, 0.02, 0.3));
+    let mut smoke = Medium::homogeneous(0.1, 0.5, 0.0);
+    smoke.density = Density::Grid(VoxelGrid::from_fn(
+        Point::new(-1.0, -1.0, -1.0),
+        Point::new(1.0, 1.0, 1.0),
+        [2, 3, 4],
+        |p| p.y + 1.0,
+    ));
+    scene.volumes.push(Volume::new(
+        Object::Sphere(Sphere {
+            centre: Point::new(0.0, 0.0, 0.0),
+            radius: 1.0,
+            colour: ColourFloat::zero(),
+            texture: None,
+            material: Material::Lambertian,
+        }),
+        smoke,
+    ));
+
+    // Navigate the way the viewer's arrow keys do
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let mut visualiser = Visualiser::new(10, 10, camera);
+    visualiser.rotate(20.0);
+    visualiser.dolly(3.0);
+    let camera = visualiser.camera;
+
+    let text = scene_file::write(&scene, Some(&camera)).unwrap();
+    let (loaded, loaded_camera) = scene_file::read(&text).unwrap();
+    // Writing what was read gives the same file back
+    assert_eq!(
+        scene_file::write(&loaded, loaded_camera.as_ref()).unwrap(),
+        text
+    );
+
+    let loaded_camera = loaded_camera.unwrap();
+    assert_relative_eq!(loaded_camera.location, camera.location);
+    assert_relative_eq!(loaded_camera.yaw.0, camera.yaw.0);
+    assert_relative_eq!(loaded_camera.rotation_matrix, camera.rotation_matrix);
+
+    assert_eq!(loaded.objects.len(), scene.objects.len());
+    assert_eq!(loaded.lights.len(), 2);
+    assert_eq!(loaded.volumes.len(), 1);
+    assert!(loaded.fog.is_some());
+
+    // The same rays hit the same surfaces at the same distances
+    for i in 0..50 {
+        let dir = Vector::new((i as f32 * 0.37).sin(), (i as f32 * 0.61).cos(), -1.0).normalize();
+        let ray = Ray::new(Point::new(0.5, 0.3, 8.0), dir);
+        let expected = scene.closest_intersection(&ray);
+        let actual = loaded.closest_intersection(&ray);
+        assert_eq!(expected.is_some(), actual.is_some());
+        if let (Some(expected), Some(actual)) = (expected, actual) {
+            assert_relative_eq!(
+                expected.location.distance,
+                actual.location.distance,
+                epsilon = 1e-4
+            );
+        }
+    }
+
+    let error = scene_file::read("object sphere {\n    centre 0 0 0\n    radius one\n}")
+        .err()
+        .unwrap();
+    assert!(error.to_string().starts_with("line 3:"));
+    let empty_range = "object sphere {\n    centre 0 0 0\n    radius 1\n    colour 1 1 1\n    \
+                       material diffuse\n    texture remap {\n        input noise { scale 1 }\n        \
+                       from 0.5 0.5\n        to 0 1\n    }\n}";
+    let error = scene_file::read(empty_range).err().unwrap();
+    assert!(error.to_string().contains("remap ranges can't be empty"));
+    let empty_grid = "volume {\n    boundary sphere {\n        centre 0 0 0\n        radius 1\n        \
+                      colour 0 0 0\n        material lambertian\n    }\n    medium {\n        \
+                      absorption 0\n        scattering 1\n        g 0\n        grid {\n            \
+                      min -1 -1 -1\n            max 1 1 1\n            resolution 0 2 2\n            \
+                      densities { }\n        }\n    }\n}";
+    let error = scene_file::read(empty_grid).err().unwrap();
+    assert!(error
+        .to_string()
+        .contains("grid resolutions must be at least 1"));
+
+    // Axis-aligned boxes can be read from their corners, and are written out oriented
+    let corners = "object cuboid {\n    min -1 -2 -3\n    max 1 2 3\n    colour 1 1 1\n    \
+                   material diffuse\n}";
+    let (loaded, _) = scene_file::read(corners).unwrap();
+    match loaded.objects[..] {
+        [Object::Cuboid(ref c)] => {
+            assert_relative_eq!(c.frame().origin, Point::new(0.0, 0.0, 0.0));
+            assert_relative_eq!(c.half_extents, Vector::new(1.0, 2.0, 3.0));
+        }
+        _ => panic!("Expected a single cuboid"),
+    }
+    let text = scene_file::write(&loaded, None).unwrap();
+    assert!(text.contains("half_extents 1 2 3"));
+
+    // Gradients keep the space they're laid out in
+    let uv_gradient = "object sphere {\n    centre 0 0 0\n    radius 1\n    colour 1 1 1\n    \
+                       material diffuse\n    texture gradient {\n        origin 0 0 0\n        \
+                       direction 1 0 0\n        space uv\n    }\n}\n";
+    let (loaded, _) = scene_file::read(uv_gradient).unwrap();
+    assert!(scene_file::write(&loaded, None)
+        .unwrap()
+        .ends_with(uv_gradient));
+
+    // Voxel grids can be filled from a texture, and are written out voxel by voxel
+    let textured_grid = empty_grid.replace(
+        "resolution 0 2 2\n            densities { }",
+        "resolution 2 1 1\n            density gradient {\n                \
+         origin -1 0 0\n                direction 2 0 0\n            }",
+    );
+    let (loaded, _) = scene_file::read(&textured_grid).unwrap();
+    match loaded.volumes[0].medium.density {
+        Density::Grid(ref grid) => assert_eq!(grid.densities(), &[0.25, 0.75]),
+        _ => panic!("Expected a voxel grid"),
+    }
+    let text = scene_file::write(&loaded, None).unwrap();
+    assert!(text.contains("0.25 0.75"));
+}
+
+#[test]
+fn test_scene_graph() {
+    use crate::instance::Transform;
+    use crate::primitives::*;
+    use crate::scene_file;
+    use crate::scene_graph::*;
+
+    let white = ColourFloat::new(255.0, 255.0, 255.0);
+    let mut table = Node::new("table");
+    table.transform = Transform::from_translation(Vector::new(0.0, 0.0, -10.0));
+    table.children.push(Node::with_object(
+        "top",
+        Object::Cuboid(Cuboid::axis_aligned(
+            Point::new(-2.0, 0.9, -1.0),
+            Point::new(2.0, 1.0, 1.0),
+            white,
+            Material::Lambertian,
+        )),
+    ));
+    let mut leg = Node::with_object(
+        "leg",
+        Object::Cylinder(Cylinder::new(
+            Point::zero(),
+            Vector::unit_y(),
+            0.1,
+            0.9,
+            white,
+            Material::Lambertian,
+        )),
+    );
+    leg.transform = Transform::from_translation(Vector::new(1.5, 0.0, 0.0));
+    table.children.push(leg);
+
+    let mut scene = Scene::new(vec![Object::Sphere(Sphere {
+        centre: Point::new(0.0, 5.0, -10.0),
+        radius: 1.0,
+        colour: white,
+        texture: None,
+        material: Material::Lambertian,
+    })]);
+    scene.graph.add(table);
+    assert_eq!(scene.graph.objects().len(), 2);
+    assert!(scene.graph.find("leg").is_some());
+    assert!(scene.graph.find("chair").is_none());
+
+    // Picking names the node hit, children being placed by their parents
+    let pick = |scene: &Scene, start: Point| {
+        let ray = Ray::new(start, -Vector::unit_z());
+        scene.closest_intersection(&ray).map(|i| {
+            (
+                i.location.distance,
+                scene.graph.path(i.object).map(String::from),
+            )
+        })
+    };
+    let (distance, path) = pick(&scene, Point::new(1.5, 0.5, 0.0)).unwrap();
+    assert_relative_eq!(distance, 9.9, epsilon = 1e-4);
+    assert_eq!(path.as_deref(), Some("table/leg"));
+    let (_, path) = pick(&scene, Point::new(0.0, 0.95, 0.0)).unwrap();
+    assert_eq!(path.as_deref(), Some("table/top"));
+    // Loose objects are still hit, but have no name
+    let (_, path) = pick(&scene, Point::new(0.0, 5.0, 0.0)).unwrap();
+    assert_eq!(path, None);
+
+    let text = scene_file::write(&scene, None).unwrap();
+    let (loaded, _) = scene_file::read(&text).unwrap();
+    assert_eq!(loaded.graph.objects().len(), 2);
+    let (distance, path) = pick(&loaded, Point::new(1.5, 0.5, 0.0)).unwrap();
+    assert_relative_eq!(distance, 9.9, epsilon = 1e-4);
+    assert_eq!(path.as_deref(), Some("table/leg"));
+
+    // Moving the table moves its legs too
+    assert!(scene.graph.edit("table", |table| {
+        table.transform = Transform::from_translation(Vector::new(0.0, 0.0, -20.0));
+    }));
+    let (distance, _) = pick(&scene, Point::new(1.5, 0.5, 0.0)).unwrap();
+    assert_relative_eq!(distance, 19.9, epsilon = 1e-4);
+
+    // Hiding the leg leaves the rest of the table
+    assert!(scene.graph.edit("leg", |leg| leg.visible = false));
+    assert_eq!(scene.graph.objects().len(), 1);
+    assert!(pick(&scene, Point::new(1.5, 0.5, 0.0)).is_none());
+    assert!(scene.graph.edit("table", |table| table.visible = false));
+    assert!(scene.graph.objects().is_empty());
+    assert!(!scene.graph.edit("chair", |chair| chair.visible = false));
+
+    // Edits under a hidden node place nothing until it's shown again
+    let paths = |scene: &Scene| {
+        let graph = &scene.graph;
+        graph
+            .objects()
+            .iter()
+            .map(|object| graph.path(object).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert!(scene.graph.edit("leg", |leg| leg.visible = true));
+    assert!(scene.graph.objects().is_empty());
+    let mut lamp = Node::new("lamp");
+    lamp.children.push(Node::with_object(
+        "leg",
+        Object::Sphere(Sphere {
+            centre: Point::zero(),
+            radius: 0.5,
+            colour: white,
+            texture: None,
+            material: Material::Lambertian,
+        }),
+    ));
+    scene.graph.add(lamp);
+    assert_eq!(paths(&scene), ["lamp/leg"]);
+    assert!(scene.graph.edit("table", |table| table.visible = true));
+    assert_eq!(paths(&scene), ["table/top", "table/leg", "lamp/leg"]);
+    let (distance, path) = pick(&scene, Point::new(1.5, 0.5, 0.0)).unwrap();
+    assert_relative_eq!(distance, 19.9, epsilon = 1e-4);
+    assert_eq!(path.as_deref(), Some("table/leg"));
+
+    // Names find the first node depth first, and follow renames
+    assert!(scene.graph.edit("leg", |leg| leg.visible = false));
+    assert_eq!(paths(&scene), ["table/top", "lamp/leg"]);
+    assert!(scene
+        .graph
+        .edit("lamp", |lamp| lamp.name = "light".to_string()));
+    assert!(scene.graph.find("lamp").is_none());
+    assert_eq!(scene.graph.find("light").unwrap().children.len(), 1);
+    assert_eq!(paths(&scene), ["table/top", "light/leg"]);
+    assert!(scene.graph.edit("table", |table| table.children.clear()));
+    assert!(scene.graph.edit("leg", |leg| {
+        leg.transform = Transform::from_translation(Vector::new(0.0, 0.0, -3.0))
+    }));
+    assert_eq!(paths(&scene), ["light/leg"]);
+    let (distance, path) = pick(&scene, Point::zero()).unwrap();
+    assert_relative_eq!(distance, 2.5, epsilon = 1e-4);
+    assert_eq!(path.as_deref(), Some("light/leg"));
+    let loose = &scene.objects[0];
+    assert_eq!(scene.graph.path(loose), None);
+}
+
+#[test]
+fn test_seeded_render() {
+    // The reference PCG32 sequence for seed 42 on stream 54
+    let mut rng = Rng::new(42, 54);
+    let expected = [
+        0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e,
+    ];
+    for &value in expected.iter() {
+        assert_eq!(rng.next_u32(), value);
+    }
+    let mut rng = Rng::new(1, 0);
+    for _ in 0..1000 {
+        let u = rng.f32();
+        assert!((0.0..1.0).contains(&u));
+    }
+
+    // The same seed gives a bit-identical image however many threads render it
+    let scene = crate::cornell_box::get_scene();
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let visualiser = Visualiser::new(12, 16, camera);
+    let render = |seed, threads| {
+        crate::draw::render(
+            &visualiser,
+            &scene,
+            &crate::draw::RenderSettings {
+                seed,
+                threads,
+                ..Default::default()
+            },
+        )
+        .colours
+    };
+    let image = render(7, 1);
+    assert_eq!(image.len(), 12 * 16);
+    for &threads in [1, 3, 5].iter() {
+        let other = render(7, threads);
+        assert!(image
+            .iter()
+            .zip(other.iter())
+            .all(|(a, b)| a.map(f32::to_bits) == b.map(f32::to_bits)));
+    }
+    assert!(image != render(8, 3));
+}
+
+#[test]
+fn test_samplers() {
+    use crate::sampler::SamplerKind;
+
+    // Error estimating integrals over each pixel's samples, in the dimensions taken
+    // first like a camera ray's and in later ones
+    let samples = 64;
+    let rms_error = |kind: SamplerKind| {
+        let mut sampler = kind.create(5, samples);
+        let mut squared_errors = [0.0; 3];
+        for pixel in 0..256 {
+            let mut sums = [0.0; 3];
+            for index in 0..samples {
+                sampler.start(pixel % 16, pixel / 16, index);
+                let [a, b] = sampler.get_2d();
+                let u = sampler.get_1d();
+                let [c, d] = sampler.get_2d();
+                for &value in [u, a, b, c, d].iter() {
+                    assert!((0.0..1.0).contains(&value));
+                }
+                sums[0] += u * u;
+                sums[1] += if (a - 0.5).powi(2) + (b - 0.5).powi(2) < 0.16 {
+                    1.0
+                } else {
+                    0.0
+                };
+                sums[2] += (c * 7.0).sin() * d;
+            }
+            let exact = [
+                1.0 / 3.0,
+                0.16 * std::f32::consts::PI,
+                (1.0 - 7.0_f32.cos()) / 14.0,
+            ];
+            for i in 0..3 {
+                squared_errors[i] += (sums[i] / samples as f32 - exact[i]).powi(2);
+            }
+        }
+        squared_errors.map(|e| (e / 256.0).sqrt())
+    };
+    let independent = rms_error(SamplerKind::Independent);
+    for &kind in [
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ]
+    .iter()
+    {
+        let error = rms_error(kind);
+        for i in 0..3 {
+            assert!(error[i] < 0.6 * independent[i], "{:?}", kind);
+        }
+    }
+
+    // The same sample gives the same numbers, whenever it's taken
+    let mut sampler = SamplerKind::Sobol.create(1, samples);
+    sampler.start(3, 4, 5);
+    let first = (sampler.get_1d(), sampler.get_2d());
+    sampler.start(0, 0, 0);
+    sampler.get_2d();
+    sampler.start(3, 4, 5);
+    assert_eq!(first, (sampler.get_1d(), sampler.get_2d()));
+    // Skipping dimensions leaves a sampler where drawing them would
+    for &kind in [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ]
+    .iter()
+    {
+        let mut sampler = kind.create(2, samples);
+        sampler.start(1, 2, 3);
+        sampler.get_2d();
+        sampler.get_2d();
+        let drawn = sampler.get_2d();
+        sampler.start(1, 2, 3);
+        sampler.skip_2d(2);
+        assert_eq!(drawn, sampler.get_2d(), "{:?}", kind);
+    }
+    assert_eq!("blue-noise".parse(), Ok(SamplerKind::BlueNoise));
+    assert!("random".parse::<SamplerKind>().is_err());
+
+    // A lens blurs what's out of focus, but not what's in it
+    let scene = crate::cornell_box::get_scene();
+    let mut camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    camera.aperture = 0.5;
+    camera.focus_distance = 10.0;
+    let visualiser = Visualiser::new(12, 16, camera);
+    let mut sampler = SamplerKind::Sobol.create(1, samples);
+    let mut spread = 0.0_f32;
+    for index in 0..samples {
+        sampler.start(8, 6, index);
+        let [u, v] = sampler.get_2d();
+        let ray = visualiser.sample_camera_ray([8.0 + u, 6.0 + v], samples, sampler.as_mut());
+        spread = spread.max(ray.start.distance(visualiser.camera.location));
+        // Where it crosses the plane in focus, seen through the pinhole, is in the pixel
+        let target = ray.start + ray.dir * (10.0 / -ray.dir.z);
+        let screen = (target - visualiser.camera.location) / 10.0;
+        assert!((0.0625..0.1875).contains(&screen.x));
+        assert!((-0.25..-0.0833).contains(&screen.y));
+    }
+    assert!(spread > 0.1 && spread <= 0.5);
+    let (_, camera) =
+        scene_file::read(&scene_file::write(&scene, Some(&visualiser.camera)).unwrap()).unwrap();
+    assert_relative_eq!(camera.unwrap().aperture, 0.5);
+}
+
+/// Pearson's chi-squared statistic for `values` in [0, 1) being uniform over `bins`
+fn chi_squared(values: &[f32], bins: usize) -> f32 {
+    let mut counts = vec![0; bins];
+    for &value in values.iter() {
+        counts[((value * bins as f32) as usize).min(bins - 1)] += 1;
+    }
+    let expected = values.len() as f32 / bins as f32;
+    counts
+        .iter()
+        .map(|&count| (count as f32 - expected).powi(2) / expected)
+        .sum()
+}
+
+#[test]
+fn test_sampling() {
+    use crate::sampling::*;
+    use std::f32::consts::PI;
+
+    // With 16 bins, a uniform distribution only exceeds this one time in a thousand
+    let critical = 37.7;
+    let count = 20000;
+    let mut rng = Rng::new(4, 0);
+    let mut samples = || {
+        (0..count)
+            .map(|_| [rng.f32(), rng.f32()])
+            .collect::<Vec<_>>()
+    };
+    let azimuth = |dir: Vector, x: Vector, z: Vector| {
+        (dir.dot(z).atan2(dir.dot(x)) / (2.0 * PI)).rem_euclid(1.0)
+    };
+    // Averaging 1 / pdf over the samples estimates the size of what's sampled
+    let measure = |pdfs: &[f32]| pdfs.iter().map(|pdf| 1.0 / pdf).sum::<f32>() / pdfs.len() as f32;
+
+    // Heights on the sphere are uniform, as are angles around it
+    let sphere: Vec<_> = samples().into_iter().map(uniform_sphere).collect();
+    let heights: Vec<_> = sphere.iter().map(|(dir, _)| (dir.z + 1.0) / 2.0).collect();
+    let angles: Vec<_> = sphere
+        .iter()
+        .map(|&(dir, _)| azimuth(dir, Vector::unit_x(), Vector::unit_y()))
+        .collect();
+    assert!(chi_squared(&heights, 16) < critical);
+    assert!(chi_squared(&angles, 16) < critical);
+    let pdfs: Vec<_> = sphere.iter().map(|&(_, pdf)| pdf).collect();
+    assert_relative_eq!(measure(&pdfs), 4.0 * PI, max_relative = 1e-3);
+
+    let normal = Vector::new(1.0, 2.0, -0.5).normalize();
+    let frame = crate::primitives::Frame::new(Point::zero(), normal);
+    for &(cosine, ref hemisphere) in [
+        (
+            false,
+            samples()
+                .into_iter()
+                .map(|s| uniform_hemisphere(normal, s))
+                .collect::<Vec<_>>(),
+        ),
+        (
+            true,
+            samples()
+                .into_iter()
+                .map(|s| cosine_hemisphere(normal, s))
+                .collect(),
+        ),
+    ]
+    .iter()
+    {
+        // cos(theta) is uniform over the hemisphere, and its square for cosine weighting
+        let cos_thetas: Vec<_> = hemisphere
+            .iter()
+            .map(|(dir, _)| {
+                let cos_theta = dir.dot(normal);
+                assert!(cos_theta >= 0.0);
+                assert_relative_eq!(dir.magnitude(), 1.0, epsilon = 1e-4);
+                if cosine {
+                    cos_theta * cos_theta
+                } else {
+                    cos_theta
+                }
+            })
+            .collect();
+        assert!(chi_squared(&cos_thetas, 16) < critical);
+        let angles: Vec<_> = hemisphere
+            .iter()
+            .map(|&(dir, _)| azimuth(dir, frame.x, frame.z))
+            .collect();
+        assert!(chi_squared(&angles, 16) < critical);
+        for &(dir, pdf) in hemisphere.iter().take(100) {
+            let expected = if cosine {
+                cosine_hemisphere_pdf(normal, dir)
+            } else {
+                uniform_hemisphere_pdf(normal, dir)
+            };
+            assert_relative_eq!(pdf, expected);
+        }
+        let pdfs: Vec<_> = hemisphere.iter().map(|&(_, pdf)| pdf).collect();
+        assert_relative_eq!(measure(&pdfs), 2.0 * PI, max_relative = 0.02);
+    }
+
+    // Squared radii on the disc are uniform, as are angles around it
+    let disc: Vec<_> = samples().into_iter().map(concentric_disc).collect();
+    let radii: Vec<_> = disc.iter().map(|([x, y], _)| x * x + y * y).collect();
+    let angles: Vec<_> = disc
+        .iter()
+        .map(|&([x, y], _)| azimuth(Vector::new(x, 0.0, y), Vector::unit_x(), Vector::unit_z()))
+        .collect();
+    assert!(radii.iter().all(|&r| r <= 1.0 + 1e-6));
+    assert!(chi_squared(&radii, 16) < critical);
+    assert!(chi_squared(&angles, 16) < critical);
+    assert_relative_eq!(disc[0].1, 1.0 / PI);
+
+    // Cosines within the cone are uniform
+    let cos_max = 0.8;
+    let cone: Vec<_> = samples()
+        .into_iter()
+        .map(|s| uniform_cone(normal, cos_max, s))
+        .collect();
+    let cos_thetas: Vec<_> = cone
+        .iter()
+        .map(|(dir, _)| (dir.dot(normal) - cos_max) / (1.0 - cos_max))
+        .collect();
+    assert!(cos_thetas
+        .iter()
+        .all(|&c| (-1e-4..=1.0 + 1e-4).contains(&c)));
+    assert!(chi_squared(&cos_thetas, 16) < critical);
+    assert_relative_eq!(cone[0].1, uniform_cone_pdf(cos_max));
+    assert_relative_eq!(1.0 / cone[0].1, 2.0 * PI * (1.0 - cos_max));
+
+    // Any line through a vertex splits the triangle into parts in proportion to their
+    // areas, so the distance across from the vertex, squared, is uniform
+    let (v0, v1, v2) = (
+        Point::new(0.0, 0.0, 0.0),
+        Point::new(4.0, 0.0, 0.0),
+        Point::new(1.0, 3.0, 0.0),
+    );
+    let triangle: Vec<_> = samples()
+        .into_iter()
+        .map(|s| uniform_triangle(v0, v1, v2, s))
+        .collect();
+    let weights: Vec<_> = triangle
+        .iter()
+        .map(|&(point, [b1, b2], pdf)| {
+            assert_relative_eq!(point, v0 + b1 * (v1 - v0) + b2 * (v2 - v0), epsilon = 1e-4);
+            assert_relative_eq!(pdf, 1.0 / 6.0);
+            assert!(b1 >= 0.0 && b2 >= 0.0 && b1 + b2 <= 1.0 + 1e-6);
+            (b1 + b2).powi(2)
+        })
+        .collect();
+    assert!(chi_squared(&weights, 16) < critical);
+    let split: Vec<_> = triangle
+        .iter()
+        .map(|&(_, [b1, b2], _)| b2 / (b1 + b2))
+        .collect();
+    assert!(chi_squared(&split, 16) < critical);
+}
+
+#[test]
+fn test_filters() {
+    use crate::film::{Film, Filter, Region};
+
+    let names = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+    let filters: Vec<Filter> = names.iter().map(|name| name.parse().unwrap()).collect();
+    assert!("sinc".parse::<Filter>().is_err());
+    for (filter, name) in filters.iter().zip(names.iter()) {
+        assert_eq!(filter.name(), *name);
+        assert!(filter.eval(0.0, 0.0) > 0.0);
+        assert_eq!(filter.eval(filter.radius() + 0.01, 0.0), 0.0);
+        assert_eq!(filter.eval(0.0, -filter.radius()), 0.0);
+        let wider = filter.with_radius(2.0 * filter.radius());
+        assert_relative_eq!(wider.radius(), 2.0 * filter.radius());
+        if *name == "lanczos" {
+            continue;
+        }
+        assert_relative_eq!(
+            wider.eval(0.5, 0.25) / wider.eval(0.0, 0.0),
+            filter.eval(0.25, 0.125) / filter.eval(0.0, 0.0),
+            epsilon = 1e-4
+        );
+    }
+    // Mitchell-Netravali and Lanczos dip below zero to sharpen edges
+    assert!(filters[3].eval(1.5, 0.0) < 0.0);
+    assert!(filters[4].eval(1.5, 0.0) < 0.0);
+
+    // Samples of one colour give that colour whatever the filter
+    let mut rng = Rng::new(6, 0);
+    let grey = ColourFloat::new(0.5, 0.5, 0.5);
+    for filter in filters.iter() {
+        let mut film = Film::new(6, 5, *filter);
+        for y in 0..5 {
+            for x in 0..6 {
+                for _ in 0..16 {
+                    film.splat([x as f32 + rng.f32(), y as f32 + rng.f32()], grey);
+                }
+            }
+        }
+        for colour in film.colours() {
+            assert_relative_eq!(colour, grey, epsilon = 1e-4);
+        }
+    }
+
+    // A box half a pixel wide just averages each pixel's own samples, even ones on the
+    // edge between two pixels
+    let mut film = Film::new(2, 1, filters[0]);
+    film.splat([0.0, 0.5], ColourFloat::new(1.0, 0.0, 0.0));
+    film.splat([0.9, 0.2], ColourFloat::new(0.0, 1.0, 0.0));
+    film.splat([1.0, 0.5], ColourFloat::new(0.0, 0.0, 4.0));
+    assert_eq!(
+        film.colours(),
+        vec![
+            ColourFloat::new(0.5, 0.5, 0.0),
+            ColourFloat::new(0.0, 0.0, 4.0)
+        ]
+    );
+
+    // Wider filters reach into the neighbouring pixels, and films of regions of an image
+    // merge into the same as one of all of it
+    let gaussian = filters[2];
+    let white = ColourFloat::new(1.0, 1.0, 1.0);
+    let mut whole = Film::new(8, 8, gaussian);
+    let mut merged = Film::new(8, 8, gaussian);
+    for y in 0..8 {
+        let row = Region {
+            x: 0,
+            y,
+            width: 8,
+            height: 1,
+        };
+        let mut part = Film::reached_from(8, 8, row, gaussian);
+        assert!(part.region.contains(0, y.saturating_sub(2)));
+        assert!(!part.region.contains(0, y + 3));
+        for x in 0..8 {
+            let position = [x as f32 + 0.5, y as f32 + 0.5];
+            let colour = if (x, y) == (4, 4) { white } else { grey };
+            whole.splat(position, colour);
+            part.splat(position, colour);
+        }
+        merged.merge(&part);
+    }
+    let colours = whole.colours();
+    for (a, b) in colours.iter().zip(merged.colours().iter()) {
+        assert_relative_eq!(a, b, epsilon = 1e-6);
+    }
+    assert!(colours[4 * 8 + 4].x < 1.0);
+    assert!(colours[4 * 8 + 5].x > 0.5);
+    assert!(colours[3 * 8 + 4].x > 0.5);
+    assert_relative_eq!(colours[4 * 8 + 6].x, 0.5);
+}
+
+#[test]
+fn test_adaptive_sampling() {
+    use crate::draw::{render, save_heatmap, RenderSettings};
+    use std::time::Duration;
+
+    let scene = crate::cornell_box::get_scene();
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let visualiser = Visualiser::new(12, 16, camera);
+    let settings = |threads, noise_threshold| RenderSettings {
+        threads,
+        samples: 4,
+        max_samples: 32,
+        noise_threshold,
+        ..Default::default()
+    };
+
+    // Noisy pixels get more samples than quiet ones, within the limits
+    let rendered = render(&visualiser, &scene, &settings(1, 2.0));
+    let (fewest, most) = (
+        *rendered.samples.iter().min().unwrap(),
+        *rendered.samples.iter().max().unwrap(),
+    );
+    assert!(fewest >= 4 && most <= 32 && fewest < most);
+    assert!(rendered.samples.iter().all(|&count| count % 4 == 0));
+
+    // Which pixels stop depends only on their own samples
+    let threaded = render(&visualiser, &scene, &settings(3, 2.0));
+    assert_eq!(rendered.samples, threaded.samples);
+    assert!(rendered
+        .colours
+        .iter()
+        .zip(threaded.colours.iter())
+        .all(|(a, b)| a.map(f32::to_bits) == b.map(f32::to_bits)));
+
+    // With no threshold only pixels whose samples all agree stop early, and nothing
+    // gets more than the least samples with no time for more
+    let exhaustive = render(&visualiser, &scene, &settings(2, 0.0));
+    assert!(exhaustive
+        .samples
+        .iter()
+        .zip(rendered.samples.iter())
+        .all(|(&count, &adaptive)| count == 32 || count == 4 && adaptive == 4));
+    assert!(
+        exhaustive
+            .samples
+            .iter()
+            .filter(|&&count| count == 32)
+            .count()
+            > 16
+    );
+    let rushed = RenderSettings {
+        time_budget: Some(Duration::from_secs(0)),
+        ..settings(2, 0.0)
+    };
+    let rushed = render(&visualiser, &scene, &rushed);
+    assert!(rushed.samples.iter().all(|&count| count == 4));
+
+    let path = std::env::temp_dir().join("raytracer_test_heatmap.png");
+    let path = path.to_str().unwrap();
+    save_heatmap(path, 16, &rendered.samples);
+    let heatmap = image::open(path).unwrap().to_rgb();
+    assert_eq!(heatmap.dimensions(), (16, 12));
+    let most_sampled = rendered
+        .samples
+        .iter()
+        .position(|&count| count == most)
+        .unwrap();
+    let (x, y) = ((most_sampled % 16) as u32, (most_sampled / 16) as u32);
+    assert_eq!(heatmap.get_pixel(x, y).0, [255, 255, 0]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_render_budgets() {
+    use crate::draw::{render, RenderSettings};
+    use std::time::{Duration, Instant};
+
+    let scene = crate::cornell_box::get_scene();
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let visualiser = Visualiser::new(6, 8, camera);
+
+    // Given time, it keeps sampling the pixels that aren't converged until it runs out,
+    // saving the image so far as it goes
+    let path = std::env::temp_dir().join("raytracer_test_checkpoint.png");
+    let path = path.to_str().unwrap().to_string();
+    let budget = Duration::from_millis(200);
+    let started = Instant::now();
+    let rendered = render(
+        &visualiser,
+        &scene,
+        &RenderSettings {
+            threads: 2,
+            samples: 2,
+            max_samples: u32::MAX,
+            noise_threshold: 0.0,
+            time_budget: Some(budget),
+            progress: true,
+            checkpoint_image: Some(path.clone()),
+            checkpoint_interval: Duration::from_secs(0),
+            ..Default::default()
+        },
+    );
+    let elapsed = started.elapsed();
+    assert!(elapsed >= budget && elapsed < Duration::from_secs(20));
+    assert!(rendered.samples.iter().any(|&count| count > 8));
+    assert_eq!(image::open(&path).unwrap().to_rgb().dimensions(), (8, 6));
+    std::fs::remove_file(&path).unwrap();
+
+    // A sample budget stops it sooner
+    let rendered = render(
+        &visualiser,
+        &scene,
+        &RenderSettings {
+            samples: 2,
+            max_samples: 6,
+            noise_threshold: 0.0,
+            time_budget: Some(Duration::from_secs(60)),
+            ..Default::default()
+        },
+    );
+    assert!(rendered.samples.iter().all(|&count| count <= 6));
+    assert!(rendered.samples.contains(&6));
+}
+
+#[test]
+fn test_checkpoints() {
+    use crate::checkpoint;
+    use crate::draw::{render, render_from, RenderSettings};
+
+    let scene = crate::cornell_box::get_scene();
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let visualiser = Visualiser::new(6, 8, camera);
+    let path = std::env::temp_dir().join("raytracer_test_checkpoint.ckpt");
+    let path = path.to_str().unwrap().to_string();
+    let settings = |max_samples: u32| RenderSettings {
+        threads: 2,
+        samples: 2,
+        max_samples,
+        noise_threshold: 0.0,
+        checkpoint: Some(path.clone()),
+        ..Default::default()
+    };
+
+    // Nothing to resume yet
+    let _ = std::fs::remove_file(&path);
+    assert!(checkpoint::resume(&path, &visualiser, &scene, &settings(8))
+        .unwrap()
+        .is_none());
+
+    // Stopping after 4 samples and carrying on to 8 gives the same image as going
+    // straight to 8
+    render(&visualiser, &scene, &settings(4));
+    let state = checkpoint::resume(&path, &visualiser, &scene, &settings(8))
+        .unwrap()
+        .unwrap();
+    let taken: u64 = state.pixels.iter().map(|pixel| pixel.samples as u64).sum();
+    assert_eq!(state.samples, taken);
+    assert!(state.pixels.iter().all(|pixel| pixel.samples <= 4));
+    let resumed = render_from(&visualiser, &scene, &settings(8), state);
+    let uninterrupted = render(
+        &visualiser,
+        &scene,
+        &RenderSettings {
+            checkpoint: None,
+            ..settings(8)
+        },
+    );
+    assert_eq!(resumed.samples, uninterrupted.samples);
+    for (a, b) in resumed.colours.iter().zip(uninterrupted.colours.iter()) {
+        assert_eq!(a, b);
+    }
+
+    // Not for a different camera or image size
+    let moved = Visualiser::new(6, 8, Camera::new(Point::new(0.0, 1.0, 25.0), 1.0, Deg(0.0)));
+    assert!(checkpoint::resume(&path, &moved, &scene, &settings(8)).is_err());
+    let resized = Visualiser::new(8, 8, Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0)));
+    assert!(checkpoint::resume(&path, &resized, &scene, &settings(8)).is_err());
+
+    std::fs::write(&path, b"not a checkpoint").unwrap();
+    assert!(checkpoint::resume(&path, &visualiser, &scene, &settings(8)).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_crop() {
+    use crate::draw::{render, render_headless, RenderSettings};
+    use crate::film::Region;
+
+    assert_eq!(
+        "2,3,4,5".parse::<Region>(),
+        Ok(Region {
+            x: 2,
+            y: 3,
+            width: 4,
+            height: 5
+        })
+    );
+    assert!("2,3,0,5".parse::<Region>().is_err());
+    assert!("2,3,4".parse::<Region>().is_err());
+    assert_eq!(
+        Region::spanning((5, 1), (2, 3)),
+        Region {
+            x: 2,
+            y: 1,
+            width: 4,
+            height: 3
+        }
+    );
+    assert_eq!(
+        "6,2,10,10".parse::<Region>().unwrap().within(8, 6),
+        Region {
+            x: 6,
+            y: 2,
+            width: 2,
+            height: 4
+        }
+    );
+
+    let scene = crate::cornell_box::get_scene();
+    let camera = || Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let mut visualiser = Visualiser::new(12, 12, camera());
+    let crop = Region {
+        x: 2,
+        y: 3,
+        width: 8,
+        height: 6,
+    };
+    let settings = RenderSettings {
+        threads: 2,
+        samples: 2,
+        ..Default::default()
+    };
+    let whole = render(&visualiser, &scene, &settings);
+    let cropped_settings = RenderSettings {
+        crop: Some(crop),
+        ..settings
+    };
+    let cropped = render(&visualiser, &scene, &cropped_settings);
+    assert_eq!(cropped.region, crop);
+
+    // Only the pixels in the crop are traced, the same as in the whole image, and
+    // those beyond the filter's reach of its edge come out the same too
+    let reach = cropped_settings.filter.radius().ceil() as usize;
+    for y in 0..12 {
+        for x in 0..12 {
+            let index = y * 12 + x;
+            if !crop.contains(x, y) {
+                assert_eq!(cropped.samples[index], 0);
+                continue;
+            }
+            assert_eq!(cropped.samples[index], whole.samples[index]);
+            let inner = Region {
+                x: crop.x + reach,
+                y: crop.y + reach,
+                width: crop.width - 2 * reach,
+                height: crop.height - 2 * reach,
+            };
+            if inner.contains(x, y) {
+                assert_relative_eq!(
+                    cropped.colours[index],
+                    whole.colours[index],
+                    max_relative = 1e-5
+                );
+            }
+        }
+    }
+
+    // The rest of the image keeps what was there
+    visualiser.put_pixel(0, 0, [1, 2, 3]);
+    visualiser.put_pixel(11, 11, [4, 5, 6]);
+    render_headless(&mut visualiser, &scene, &cropped_settings).unwrap();
+    assert_eq!(visualiser.screen.get_pixel(0, 0).0, [1, 2, 3]);
+    assert_eq!(visualiser.screen.get_pixel(11, 11).0, [4, 5, 6]);
+    let centre = as_int(whole.colours[6 * 12 + 6]);
+    assert_eq!(visualiser.screen.get_pixel(6, 6).0, centre);
+}
+
+#[test]
+fn test_distributed_render() {
+    use crate::distributed::{self, TILE_SIZE};
+    use crate::draw::{render, RenderSettings};
+    use crate::film::Region;
+    use std::io::{Read, Write};
+
+    // Tiles cover a region exactly
+    let region = Region {
+        x: 3,
+        y: 5,
+        width: 2 * TILE_SIZE + 7,
+        height: TILE_SIZE + 1,
+    };
+    let tiles = distributed::tiles(region);
+    assert_eq!(tiles.len(), 6);
+    for y in 0..region.y + region.height + 1 {
+        for x in 0..region.x + region.width + 1 {
+            let covering = tiles.iter().filter(|tile| tile.contains(x, y)).count();
+            assert_eq!(covering, region.contains(x, y) as usize);
+        }
+    }
+
+    let scene = crate::cornell_box::get_scene();
+    let visualiser =
+        |y: f32| Visualiser::new(36, 40, Camera::new(Point::new(0.0, y, 25.0), 1.0, Deg(0.0)));
+    let settings = RenderSettings {
+        threads: 1,
+        samples: 2,
+        ..Default::default()
+    };
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (distributed, tiles_rendered) = std::thread::scope(|scope| {
+        let coordinator =
+            scope.spawn(|| distributed::coordinate(&listener, &visualiser(0.0), &scene, &settings));
+        // Workers with a different scene or camera are turned away
+        assert!(distributed::work(&address, &visualiser(1.0), &scene, &settings).is_err());
+        // Or that would stop taking samples at a different point
+        let more_samples = RenderSettings {
+            max_samples: 8,
+            ..settings.clone()
+        };
+        assert!(distributed::work(&address, &visualiser(0.0), &scene, &more_samples).is_err());
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                scope.spawn(|| distributed::work(&address, &visualiser(0.0), &scene, &settings))
+            })
+            .collect();
+        let tiles_rendered: usize = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap().unwrap())
+            .sum();
+        (coordinator.join().unwrap().unwrap(), tiles_rendered)
+    });
+    assert_eq!(tiles_rendered, 4);
+
+    // The same image as rendering it all in one process, but for the order in which
+    // splats across the tiles' edges are added up
+    let local = render(&visualiser(0.0), &scene, &settings);
+    assert_eq!(distributed.samples, local.samples);
+    for (a, b) in distributed.colours.iter().zip(local.colours.iter()) {
+        assert_relative_eq!(a, b, max_relative = 1e-5);
+    }
+
+    // A worker that takes a tile and goes quiet has it handed to another
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let hash = distributed::job_hash(&visualiser(0.0), &scene, &settings).unwrap();
+    let (requeued, tiles_rendered) = std::thread::scope(|scope| {
+        let coordinator = scope.spawn(|| {
+            let timeout = std::time::Duration::from_millis(200);
+            distributed::coordinate_with_timeout(
+                &listener,
+                &visualiser(0.0),
+                &scene,
+                &settings,
+                timeout,
+            )
+        });
+        let mut quiet = std::net::TcpStream::connect(&address).unwrap();
+        quiet.write_all(b"RTWORK01").unwrap();
+        quiet.write_all(&hash.to_le_bytes()).unwrap();
+        // Accepted, then handed a tile
+        let mut reply = [0; 18];
+        quiet.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..2], [1, 1]);
+        let tiles_rendered =
+            distributed::work(&address, &visualiser(0.0), &scene, &settings).unwrap();
+        (coordinator.join().unwrap().unwrap(), tiles_rendered)
+    });
+    assert_eq!(tiles_rendered, 4);
+    assert_eq!(requeued.colours, distributed.colours);
+
+    // Workers can't be left rendering until a time budget that they don't keep runs out
+    let args = |line: &str| crate::parse_args(line.split(' ').map(String::from));
+    assert!(args("--worker 127.0.0.1:7878 --time-budget 60").is_err());
+    assert!(args("--coordinator 127.0.0.1:7878 --time-budget 60").is_err());
+    let capped = args("--coordinator 127.0.0.1:7878 --time-budget 60 --max-samples 16").unwrap();
+    assert_eq!(capped.settings.max_samples, 16);
+    let budgeted = args("--headless --time-budget 60").unwrap();
+    assert_eq!(budgeted.settings.max_samples, u32::MAX);
+}
+
+/// Bumpy grid of `size` by `size` quads facing up the z axis, two triangles each
+fn bumpy_grid(size: usize) -> crate::mesh::Mesh {
+    use crate::mesh::*;
+
+    let mut positions = Vec::new();
+    for y in 0..=size {
+        for x in 0..=size {
+            let height = ((x * 7 + y * 3) % 5) as f32 * 0.2;
+            positions.push(Point::new(x as f32, y as f32, height));
+        }
+    }
+    let mut faces = Vec::new();
+    for y in 0..size {
+        for x in 0..size {
+            let i = y * (size + 1) + x;
+            for indices in [
+                [i, i + 1, i + size + 1],
+                [i + 1, i + size + 2, i + size + 1],
+            ]
+            .iter()
+            {
+                faces.push(Face {
+                    indices: *indices,
+                    material: 0,
+                });
+            }
+        }
+    }
+    Mesh::new(
+        positions,
+        faces,
+        ColourFloat::zero(),
+        vec![Material::Diffuse],
+    )
+}
+
+/// Scene of a bumpy grid, spheres over it and a triangle and a plane, which doesn't
+/// have a packet test of its own
+fn packet_scene(size: usize) -> Scene {
+    use crate::primitives::*;
+
+    let mut objects = vec![
+        Object::Mesh(bumpy_grid(size)),
+        Object::Triangle(Triangle::new(
+            Point::new(0.0, 0.0, 2.0),
+            Point::new(0.0, 3.0, 2.0),
+            Point::new(3.0, 0.0, 2.0),
+            [ColourFloat::zero(); 3],
+            Material::Diffuse,
+        )),
+        Object::Plane(Plane::new(
+            Point::new(0.0, 0.0, -1.0),
+            Vector::unit_z(),
+            ColourFloat::zero(),
+            Material::Diffuse,
+        )),
+    ];
+    let mut rng = Rng::new(4, 0);
+    for _ in 0..size {
+        objects.push(Object::Sphere(Sphere {
+            centre: Point::new(
+                rng.f32_range(0.0, size as f32),
+                rng.f32_range(0.0, size as f32),
+                rng.f32_range(1.0, 3.0),
+            ),
+            radius: rng.f32_range(0.2, 1.0),
+            colour: ColourFloat::zero(),
+            texture: None,
+            material: Material::Diffuse,
+        }));
+    }
+    Scene::new(objects)
+}
+
+#[test]
+fn test_ray_packets() {
+    use crate::draw::{render, RenderSettings};
+    use crate::packet::{RayPacket, PACKET_SIZE};
+    use crate::simd::{F32x4, Mask4};
+
+    // Lanes ignore NaNs the way f32::min and f32::max do
+    let a = F32x4::from_array([1.0, f32::NAN, 3.0, f32::NAN]);
+    let b = F32x4::from_array([2.0, 5.0, f32::NAN, f32::NAN]);
+    let lanes = |x: F32x4| x.to_array().map(|x| if x.is_nan() { -1.0 } else { x });
+    assert_eq!(lanes(a.min(b)), [1.0, 5.0, 3.0, -1.0]);
+    assert_eq!(lanes(a.max(b)), [2.0, 5.0, 3.0, -1.0]);
+    assert_eq!((a.lt(b) | !Mask4::from_bits(0b1110)).bits(), 0b0001);
+
+    // Each ray in a packet gets exactly the intersection it gets alone, including rays
+    // starting inside spheres and partly filled packets
+    let scene = packet_scene(8);
+    let mut rng = Rng::new(5, 0);
+    let mut hits = 0;
+    for packet_size in (1..=PACKET_SIZE).cycle().take(400) {
+        let rays: Vec<Ray> = (0..packet_size)
+            .map(|_| {
+                let start = Point::new(
+                    rng.f32_range(-1.0, 9.0),
+                    rng.f32_range(-1.0, 9.0),
+                    rng.f32_range(1.0, 5.0),
+                );
+                let dir = Vector::new(rng.f32_range(-0.5, 0.5), rng.f32_range(-0.5, 0.5), -1.0);
+                Ray::new(start, dir.normalize())
+            })
+            .collect();
+        let packet = RayPacket::new(&rays);
+        let together = scene.closest_intersections(&rays);
+        assert!(together[packet_size..].iter().all(Option::is_none));
+        for (ray, together) in rays.iter().zip(together.iter()) {
+            let alone = scene.closest_intersection(ray);
+            assert_eq!(alone.is_some(), together.is_some());
+            if let (Some(alone), Some(together)) = (alone, together) {
+                hits += !matches!(alone.object, Object::Plane(_)) as usize;
+                assert_eq!(alone.location.distance, together.location.distance);
+                assert_eq!(alone.location.primitive, together.location.primitive);
+                assert!(std::ptr::eq(alone.object, together.object));
+            }
+        }
+        for object in scene.objects.iter() {
+            let together = object.packet_intersection(&packet);
+            for (lane, together) in together.iter().enumerate() {
+                let alone = rays.get(lane).and_then(|ray| object.intersection(ray));
+                assert_eq!(
+                    alone.map(|location| location.distance),
+                    together.as_ref().map(|location| location.distance)
+                );
+            }
+        }
+    }
+    assert!(hits > 500);
+
+    // A ray grazing a sphere, outside it by less than the tolerance, hits it either way
+    let sphere = Object::Sphere(Sphere {
+        centre: Point::new(0.0, 0.0, 0.0),
+        radius: 1.0,
+        colour: ColourFloat::zero(),
+        texture: None,
+        material: Material::Lambertian,
+    });
+    let grazing = [Ray::new(Point::new(1.000002, 0.0, 0.5), -Vector::unit_z())];
+    let alone = sphere
+        .intersection(&grazing[0])
+        .map(|location| location.distance);
+    let together = sphere.packet_intersection(&RayPacket::new(&grazing))[0]
+        .as_ref()
+        .map(|location| location.distance);
+    assert!(alone.is_some());
+    assert_eq!(alone, together);
+
+    // So renders come out exactly the same with packets as without
+    let scene = crate::cornell_box::get_scene();
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let visualiser = Visualiser::new(10, 12, camera);
+    let settings = |packets| RenderSettings {
+        samples: 3,
+        packets,
+        ..Default::default()
+    };
+    let with = render(&visualiser, &scene, &settings(true));
+    let without = render(&visualiser, &scene, &settings(false));
+    assert_eq!(with.colours, without.colours);
+    assert_eq!(with.samples, without.samples);
+}
+
+/// Speed of finding camera rays' first hits in packets and one at a time, the best of
+/// a few runs of each. Run with
+/// `cargo test --release bench_ray_packets -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_ray_packets() {
+    use crate::draw::{render, RenderSettings};
+    use crate::packet::PACKET_SIZE;
+    use std::time::{Duration, Instant};
+
+    let best = |f: &dyn Fn()| {
+        (0..5)
+            .map(|_| {
+                let started = Instant::now();
+                f();
+                started.elapsed()
+            })
+            .min()
+            .unwrap_or(Duration::ZERO)
+    };
+    let report = |name: String, scalar: Duration, packets: Duration| {
+        println!(
+            "{}: {:?} one at a time, {:?} in packets, {:.2}x faster",
+            name,
+            scalar,
+            packets,
+            scalar.as_secs_f64() / packets.as_secs_f64()
+        )
+    };
+
+    let size = 64;
+    let scene = packet_scene(size);
+    let camera = Point::new(size as f32 / 2.0, size as f32 / 2.0, size as f32);
+    let rays: Vec<Ray> = (0..512 * 512)
+        .map(|i| {
+            let (x, y) = ((i % 512) as f32 / 512.0, (i / 512) as f32 / 512.0);
+            let dir = Vector::new(x - 0.5, y - 0.5, -1.0).normalize();
+            Ray::new(camera, dir)
+        })
+        .collect();
+    let alone: Vec<_> = rays
+        .iter()
+        .map(|ray| scene.closest_intersection(ray))
+        .collect();
+    let together: Vec<_> = rays
+        .chunks(PACKET_SIZE)
+        .flat_map(|rays| IntoIterator::into_iter(scene.closest_intersections(rays)))
+        .collect();
+    for (alone, together) in alone.iter().zip(together.iter()) {
+        assert_eq!(
+            alone.as_ref().map(|hit| hit.location.distance),
+            together.as_ref().map(|hit| hit.location.distance)
+        );
+    }
+    report(
+        format!("{} triangles and {} spheres", 2 * size * size, size),
+        best(&|| {
+            for ray in rays.iter() {
+                scene.closest_intersection(ray);
+            }
+        }),
+        best(&|| {
+            for rays in rays.chunks(PACKET_SIZE) {
+                scene.closest_intersections(rays);
+            }
+        }),
+    );
+
+    let scene = crate::cornell_box::get_scene();
+    let camera = Camera::new(Point::new(0.0, 0.0, 25.0), 1.0, Deg(0.0));
+    let visualiser = Visualiser::new(200, 200, camera);
+    let time = |packets| {
+        best(&|| {
+            render(
+                &visualiser,
+                &scene,
+                &RenderSettings {
+                    samples: 4,
+                    packets,
+                    ..Default::default()
+                },
+            );
+        })
+    };
+    report("Cornell box".to_string(), time(false), time(true));
 }
@@ -0,0 +1,348 @@
+use cgmath::prelude::*;
+
+use crate::mipmap::ImageTexture;
+use crate::raytracing::*;
+use std::sync::Arc;
+
+pub type Uv = cgmath::Vector2<f32>;
+
+/// Where on a surface a texture is being looked up.
+pub struct TexturePoint {
+    pub position: Point,
+    pub uv: Uv,
+    /// Change in `uv` between neighbouring pixels, zero for a point sample
+    pub duvdx: Uv,
+    pub duvdy: Uv,
+}
+
+impl TexturePoint {
+    /// Where a pattern is evaluated. Texture coordinates are laid in the y = 0 plane, so
+    /// that wood's rings circle their origin.
+    fn coords(&self, space: TextureSpace) -> Point {
+        match space {
+            TextureSpace::World => self.position,
+            TextureSpace::Uv => Point::new(self.uv.x, 0.0, self.uv.y),
+        }
+    }
+}
+
+/// Which coordinates a pattern is evaluated in.
+#[derive(Clone, Copy)]
+pub enum TextureSpace {
+    World,
+    Uv,
+}
+
+/// Procedural textures, composable into graphs.
+///
+/// `Solid` colours use the same 0-255 range as the rest of the renderer. The scalar
+/// patterns (`Noise`, `Marble`, `Voronoi`...) return a grey value between 0 and 1
+/// in every channel, and are meant to drive `Mix`, `Ramp`, `Multiply` or `Remap`.
+pub enum Texture {
+    Solid(ColourFloat),
+    Image(Arc<ImageTexture>),
+    Checker {
+        even: Box<Texture>,
+        odd: Box<Texture>,
+        scale: f32,
+        space: TextureSpace,
+    },
+    Noise {
+        scale: f32,
+        space: TextureSpace,
+    },
+    Turbulence {
+        scale: f32,
+        octaves: u32,
+        space: TextureSpace,
+    },
+    Marble {
+        scale: f32,
+        turbulence: f32,
+        octaves: u32,
+        space: TextureSpace,
+    },
+    Wood {
+        scale: f32,
+        turbulence: f32,
+        space: TextureSpace,
+    },
+    Voronoi {
+        scale: f32,
+        space: TextureSpace,
+    },
+    /// 0 at `origin`, rising to 1 at `origin + direction`
+    Gradient {
+        origin: Point,
+        direction: Vector,
+        space: TextureSpace,
+    },
+    /// Map the scalar `input` onto a list of `(position, colour)` stops, sorted by position
+    Ramp {
+        input: Box<Texture>,
+        stops: Vec<(f32, ColourFloat)>,
+    },
+    Mix {
+        a: Box<Texture>,
+        b: Box<Texture>,
+        factor: Box<Texture>,
+    },
+    Multiply(Box<Texture>, Box<Texture>),
+    /// Linearly map `input` from the range `from`, which mustn't be empty, onto the
+    /// range `to`
+    Remap {
+        input: Box<Texture>,
+        from: (f32, f32),
+        to: (f32, f32),
+    },
+}
+
+impl Texture {
+    pub fn eval(&self, point: &TexturePoint) -> ColourFloat {
+        use Texture::*;
+        match *self {
+            Solid(colour) => colour,
+            Image(ref image) => image.lookup(point.uv, point.duvdx, point.duvdy),
+            Checker {
+                ref even,
+                ref odd,
+                scale,
+                space,
+            } => {
+                let p = point.coords(space) * scale;
+                let cells = p.x.floor() + p.y.floor() + p.z.floor();
+                if (cells as i32).rem_euclid(2) == 0 {
+                    even.eval(point)
+                } else {
+                    odd.eval(point)
+                }
+            }
+            Noise { scale, space } => grey(0.5 * (1.0 + perlin(point.coords(space) * scale))),
+            Turbulence {
+                scale,
+                octaves,
+                space,
+            } => grey(turbulence(point.coords(space) * scale, octaves).min(1.0)),
+            Marble {
+                scale,
+                turbulence: amount,
+                octaves,
+                space,
+            } => {
+                let p = point.coords(space) * scale;
+                let phase = p.x + amount * turbulence(p, octaves);
+                grey(0.5 * (1.0 + phase.sin()))
+            }
+            Wood {
+                scale,
+                turbulence: amount,
+                space,
+            } => {
+                let p = point.coords(space) * scale;
+                let rings = (p.x * p.x + p.z * p.z).sqrt() + amount * perlin(p);
+                grey(rings - rings.floor())
+            }
+            Voronoi { scale, space } => grey(voronoi(point.coords(space) * scale).min(1.0)),
+            Gradient {
+                origin,
+                direction,
+                space,
+            } => {
+                let t = (point.coords(space) - origin).dot(direction) / direction.magnitude2();
+                grey(t.clamp(0.0, 1.0))
+            }
+            Ramp {
+                ref input,
+                ref stops,
+            } => ramp(input.eval(point).x, stops),
+            Mix {
+                ref a,
+                ref b,
+                ref factor,
+            } => {
+                let t = factor.eval(point).x;
+                (1.0 - t) * a.eval(point) + t * b.eval(point)
+            }
+            Multiply(ref a, ref b) => a.eval(point).mul_element_wise(b.eval(point)),
+            Remap {
+                ref input,
+                from,
+                to,
+            } => {
+                let value = input.eval(point);
+                let scale = (to.1 - to.0) / (from.1 - from.0);
+                value.map(|c| to.0 + (c - from.0) * scale)
+            }
+        }
+    }
+}
+
+/// Ways of perturbing a surface's shading normal.
+pub enum NormalMap {
+    /// Texture whose 0-1 channels encode a normal in the tangent frame, with blue along the normal
+    TangentSpace(Texture),
+    /// Scalar height texture, displacing the surface by `strength * height` along the normal
+    Bump { height: Texture, strength: f32 },
+}
+
+impl NormalMap {
+    /// Perturb `normal`, given the derivatives of position with respect to `u` and `v`.
+    pub fn apply(
+        &self,
+        point: &TexturePoint,
+        normal: Vector,
+        dpdu: Vector,
+        dpdv: Vector,
+    ) -> Vector {
+        match *self {
+            NormalMap::TangentSpace(ref texture) => {
+                let tangent = (dpdu - normal * normal.dot(dpdu)).normalize();
+                let bitangent = normal.cross(tangent);
+                let bitangent = if bitangent.dot(dpdv) < 0.0 {
+                    -bitangent
+                } else {
+                    bitangent
+                };
+                let t = texture.eval(point).map(|c| 2.0 * c - 1.0);
+                (t.x * tangent + t.y * bitangent + t.z * normal).normalize()
+            }
+            NormalMap::Bump {
+                ref height,
+                strength,
+            } => {
+                // Forward differences of the height field along u and v
+                let delta = 0.001;
+                let height_at = |du: f32, dv: f32| {
+                    let offset = TexturePoint {
+                        position: point.position + dpdu * du + dpdv * dv,
+                        uv: point.uv + Uv::new(du, dv),
+                        duvdx: point.duvdx,
+                        duvdy: point.duvdy,
+                    };
+                    strength * height.eval(&offset).x
+                };
+                let h = height_at(0.0, 0.0);
+                let dhdu = (height_at(delta, 0.0) - h) / delta;
+                let dhdv = (height_at(0.0, delta) - h) / delta;
+                let bumped = (dpdu + dhdu * normal).cross(dpdv + dhdv * normal);
+                let bumped = if bumped.dot(normal) < 0.0 {
+                    -bumped
+                } else {
+                    bumped
+                };
+                bumped.normalize()
+            }
+        }
+    }
+}
+
+fn grey(value: f32) -> ColourFloat {
+    ColourFloat::new(value, value, value)
+}
+
+fn ramp(t: f32, stops: &[(f32, ColourFloat)]) -> ColourFloat {
+    match stops.iter().position(|&(pos, _)| t < pos) {
+        Some(0) => stops[0].1,
+        Some(i) => {
+            let (p0, c0) = stops[i - 1];
+            let (p1, c1) = stops[i];
+            c0.lerp(c1, (t - p0) / (p1 - p0))
+        }
+        None => stops.last().map_or(ColourFloat::zero(), |&(_, c)| c),
+    }
+}
+
+// Integer lattice hash, used in place of Perlin's permutation table
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn gradient(hash: u32, x: f32, y: f32, z: f32) -> f32 {
+    // The 12 cube edge directions from Perlin's improved noise
+    match hash & 15 {
+        0 | 12 => x + y,
+        1 | 13 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 | 14 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+/// Improved Perlin noise, roughly in the range -1 to 1.
+pub fn perlin(p: Point) -> f32 {
+    let (xi, yi, zi) = (p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32);
+    let (x, y, z) = (p.x - p.x.floor(), p.y - p.y.floor(), p.z - p.z.floor());
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        gradient(
+            hash(xi + dx, yi + dy, zi + dz),
+            x - dx as f32,
+            y - dy as f32,
+            z - dz as f32,
+        )
+    };
+    let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+            lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+        ),
+        lerp(
+            v,
+            lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+            lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+        ),
+    )
+}
+
+/// Sum of `octaves` layers of absolute noise, each at double the frequency and half the weight.
+pub fn turbulence(p: Point, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += perlin(p * frequency).abs() / frequency;
+        frequency *= 2.0;
+    }
+    sum
+}
+
+/// Distance to the nearest feature point, one feature point per unit cell.
+pub fn voronoi(p: Point) -> f32 {
+    let cell = p.map(|c| c.floor());
+    let mut closest = f32::MAX;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let neighbour = cell + Vector::new(dx as f32, dy as f32, dz as f32);
+                let h = hash(neighbour.x as i32, neighbour.y as i32, neighbour.z as i32);
+                let jitter = Vector::new(
+                    (h & 0x3ff) as f32 / 1023.0,
+                    ((h >> 10) & 0x3ff) as f32 / 1023.0,
+                    ((h >> 20) & 0x3ff) as f32 / 1023.0,
+                );
+                closest = closest.min((neighbour + jitter - p).magnitude());
+            }
+        }
+    }
+    closest
+}
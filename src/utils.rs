@@ -1,9 +1,10 @@
 use crate::raytracing::*;
-use rand::Rng;
+use cgmath::prelude::*;
 
-const EPSILON: f32 = 0.000005;
+pub const EPSILON: f32 = 0.000005;
 
 // All functions optimistically return true
+#[allow(dead_code)]
 pub fn is_eq(num1: f32, num2: f32) -> bool {
     (num1 - num2).abs() < EPSILON
 }
@@ -20,6 +21,7 @@ pub fn is_positive(num1: f32) -> bool {
     num1 > EPSILON
 }
 
+#[allow(dead_code)]
 pub fn is_less_than(num1: f32, num2: f32) -> bool {
     num1 < num2 - EPSILON
 }
@@ -36,24 +38,90 @@ pub fn to_4(vec3: &cgmath::Vector3<f32>) -> cgmath::Vector4<f32> {
     cgmath::Vector4::new(vec3.x, vec3.y, vec3.z, 1.0)
 }
 
-pub fn rand_f32() -> f32 {
-    rand::thread_rng().gen::<f32>()
+/// PCG32 random number generator (O'Neill 2014), small and fast with good statistics.
+///
+/// Everything random in a render draws from one of these, passed in explicitly, so the
+/// same seed always gives the same image. Its algorithm is fixed here rather than left
+/// to a library, so images stay the same across dependency upgrades too.
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+    increment: u64,
 }
 
-pub fn rand_f32_range(min: f32, max: f32) -> f32 {
-    rand::thread_rng().gen_range(min..max)
-}
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
 
-pub fn rand_vector() -> Vector {
-    let mut rng = rand::thread_rng();
-    Vector::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>())
+/// SplitMix64's finaliser, to spread nearby seeds far apart
+pub fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
-pub fn rand_vector_range(min: f32, max: f32) -> Vector {
-    let mut rng = rand::thread_rng();
-    Vector::new(
-        rng.gen_range(min..max),
-        rng.gen_range(min..max),
-        rng.gen_range(min..max),
-    )
+impl Rng {
+    /// Generators with different `stream`s give independent sequences for the same seed
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Rng {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    /// Generator for one sample of one pixel. Depending only on the pixel and sample,
+    /// not on when it's rendered, it gives the same result whatever order or thread
+    /// pixels are rendered on.
+    pub fn for_sample(seed: u64, x: u32, y: u32, sample: u32) -> Self {
+        let pixel = ((y as u64) << 32) | x as u64;
+        Rng::new(mix(seed ^ mix(pixel)), sample as u64)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    /// Uniform in [0, 1)
+    pub fn f32(&mut self) -> f32 {
+        // 24 bits is all an f32 can hold below 1
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    #[allow(dead_code)]
+    pub fn f32_range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.f32()
+    }
+
+    #[allow(dead_code)]
+    pub fn vector(&mut self) -> Vector {
+        Vector::new(self.f32(), self.f32(), self.f32())
+    }
+
+    #[allow(dead_code)]
+    pub fn vector_range(&mut self, min: f32, max: f32) -> Vector {
+        Vector::new(
+            self.f32_range(min, max),
+            self.f32_range(min, max),
+            self.f32_range(min, max),
+        )
+    }
+
+    /// Uniformly distributed direction
+    #[allow(dead_code)]
+    pub fn unit_vector(&mut self) -> Vector {
+        loop {
+            let p = self.vector_range(-1.0, 1.0);
+            let length2 = p.magnitude2();
+            if length2 < 1.0 && !is_zero(length2) {
+                return p.normalize();
+            }
+        }
+    }
 }